@@ -59,6 +59,1265 @@ mod tests {
         assert_eq!(m.generate(&[1, 2, 3]), "F0 01 02 03 F7");
     }
 
+    #[test]
+    fn test_bit_field_parameters() {
+        let mut m: lib::midi_command::MidiCommand =
+            lib::midi_command::MidiCommand::new("Parameter Change".to_owned());
+        m.midi = String::from("F0 42 3n 03 41 ws F7");
+        m.add_parameter(lib::midi_command::MidiParameter::new_parse("n : 1 : Channel"));
+        m.add_parameter(lib::midi_command::MidiParameter::new_parse("w : b0-2 : Waveform"));
+        m.add_parameter(lib::midi_command::MidiParameter::new_parse("s : b3 : Sync"));
+        m.update_mask();
+
+        let bytes = m.generate_bytes(&[2, 5, 1]);
+        assert_eq!(bytes, [0xF0, 0x42, 0x32, 0x03, 0x41, 0x0D, 0xF7]);
+        assert!(m.matches(&bytes));
+        assert!(!m.matches(&[0xF0, 0x42, 0x32, 0x03, 0x41, 0x1D, 0xF7]));
+
+        let values = m.decode(&bytes).unwrap();
+        assert_eq!(values, vec![
+            (String::from("n"), lib::midi_command::ParameterValue::Integer(2)),
+            (String::from("w"), lib::midi_command::ParameterValue::Integer(5)),
+            (String::from("s"), lib::midi_command::ParameterValue::Integer(1))
+        ]);
+    }
+
+    #[test]
+    fn test_text_parameters() {
+        use lib::midi_command::{Argument, ParameterValue};
+
+        let mut conf = lib::config::Config::new();
+        conf.run_json(r#"
+        {
+            "id" : "test",
+            "commands" : [
+                {
+                    "name" : "Program Name",
+                    "midi" : "F0 42 3n 03 44 m F7",
+                    "parameters" : [
+                        "n : 1 : Channel",
+                        { "key" : "m", "size" : "s10", "name" : "Name", "padding" : "_" }
+                    ],
+                    "alias" : "name"
+                }
+            ]
+        }
+        "#.to_owned()).unwrap();
+
+        let command = conf.get_current_synth().unwrap().get_command("name").unwrap();
+        let args = vec![
+            Argument::parse("1").unwrap(),
+            Argument::parse("name=\"Fat Brass\"").unwrap()
+        ];
+        let bytes = lib::utils::stob(command.generate_arguments(&args).unwrap().as_str());
+        assert_eq!(bytes.len(), 16);
+        assert_eq!(&bytes[5..15], b"Fat Brass_");
+        assert!(command.matches(&bytes));
+
+        let values = command.decode(&bytes).unwrap();
+        assert_eq!(values[1], (String::from("m"), ParameterValue::Text(String::from("Fat Brass"))));
+
+        let args = vec![Argument::parse("\"abc\"").unwrap(), Argument::parse("\"Brass\"").unwrap()];
+        assert_eq!(
+            command.generate_arguments(&args).unwrap_err(),
+            "Parameter Channel of command Program Name expects a number, got \"abc\""
+        );
+    }
+
+    #[test]
+    fn test_patch_diff() {
+        use lib::midi_command::ParameterValue;
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let s = conf.get_synth("dw-8000").unwrap();
+
+        let mut dump: Vec<u8> = vec![0xF0, 0x42, 0x30, 0x03, 0x40];
+        dump.extend_from_slice(&[0x00; 51]);
+        dump.push(0xF7);
+
+        let a = lib::patch::Patch::from_dump(s, "a", &dump);
+        dump[5 + 15] = 40;
+        dump[5 + 34] = 3;
+        let b = lib::patch::Patch::from_dump(s, "b", &dump);
+        assert_eq!(b.get("cutoff"), Some(&ParameterValue::Integer(40)));
+
+        match lib::patch::diff(Some(s), &a, &b) {
+            lib::patch::PatchDiff::Parameters(changes) => {
+                assert_eq!(changes.len(), 2);
+                assert_eq!(changes[0].id, "cutoff");
+                assert_eq!(changes[1].new_label, "Square");
+            }
+            _ => panic!("Expected a parameter diff"),
+        }
+
+        match lib::patch::diff(None, &a, &b) {
+            lib::patch::PatchDiff::Bytes(changes) => {
+                assert_eq!(changes.len(), 2);
+                assert_eq!(changes[0].offset, 20);
+                assert_eq!(changes[0].new, Some(40));
+            }
+            _ => panic!("Expected a byte diff"),
+        }
+    }
+
+    #[test]
+    fn test_randomizer() {
+        use lib::midi_command::ParameterValue;
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let s = conf.get_synth("dw-8000").unwrap();
+
+        let mut r = lib::randomizer::Randomizer::new(42);
+        let a = r.generate(s, None);
+        assert_eq!(a.values, r.generate(s, None).values);
+        for p in &s.parameters {
+            let v = a.get(&p.id).unwrap().as_integer().unwrap();
+            assert!(v >= p.min && v <= p.max);
+        }
+
+        r.lock("cutoff");
+        r.ratio("resonance", 0.0);
+        r.mutate(10.0);
+        let b = r.generate(s, Some(&a));
+        assert_eq!(b.get("cutoff"), a.get("cutoff"));
+        assert_eq!(b.get("resonance"), a.get("resonance"));
+
+        let cutoff = s.get_parameter("cutoff").unwrap();
+        let bytes = s.parameter_change_bytes(Some(1), cutoff, &ParameterValue::Integer(40)).unwrap();
+        assert_eq!(bytes, [0xF0, 0x42, 0x31, 0x03, 0x41, 0x0F, 0x28, 0xF7]);
+
+        let dump = s.program_dump_bytes(Some(0), &b).unwrap();
+        assert_eq!(dump.len(), 57);
+        assert_eq!(lib::patch::Patch::from_dump(s, "b", &dump).values, b.values);
+    }
+
+    #[test]
+    fn test_morph() {
+        use lib::midi_command::ParameterValue;
+        use lib::midi_interface::{MidiInterfaceError, MidiTransport};
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let s = conf.get_synth("dw-8000").unwrap();
+
+        let mut a = lib::patch::Patch::new("dw-8000", "a");
+        a.set("cutoff", ParameterValue::Integer(10));
+        a.set("resonance", ParameterValue::Integer(5));
+        a.set("mg-waveform", ParameterValue::Integer(0));
+        let mut b = a.clone();
+        b.set("cutoff", ParameterValue::Integer(50));
+        b.set("mg-waveform", ParameterValue::Integer(3));
+
+        let m = lib::morph::morph(s, &a, &b, 0.25);
+        assert_eq!(m.get("cutoff"), Some(&ParameterValue::Integer(20)));
+        assert_eq!(m.get("mg-waveform"), Some(&ParameterValue::Integer(0)));
+        assert_eq!(lib::morph::morph(s, &a, &b, 0.5).get("mg-waveform"), Some(&ParameterValue::Integer(3)));
+
+        /// Device failing to send a message when unplugged
+        struct Flaky {
+            device: FakeDevice,
+            unplugged: bool,
+        }
+
+        impl MidiTransport for Flaky {
+            fn send(&mut self, data: &[u8]) -> Result<(), MidiInterfaceError> {
+                if std::mem::take(&mut self.unplugged) {
+                    return Err(MidiInterfaceError::Timeout(String::from("Unplugged")));
+                }
+                self.device.send(data)
+            }
+
+            fn receive(&mut self, timeout: std::time::Duration) -> Option<Vec<u8>> {
+                self.device.receive(timeout)
+            }
+        }
+
+        let mut synth = Flaky { device: FakeDevice::new(|_| Vec::new()), unplugged: false };
+        let send = |synth: &mut Flaky, changes: &[(String, ParameterValue)]| -> Result<(), MidiInterfaceError> {
+            for (id, value) in changes {
+                synth.send(&s.parameter_change_bytes(Some(0), s.get_parameter(id).unwrap(), value).unwrap())?;
+            }
+            Ok(())
+        };
+
+        let mut morpher = lib::morph::Morpher::new(a, b);
+        assert_eq!(morpher.step(s, 0.0, |c| send(&mut synth, c)).unwrap(), 3);
+        assert_eq!(morpher.step(s, 0.25, |c| send(&mut synth, c)).unwrap(), 1);
+        assert_eq!(synth.device.sent().last().unwrap().1, vec![0xF0, 0x42, 0x30, 0x03, 0x41, 0x0F, 20, 0xF7]);
+
+        // The values that failed to be sent are sent again
+        synth.unplugged = true;
+        assert!(morpher.step(s, 0.5, |c| send(&mut synth, c)).is_err());
+        assert_eq!(morpher.position, 0.25);
+        assert_eq!(morpher.step(s, 0.5, |c| send(&mut synth, c)).unwrap(), 2);
+        assert_eq!(synth.device.sent().len(), 6);
+    }
+
+    /// Answers DW-8000 dump requests with its edit buffer, which program
+    /// dumps and parameter changes overwrite unless it is write protected.
+    /// Program dumps are acknowledged like the synth does.
+    struct FakeDw8000 {
+        program: Vec<u8>,
+        outbox: std::collections::VecDeque<Vec<u8>>,
+        protected: bool,
+    }
+
+    impl lib::midi_interface::MidiTransport for FakeDw8000 {
+        fn send(&mut self, data: &[u8]) -> Result<(), lib::midi_interface::MidiInterfaceError> {
+            if data == [0xF0, 0x42, 0x30, 0x03, 0x10, 0xF7] {
+                self.outbox.push_back(self.program.clone());
+            } else if data.starts_with(&[0xF0, 0x42, 0x30, 0x03, 0x40]) {
+                let reply = if self.protected { 0x24 } else { 0x23 };
+                self.outbox.push_back(vec![0xF0, 0x42, 0x30, 0x03, reply, 0xF7]);
+                if !self.protected {
+                    self.program = data.to_vec();
+                }
+            } else if self.protected {
+                return Ok(());
+            } else if data.starts_with(&[0xF0, 0x42, 0x30, 0x03, 0x41]) {
+                self.program[5 + data[5] as usize] = data[6];
+            }
+            Ok(())
+        }
+
+        fn receive(&mut self, _timeout: std::time::Duration) -> Option<Vec<u8>> {
+            self.outbox.pop_front()
+        }
+    }
+
+    /// Records the messages received in the session state like the input
+    /// tracker, then the knob moves made on the synth meanwhile
+    struct Tracked<'a, T> {
+        synth: T,
+        state: lib::state::SessionState,
+        definition: &'a lib::synth::Synth,
+        moves: Vec<Vec<u8>>,
+    }
+
+    impl<'a, T: lib::midi_interface::MidiTransport> Tracked<'a, T> {
+        fn new(synth: T, definition: &'a lib::synth::Synth) -> Tracked<'a, T> {
+            Tracked { synth, state: lib::state::SessionState::new(), definition, moves: Vec::new() }
+        }
+
+        /// Requests values like `Interpreter::sync`, returning the changes
+        /// recorded meanwhile
+        fn sync<F>(&mut self, request: F) -> Result<Vec<lib::state::StateChange>, lib::state::StateError>
+        where
+            F: FnOnce(&mut Self) -> Result<Vec<Vec<u8>>, lib::state::StateError>,
+        {
+            let before = self.state.clone();
+            request(self)?;
+            Ok(self.state.changes_since(&before, self.definition.id.as_str(), 0))
+        }
+    }
+
+    impl<T: lib::midi_interface::MidiTransport> lib::midi_interface::MidiTransport for Tracked<'_, T> {
+        fn send(&mut self, data: &[u8]) -> Result<(), lib::midi_interface::MidiInterfaceError> {
+            self.synth.send(data)
+        }
+
+        fn receive(&mut self, timeout: std::time::Duration) -> Option<Vec<u8>> {
+            let message = self.synth.receive(timeout)?;
+            self.state.apply_sysex(self.definition, 0, &message, lib::state::Direction::Received);
+            for knob in &self.moves {
+                self.state.apply_sysex(self.definition, 0, knob, lib::state::Direction::Received);
+            }
+            Some(message)
+        }
+    }
+
+    #[test]
+    fn test_backup_restore() {
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let s = conf.get_synth("dw-8000").unwrap();
+
+        let mut program: Vec<u8> = vec![0xF0, 0x42, 0x30, 0x03, 0x40];
+        program.extend((0..51).map(|i| i as u8));
+        program.push(0xF7);
+        let mut synth = FakeDw8000 { program: program.clone(), outbox: Default::default(), protected: false };
+
+        let dir = std::env::temp_dir().join("midiprog-test-backup");
+        let timeout = std::time::Duration::from_millis(10);
+        let manifest = lib::backup::backup(&mut synth, s, Some(0), &dir, timeout).unwrap();
+        assert_eq!(manifest.entries.len(), 1);
+        assert_eq!(manifest.entries[0].bytes, 57);
+        assert_eq!(std::fs::read(dir.join("program.syx")).unwrap(), program);
+
+        synth.program[10] = 0;
+        let pace = std::time::Duration::from_millis(0);
+        let verifier = lib::verify::Verifier::new(0, timeout);
+        lib::backup::restore(&mut synth, s, &dir, pace, Some(&verifier)).unwrap();
+        assert_eq!(synth.program, program);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        // The bank dump isn't answered : the backup fails, the program is kept
+        conf.run_json(String::from(r#"{
+            "id" : "dw-8000-banks", "manufacturer" : "Korg",
+            "commands" : [
+                { "name" : "Data Save Request", "midi" : "F0 {manufacturer} 3n 03 10 F7", "parameters" : ["n : 1 : Channel"], "alias" : "dsr" },
+                { "name" : "Bank Request", "midi" : "F0 {manufacturer} 3n 03 1C F7", "parameters" : ["n : 1 : Channel"], "alias" : "bank" }
+            ],
+            "dumps" : [
+                { "name" : "program", "request" : "dsr", "header" : "F0 {manufacturer} 3n 03 40" },
+                { "name" : "bank", "request" : "bank", "header" : "F0 {manufacturer} 3n 03 4C" }
+            ]
+        }"#)).unwrap();
+        let banks = conf.get_synth("dw-8000-banks").unwrap();
+        let error = lib::backup::backup(&mut synth, banks, Some(0), &dir, timeout).unwrap_err();
+        assert_eq!(error.to_string(), "Backup incomplete, failed to get bank");
+        let manifest = lib::backup::Manifest::load(&dir).unwrap();
+        assert_eq!(manifest.entries.len(), 1);
+        assert_eq!(manifest.entries[0].dump, "program");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_verify_upload() {
+        use lib::midi_command::ParameterValue;
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let s = conf.get_synth("dw-8000").unwrap();
+
+        let mut program: Vec<u8> = vec![0xF0, 0x42, 0x30, 0x03, 0x40];
+        program.extend_from_slice(&[0x00; 51]);
+        program.push(0xF7);
+        let mut synth = FakeDw8000 { program, outbox: Default::default(), protected: false };
+
+        let cutoff = s.get_parameter("cutoff").unwrap();
+        let value = ParameterValue::Integer(40);
+        let messages = vec![s.parameter_change_bytes(Some(0), cutoff, &value).unwrap()];
+        let mut expected = lib::patch::Patch::new("dw-8000", "");
+        expected.set("cutoff", value);
+
+        let dump = s.program_dump().unwrap();
+        let verifier = lib::verify::Verifier::new(1, std::time::Duration::from_millis(10));
+        assert!(verifier.upload(&mut synth, s, dump, Some(0), &messages, &expected).is_ok());
+
+        synth.protected = true;
+        expected.set("cutoff", ParameterValue::Integer(41));
+        let messages = vec![s.parameter_change_bytes(Some(0), cutoff, &ParameterValue::Integer(41)).unwrap()];
+        match verifier.upload(&mut synth, s, dump, Some(0), &messages, &expected) {
+            Err(lib::verify::VerifyError::Mismatch(lib::patch::PatchDiff::Parameters(changes))) => {
+                assert_eq!(changes.len(), 1);
+                assert_eq!(changes[0].id, "cutoff");
+                assert_eq!(changes[0].new, Some(ParameterValue::Integer(40)));
+            }
+            _ => panic!("Expected a parameter mismatch"),
+        }
+
+        // A dump request left unanswered is retried like a mismatch
+        struct Deaf<'a>(&'a mut FakeDw8000, usize);
+
+        impl lib::midi_interface::MidiTransport for Deaf<'_> {
+            fn send(&mut self, data: &[u8]) -> Result<(), lib::midi_interface::MidiInterfaceError> {
+                if data == [0xF0, 0x42, 0x30, 0x03, 0x10, 0xF7] && self.1 > 0 {
+                    self.1 -= 1;
+                    return Ok(());
+                }
+                self.0.send(data)
+            }
+
+            fn receive(&mut self, timeout: std::time::Duration) -> Option<Vec<u8>> {
+                self.0.receive(timeout)
+            }
+        }
+
+        synth.protected = false;
+        assert!(verifier.upload(&mut Deaf(&mut synth, 1), s, dump, Some(0), &messages, &expected).is_ok());
+        match verifier.upload(&mut Deaf(&mut synth, 2), s, dump, Some(0), &messages, &expected) {
+            Err(lib::verify::VerifyError::Mismatch(_)) | Ok(()) => panic!("Expected a read back error"),
+            Err(_) => {}
+        }
+    }
+
+    #[test]
+    fn test_session_state() {
+        use lib::midi_command::ParameterValue;
+        use lib::state::Direction;
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let s = conf.get_synth("dw-8000").unwrap();
+        let cutoff = s.get_parameter("cutoff").unwrap();
+
+        let mut state = lib::state::SessionState::new();
+        let sent = s.parameter_change_bytes(Some(2), cutoff, &ParameterValue::Integer(34)).unwrap();
+        let changes = state.apply_sysex(s, 0, &sent, Direction::Sent);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].channel, 2);
+        assert_eq!(changes[0].old, None);
+
+        let mut program: Vec<u8> = vec![0xF0, 0x42, 0x32, 0x03, 0x40];
+        program.extend_from_slice(&[0x00; 51]);
+        program[5 + 15] = 40;
+        program.push(0xF7);
+        let changes = state.apply_sysex(s, 0, &program, Direction::Received);
+        assert_eq!(changes.len(), 51);
+
+        let c = state.get("dw-8000", 2, "cutoff").unwrap();
+        assert_eq!(c.sent, Some(ParameterValue::Integer(34)));
+        assert_eq!(c.received, Some(ParameterValue::Integer(40)));
+        assert_eq!(c.value, Some(ParameterValue::Integer(40)));
+        assert!(state.get("dw-8000", 0, "cutoff").is_none());
+
+        let patch = state.to_patch(s, 2, "state");
+        assert_eq!(patch.values.len(), 51);
+        assert_eq!(patch.get("cutoff"), Some(&ParameterValue::Integer(40)));
+    }
+
+    #[test]
+    fn test_relative_edits() {
+        use lib::midi_command::ParameterValue;
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let s = conf.get_synth("dw-8000").unwrap();
+
+        let cutoff = s.get_parameter("cutoff").unwrap();
+        assert_eq!(cutoff.step(&ParameterValue::Integer(34), 5), Some(ParameterValue::Integer(39)));
+        assert_eq!(cutoff.step(&ParameterValue::Integer(60), 5), Some(ParameterValue::Integer(63)));
+        assert_eq!(cutoff.step(&ParameterValue::Integer(2), -5), Some(ParameterValue::Integer(0)));
+        assert_eq!(cutoff.step(&ParameterValue::Text(String::from("x")), 1), None);
+        assert_eq!(cutoff.toggle(&ParameterValue::Integer(0)), None);
+
+        let waveform = s.get_parameter("mg-waveform").unwrap();
+        assert_eq!(waveform.step(&ParameterValue::Integer(1), 1), Some(ParameterValue::Integer(2)));
+        assert_eq!(waveform.step(&ParameterValue::Integer(3), 1), Some(ParameterValue::Integer(3)));
+        assert_eq!(waveform.toggle(&ParameterValue::Integer(3)), Some(ParameterValue::Integer(0)));
+
+        let bend = s.get_parameter("bend-vcf").unwrap();
+        assert_eq!(bend.toggle(&ParameterValue::Integer(0)), Some(ParameterValue::Integer(1)));
+        assert_eq!(bend.toggle(&ParameterValue::Integer(1)), Some(ParameterValue::Integer(0)));
+    }
+
+    #[test]
+    fn test_sync() {
+        use lib::midi_command::ParameterValue;
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let s = conf.get_synth("dw-8000").unwrap();
+
+        let mut program: Vec<u8> = vec![0xF0, 0x42, 0x30, 0x03, 0x40];
+        program.extend((0..51).map(|i| i as u8 % 4));
+        program.push(0xF7);
+        let synth = FakeDw8000 { program, outbox: Default::default(), protected: false };
+
+        let mut tracked = Tracked::new(synth, s);
+        let timeout = std::time::Duration::from_millis(10);
+        let changes = tracked.sync(|t| lib::state::request_all(t, s, Some(0), timeout)).unwrap();
+        assert_eq!(changes.len(), 51);
+        assert_eq!(tracked.state.value("dw-8000", 0, "cutoff"), Some(&ParameterValue::Integer(3)));
+        assert_eq!(tracked.state.value("dw-8000", 0, "mg-waveform"), Some(&ParameterValue::Integer(2)));
+
+        // Without parameter requests, the whole program is requested
+        tracked.synth.program[5 + 15] = 40;
+        let cutoff = s.get_parameter("cutoff").unwrap();
+        let changes = tracked.sync(|t| lib::state::request(t, s, Some(0), cutoff, timeout)).unwrap();
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].old, Some(ParameterValue::Integer(3)));
+        assert_eq!(changes[0].new, ParameterValue::Integer(40));
+
+        let unknown = lib::synth::Synth::from_id(String::from("unknown"));
+        assert!(lib::state::request_all(&mut tracked, &unknown, Some(0), timeout).is_err());
+
+        // The resonance knob is moved once the dump is received
+        tracked.synth.program[5 + 15] = 41;
+        tracked.moves.push(vec![0xF0, 0x42, 0x30, 0x03, 0x41, 0x10, 0x1F, 0xF7]);
+        let changes = tracked.sync(|t| lib::state::request_all(t, s, Some(0), timeout)).unwrap();
+        let ids: Vec<&str> = changes.iter().map(|c| c.id.as_str()).collect();
+        assert_eq!(ids, vec!["cutoff", "resonance"]);
+        assert_eq!(changes[1].new, ParameterValue::Integer(31));
+    }
+
+    #[test]
+    fn test_parameter_request() {
+        use lib::midi_command::ParameterValue;
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dss-1.json").unwrap();
+        let mut s = conf.get_synth("dss-1").unwrap().clone();
+        assert_eq!(s.parameter_request.as_deref(), Some("pr"));
+        s.parameters.push(lib::synth::SynthParameter::from_json(&json::parse(r#"{ "id" : "cutoff", "number" : 20 }"#).unwrap()));
+        s.parameters.push(lib::synth::SynthParameter::from_json(&json::parse(r#"{ "id" : "resonance", "number" : 21 }"#).unwrap()));
+        s.parameters.push(lib::synth::SynthParameter::from_json(&json::parse(r#"{ "id" : "name", "offset" : 0 }"#).unwrap()));
+
+        // Answers parameter requests with a parameter change, the value being
+        // twice the parameter number
+        let synth = FakeDevice::new(|m| match m {
+            [0xF0, 0x42, 0x30, 0x0B, 0x10, p, 0xF7] => vec![vec![0xF0, 0x42, 0x30, 0x0B, 0x41, *p, *p * 2, 0xF7]],
+            _ => Vec::new(),
+        });
+        let mut tracked = Tracked::new(synth, &s);
+        let timeout = std::time::Duration::from_millis(10);
+        let changes = tracked.sync(|t| lib::state::request_all(t, &s, Some(0), timeout)).unwrap();
+        let requests: Vec<Vec<u8>> = tracked.synth.sent().into_iter().map(|(_, m)| m).collect();
+        assert_eq!(requests, vec![vec![0xF0, 0x42, 0x30, 0x0B, 0x10, 20, 0xF7], vec![0xF0, 0x42, 0x30, 0x0B, 0x10, 21, 0xF7]]);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(tracked.state.value("dss-1", 0, "cutoff"), Some(&ParameterValue::Integer(40)));
+        assert_eq!(tracked.state.value("dss-1", 0, "resonance"), Some(&ParameterValue::Integer(42)));
+
+        // A single parameter is requested alone
+        let resonance = s.get_parameter("resonance").unwrap();
+        let replies = lib::state::request(&mut tracked, &s, Some(0), resonance, timeout).unwrap();
+        assert_eq!(tracked.synth.sent().len(), 3);
+        assert_eq!(replies, vec![vec![0xF0, 0x42, 0x30, 0x0B, 0x41, 21, 42, 0xF7]]);
+    }
+
+    #[test]
+    fn test_panel_tracking() {
+        use lib::midi_command::ParameterValue;
+        use lib::state::Direction;
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let s = conf.get_synth("dw-8000").unwrap();
+        let midi = std::collections::HashMap::new();
+
+        let mut state = lib::state::SessionState::new();
+        state.update("dw-8000", 0, "cutoff", ParameterValue::Integer(34), Direction::Sent);
+
+        let knob = [0xF0, 0x42, 0x30, 0x03, 0x41, 0x0F, 0x28, 0xF7];
+        let changes = state.apply(Some(s), &midi, 0, &knob, Direction::Received);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].describe(Some(s)), "cutoff: 34 -> 40");
+        assert!(state.apply(Some(s), &midi, 0, &knob, Direction::Received).is_empty());
+
+        let waveform = [0xF0, 0x42, 0x30, 0x03, 0x41, 0x22, 0x03, 0xF7];
+        let changes = state.apply(Some(s), &midi, 0, &waveform, Direction::Received);
+        assert_eq!(changes[0].describe(Some(s)), "mg-waveform: Square");
+        assert!(state.apply(None, &midi, 0, &knob, Direction::Received).is_empty());
+    }
+
+    #[test]
+    fn test_sysex_assembler() {
+        use lib::midi_parser::{SysexAssembler, SysexError};
+
+        let mut a = SysexAssembler::new(16);
+        assert!(a.push(&[0xF0, 0x42, 0x30]).is_empty());
+        assert_eq!(a.push(&[0x03, 0xF8, 0x10]), vec![Ok(vec![0xF8])]);
+        assert_eq!(a.push(&[0xF7]), vec![Ok(vec![0xF0, 0x42, 0x30, 0x03, 0x10, 0xF7])]);
+        assert!(a.push(&[0x90, 0x40, 0x7F]).is_empty());
+
+        assert!(a.push(&[0xF0, 0x42]).is_empty());
+        assert_eq!(a.push(&[0x80, 0x40, 0x00, 0xF0, 0x43, 0xF7]), vec![
+            Err(SysexError::Truncated(vec![0xF0, 0x42])),
+            Ok(vec![0xF0, 0x43, 0xF7])
+        ]);
+
+        let mut long: Vec<u8> = vec![0xF0];
+        long.extend_from_slice(&[0x01; 20]);
+        assert_eq!(a.push(&long), vec![Err(SysexError::Overlong(16))]);
+        assert!(a.push(&[0x01, 0x02, 0xF7]).is_empty());
+        assert_eq!(a.push(&[0xF0, 0x7E, 0xF7]), vec![Ok(vec![0xF0, 0x7E, 0xF7])]);
+    }
+
+    #[test]
+    fn test_midi_stream_parser() {
+        use lib::midi_parser::{MIDIParser, MessageKind, CLOCK};
+
+        let mut p = MIDIParser::new();
+        let out: Vec<Vec<u8>> = p
+            .push(&[0x90, 0x40, 0x7F, 0x41, 0xF8, 0x7F, 0xFE, 0xC1, 0x05, 0x06, 0xF0, 0x42, 0xF8, 0x10, 0xF7, 0x40])
+            .into_iter()
+            .map(|m| m.unwrap())
+            .collect();
+        assert_eq!(out, vec![
+            vec![0x90, 0x40, 0x7F],
+            vec![0x90, 0x41, 0x7F],
+            vec![0xC1, 0x05],
+            vec![0xC1, 0x06],
+            vec![0xF0, 0x42, 0x10, 0xF7]
+        ]);
+
+        p.filter(CLOCK, false);
+        assert_eq!(p.push(&[0xB0, 0x07]), vec![]);
+        assert_eq!(p.push(&[0xF8, 0x64, 0xF2, 0x01, 0x02]), vec![Ok(vec![0xF8]), Ok(vec![0xB0, 0x07, 0x64]), Ok(vec![0xF2, 0x01, 0x02])]);
+        assert_eq!(p.push(&[0x10]), vec![]);
+
+        let out = p.push(&[0xF0, 0x42, 0x80, 0x40, 0x00]);
+        assert!(out[0].is_err());
+        assert_eq!(out[1], Ok(vec![0x80, 0x40, 0x00]));
+
+        // Chunks mixing channel messages and SysEx
+        assert_eq!(p.push(&[0x90, 0x40, 0x7F, 0xF0, 0x42]), vec![Ok(vec![0x90, 0x40, 0x7F])]);
+        assert_eq!(p.push(&[0x30, 0xF7, 0x90, 0x41, 0x7F, 0xF0, 0x7E, 0xF7]), vec![
+            Ok(vec![0xF0, 0x42, 0x30, 0xF7]),
+            Ok(vec![0x90, 0x41, 0x7F]),
+            Ok(vec![0xF0, 0x7E, 0xF7])
+        ]);
+
+        assert_eq!(MessageKind::of(&[0xF0, 0xF7]), Some(MessageKind::Sysex));
+        assert_eq!(MessageKind::of(&[0xF2, 0x00, 0x00]), Some(MessageKind::SystemCommon));
+        assert_eq!(MessageKind::of(&[0xE0, 0x00, 0x40]), Some(MessageKind::Channel));
+    }
+
+    #[test]
+    fn test_monitor() {
+        use lib::midi_parser::MessageKind;
+        use lib::state::Direction;
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        conf.run_file("data/midi.json").unwrap();
+
+        let mut m = lib::monitor::Monitor::new();
+        m.update_definitions(&conf);
+
+        let knob = [0xF0, 0x42, 0x31, 0x03, 0x41, 0x0F, 0x28, 0xF7];
+        let d = m.decode(&knob).unwrap();
+        assert_eq!(d.synth, "dw-8000");
+        assert_eq!(d.channel, Some(1));
+        assert_eq!(d.description, "Parameter Change (Channel 1, Parameter 15, Value 40)");
+        assert_eq!(
+            m.format(1500, Direction::Received, &knob).unwrap(),
+            "     1.500 IN  F0 42 31 03 41 0F 28 F7 : Korg (42) dw-8000 Parameter Change (Channel 1, Parameter 15, Value 40)"
+        );
+
+        let note = [0x92, 0x40, 0x7F];
+        assert_eq!(m.decode(&note).unwrap().synth, "midi");
+
+        m.filter.kinds.push(MessageKind::Sysex);
+        assert!(m.format(0, Direction::Sent, &note).is_none());
+        m.filter.kinds.push(MessageKind::Channel);
+        m.filter.channels.push(2);
+        assert!(m.format(0, Direction::Sent, &note).is_some());
+        assert!(m.format(0, Direction::Sent, &knob).is_none());
+        m.filter.channels.clear();
+        m.filter.synths.push(String::from("dw-8000"));
+        assert!(m.format(0, Direction::Sent, &note).is_none());
+        assert!(m.format(0, Direction::Sent, &knob).is_some());
+
+        // The channel is the parameter labelled Channel, whatever its key
+        conf.run_json(String::from(r#"{
+            "id" : "x", "manufacturer" : "Yamaha",
+            "commands" : [{ "name" : "Bulk", "midi" : "F0 43 n 0c F7", "alias" : "bulk", "parameters" : ["n : 2 : Number", "c : 1 : Channel"] }]
+        }"#)).unwrap();
+        m.update_definitions(&conf);
+        assert_eq!(m.decode(&[0xF0, 0x43, 0x05, 0x03, 0xF7]).unwrap().channel, Some(3));
+    }
+
+    #[test]
+    fn test_recorder() {
+        use lib::interpreter::InterpreterCommand;
+        use lib::recorder::Recorder;
+
+        let dir = std::env::temp_dir().join("midiprog-test-record");
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let knob = [0xF0, 0x42, 0x30, 0x03, 0x41, 0x0F, 0x28, 0xF7];
+        let dump = [0xF0, 0x42, 0x30, 0x03, 0x40, 0x00, 0xF7];
+
+        let file = dir.join("out.syx");
+        let mut r = Recorder::start(&file, false).unwrap();
+        r.record(&knob, None).unwrap();
+        r.record(&[0x90, 0x40, 0x7F], None).unwrap();
+        r.record(&dump, None).unwrap();
+        assert_eq!((r.messages, r.bytes), (2, 15));
+        drop(r);
+        assert_eq!(std::fs::read(&file).unwrap(), [&knob[..], &dump[..]].concat());
+
+        let split = dir.join("split");
+        let mut r = Recorder::start(&split, true).unwrap();
+        r.record(&knob, Some("Parameter Change")).unwrap();
+        r.record(&dump, None).unwrap();
+        assert_eq!(std::fs::read(split.join("0001_parameter-change.syx")).unwrap(), knob);
+        assert_eq!(std::fs::read(split.join("0002_unknown.syx")).unwrap(), dump);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let mut interpreter = lib::interpreter::Interpreter::new();
+        match interpreter.parse_command(String::from("record split takes")) {
+            Some(InterpreterCommand::Record(Some((dir, true)))) => assert_eq!(dir, "takes"),
+            _ => panic!("Expected a split recording"),
+        }
+        match interpreter.parse_command(String::from("record out.syx")) {
+            Some(InterpreterCommand::Record(Some((file, false)))) => assert_eq!(file, "out.syx"),
+            _ => panic!("Expected a recording"),
+        }
+        assert!(interpreter.parse_command(String::from("record split")).is_none());
+        assert!(interpreter.parse_command(String::from("record midi")).is_none());
+    }
+
+    #[test]
+    fn test_send_file() {
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let synths = conf.synths();
+
+        let mut program: Vec<u8> = vec![0xF0, 0x42, 0x30, 0x03, 0x40];
+        program.extend_from_slice(&[0x01; 51]);
+        program.push(0xF7);
+        let knob = vec![0xF0, 0x42, 0x30, 0x03, 0x41, 0x0F, 0x28, 0xF7];
+
+        let file = std::env::temp_dir().join("midiprog-test-send.syx");
+        std::fs::write(&file, [&program[..], &knob[..]].concat()).unwrap();
+        let messages = lib::syx::load(&file).unwrap();
+        assert_eq!(messages, vec![program.clone(), knob]);
+
+        // The program dump is acknowledged, the parameter change isn't
+        let mut synth = FakeDw8000 { program: Vec::new(), outbox: Default::default(), protected: false };
+        let handshake = conf.get_synth("dw-8000").unwrap().handshake.clone().unwrap();
+        let start = std::time::Instant::now();
+        assert_eq!(lib::syx::send_messages(&mut synth, &messages, &handshake, Some(&synths)).unwrap(), 2);
+        assert_eq!(synth.program[5 + 15], 0x28);
+        assert!(start.elapsed() < handshake.timeout);
+
+        let pace = lib::handshake::Handshake::default();
+
+        let unknown = vec![vec![0xF0, 0x41, 0x10, 0xF7]];
+        synth.program.clear();
+        assert!(lib::syx::send_messages(&mut synth, &[messages[0].clone(), unknown[0].clone()], &pace, Some(&synths)).is_err());
+        assert!(synth.program.is_empty());
+        assert_eq!(lib::syx::send_messages(&mut synth, &unknown, &pace, None).unwrap(), 1);
+
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn test_syx_info() {
+        let mut conf = lib::config::Config::new();
+        assert!(conf.run_directory("data").unwrap() >= 4);
+        conf.run_json(String::from(r#"{ "id" : "mt-32", "manufacturer" : "Roland", "address-map" : { "model" : "16" } }"#)).unwrap();
+        let synths = conf.synths();
+
+        let knob = [0xF0, 0x42, 0x30, 0x03, 0x41, 0x0F, 0x28, 0xF7];
+        let dt1 = [0xF0, 0x41, 0x10, 0x16, 0x12, 0x00, 0x00, 0x00, 0x7F, 0x01, 0xF7];
+        let bad = [0xF0, 0x41, 0x10, 0x16, 0x12, 0x00, 0x00, 0x00, 0x7F, 0x02, 0xF7];
+        let rq1 = [0xF0, 0x41, 0x10, 0x16, 0x11, 0x00, 0x00, 0x00, 0x00, 0x00, 0x7F, 0x01, 0xF7];
+        let data = [&knob[..], &[0x00, 0x01], &dt1[..], &bad[..], &[0xF0, 0x43, 0x90, 0xF7], &rq1[..], &[0xF0, 0x7E]].concat();
+
+        let infos = lib::syx::info(&data, &synths);
+        assert_eq!(infos.len(), 7);
+        assert_eq!(infos[2].checksum, Some(lib::syx::Checksum::Valid));
+        assert_eq!(infos[0].identified.as_ref().unwrap().synth, "dw-8000");
+        assert!(infos[0].is_valid());
+        assert_eq!(infos[1].offset, 8);
+        assert!(!infos[1].is_valid());
+        assert!(infos[2].is_valid());
+        assert_eq!(infos[3].problems, vec![String::from("Bad checksum")]);
+        assert_eq!(infos[4].problems, vec![String::from("Status byte 90 at offset 34")]);
+        assert_eq!(infos[5].checksum, Some(lib::syx::Checksum::Valid));
+        assert!(infos[5].is_valid());
+        let mut bad_rq1 = rq1;
+        bad_rq1[11] = 0x02;
+        assert_eq!(lib::syx::check_roland_checksum(&bad_rq1, &synths), Some(lib::syx::Checksum::Invalid));
+        assert_eq!(infos[6].problems, vec![String::from("Message not ended by F7")]);
+        assert_eq!(lib::syx::manufacturer_id(&[0xF0, 0x00, 0x20, 0x29, 0xF7]), Some(&[0x00, 0x20, 0x29][..]));
+    }
+
+    #[test]
+    fn test_manufacturers() {
+        use lib::manufacturers;
+
+        assert_eq!(manufacturers::describe(&[0x42]), "Korg (42)");
+        assert_eq!(manufacturers::describe(&[0x41]), "Roland (41)");
+        assert_eq!(manufacturers::describe(&[0x00, 0x20, 0x29]), "Focusrite/Novation (00 20 29)");
+        assert_eq!(manufacturers::describe(&[0x00, 0x7F, 0x7F]), "Unknown (00 7F 7F)");
+        assert_eq!(manufacturers::id("novation"), Some(&[0x00, 0x20, 0x29][..]));
+        assert_eq!(manufacturers::expand("F0 {manufacturer} 3n 41 p v F7", "Korg").unwrap(), "F0 42 3n 41 p v F7");
+        assert_eq!(manufacturers::expand("F0 {behringer} 00 F7", "").unwrap(), "F0 00 20 32 00 F7");
+        assert!(manufacturers::expand("F0 {manufacturer} F7", "Nobody").is_err());
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let s = conf.get_current_synth().unwrap();
+        assert!(s.commands.iter().all(|c| !c.midi.contains('{')));
+        assert!(s.get_command("parameter").unwrap().matches(&[0xF0, 0x42, 0x30, 0x03, 0x41, 0x0F, 0x28, 0xF7]));
+
+        let infos = lib::syx::info(&[0xF0, 0x41, 0x10, 0xF7], &[]);
+        assert_eq!(infos[0].to_string(), "offset 0, 4 bytes, manufacturer Roland (41) : unknown");
+
+        // Without an address map, the model ID length isn't known
+        let infos = lib::syx::info(&[0xF0, 0x41, 0x10, 0x00, 0x2B, 0x12, 0x00, 0x00, 0x7F, 0x01, 0xF7], &[]);
+        assert!(infos[0].is_valid());
+        assert_eq!(infos[0].to_string(), "offset 0, 11 bytes, manufacturer Roland (41) : unknown, checksum unknown");
+    }
+
+    #[test]
+    fn test_identity() {
+        use lib::identity::{IdentityReply, IDENTITY_REQUEST};
+
+        let mut device = FakeDevice::new(|m| {
+            if m == IDENTITY_REQUEST {
+                vec![vec![0xF0, 0x7E, 0x00, 0x06, 0x02, 0x42, 0x19, 0x00, 0x03, 0x00, 0x01, 0x00, 0x00, 0x00, 0xF7]]
+            } else {
+                Vec::new()
+            }
+        });
+        let replies = lib::identity::request(&mut device, std::time::Duration::from_millis(10)).unwrap();
+        assert_eq!(replies.len(), 1);
+        assert_eq!(replies[0].manufacturer, vec![0x42]);
+        assert_eq!(replies[0].family, vec![0x19, 0x00]);
+        assert_eq!(replies[0].to_string(), "Korg (42), family 19 00, model 03 00, version 01 00 00 00");
+
+        let behringer = IdentityReply::parse(&[0xF0, 0x7E, 0x10, 0x06, 0x02, 0x00, 0x20, 0x32, 0x01, 0x00, 0x02, 0x00, 0x01, 0x02, 0x03, 0x04, 0xF7]).unwrap();
+        assert_eq!(behringer.manufacturer, vec![0x00, 0x20, 0x32]);
+        assert_eq!(behringer.version, vec![0x01, 0x02, 0x03, 0x04]);
+        assert!(IdentityReply::parse(&IDENTITY_REQUEST).is_none());
+        assert!(IdentityReply::parse(&[0xF0, 0x7E, 0x00, 0x06, 0x02, 0x00, 0xF7]).is_none());
+        assert!(IdentityReply::parse(&[0xF0, 0x7E, 0x00, 0x06, 0x02]).is_none());
+        assert!(IdentityReply::parse(&[0xF0, 0x7E, 0x00, 0x06, 0x02, 0x42, 0x19, 0x00, 0xF7]).is_none());
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        conf.run_json(String::from(r#"{ "id" : "x5", "manufacturer" : "Korg", "identity" : { "family" : "19 00" } }"#)).unwrap();
+        conf.run_json(String::from(r#"{ "id" : "rd8", "manufacturer" : "Behringer", "identity" : { "family" : "01 00", "model" : "02 00" } }"#)).unwrap();
+
+        assert_eq!(lib::identity::find_synth(conf.synths(), &replies[0]).unwrap().id, "x5");
+        assert_eq!(lib::identity::find_synth(conf.synths(), &behringer).unwrap().id, "rd8");
+        let mut other = behringer.clone();
+        other.model = vec![0x05, 0x00];
+        assert!(lib::identity::find_synth(conf.synths(), &other).is_none());
+
+        let result = conf.run_json(String::from(r#"{ "id" : "z", "identity" : { "manufacturer" : "Nobody" } }"#));
+        match result {
+            Err(lib::config::ConfigError::CommonError(e)) => assert_eq!(e, "Bad identity manufacturer Nobody"),
+            _ => panic!("Expected a bad identity"),
+        }
+        assert!(conf.run_json(String::from(r#"{ "id" : "z", "identity" : { "family" : "1G 00" } }"#)).is_err());
+        assert!(conf.run_json(String::from(r#"{ "id" : "z", "identity" : { "manufacturer" : "00 20 32" } }"#)).is_ok());
+    }
+
+    type Sent = (std::time::Instant, Vec<u8>);
+    type Script = dyn FnMut(&[u8]) -> Vec<Vec<u8>> + Send;
+
+    /// MIDI device keeping the messages sent, with the time they were sent
+    /// at, and answering each of them with the replies of a script. Clones
+    /// share the messages and the replies.
+    #[derive(Clone)]
+    struct FakeDevice {
+        sent: std::sync::Arc<std::sync::Mutex<Vec<Sent>>>,
+        script: std::sync::Arc<std::sync::Mutex<Box<Script>>>,
+        inbox: std::sync::mpsc::Sender<Vec<u8>>,
+        replies: std::sync::Arc<std::sync::Mutex<std::sync::mpsc::Receiver<Vec<u8>>>>,
+    }
+
+    impl FakeDevice {
+        fn new<F: FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static>(script: F) -> FakeDevice {
+            let (inbox, replies) = std::sync::mpsc::channel();
+            FakeDevice::with_inbox(inbox, replies, script)
+        }
+
+        fn with_inbox<F: FnMut(&[u8]) -> Vec<Vec<u8>> + Send + 'static>(
+            inbox: std::sync::mpsc::Sender<Vec<u8>>,
+            replies: std::sync::mpsc::Receiver<Vec<u8>>,
+            script: F,
+        ) -> FakeDevice {
+            FakeDevice {
+                sent: Default::default(),
+                script: std::sync::Arc::new(std::sync::Mutex::new(Box::new(script))),
+                inbox,
+                replies: std::sync::Arc::new(std::sync::Mutex::new(replies)),
+            }
+        }
+
+        fn sent(&self) -> Vec<Sent> {
+            self.sent.lock().unwrap().clone()
+        }
+    }
+
+    impl lib::midi_interface::MidiTransport for FakeDevice {
+        fn send(&mut self, data: &[u8]) -> Result<(), lib::midi_interface::MidiInterfaceError> {
+            self.sent.lock().unwrap().push((std::time::Instant::now(), data.to_vec()));
+            for reply in (self.script.lock().unwrap())(data) {
+                let _ = self.inbox.send(reply);
+            }
+            Ok(())
+        }
+
+        fn receive(&mut self, timeout: std::time::Duration) -> Option<Vec<u8>> {
+            self.replies.lock().unwrap().recv_timeout(timeout).ok()
+        }
+    }
+
+    /// Both ends of a MIDI cable looped back, the second one corrupting the
+    /// nth message it sends if asked to
+    fn loopback(corrupt: Option<usize>) -> (FakeDevice, FakeDevice) {
+        let (a_inbox, a_replies) = std::sync::mpsc::channel();
+        let (b_inbox, b_replies) = std::sync::mpsc::channel::<Vec<u8>>();
+
+        let to_b = b_inbox.clone();
+        let a = FakeDevice::with_inbox(a_inbox.clone(), a_replies, move |m| {
+            let _ = to_b.send(m.to_vec());
+            Vec::new()
+        });
+
+        let mut sent = 0;
+        let b = FakeDevice::with_inbox(b_inbox, b_replies, move |m| {
+            let mut m = m.to_vec();
+            if corrupt == Some(sent) {
+                m[8] ^= 0x01;
+            }
+            sent += 1;
+            let _ = a_inbox.send(m);
+            Vec::new()
+        });
+
+        (a, b)
+    }
+
+    #[test]
+    fn test_sample_dump() {
+        use lib::sds::{LoopType, Sample};
+
+        let data: Vec<i32> = (0..500).map(|i| (i * 37 % 4096) - 2048).collect();
+        let mut sample = Sample::new(12, 31250, data);
+        sample.number = 5;
+        sample.loop_type = LoopType::Forward;
+        sample.loop_start = 100;
+        sample.loop_end = 400;
+        assert_eq!(sample.header(0).len(), 21);
+        assert_eq!(Sample::from_header(&sample.header(0)).unwrap().1, 500);
+
+        let packets = sample.packets(0);
+        assert_eq!(packets.len(), 9);
+        assert!(packets.iter().all(|p| p.len() == 127 && lib::sds::parse_packet(p).unwrap().2));
+
+        // The third packet gets corrupted once, and is sent again
+        let (mut sampler, mut computer) = loopback(Some(3));
+        let device = std::thread::spawn(move || lib::sds::receive(&mut sampler, 0, None));
+        assert_eq!(lib::sds::send(&mut computer, 0, &sample).unwrap(), 9);
+        assert_eq!(computer.sent().len(), 11);
+        assert_eq!(device.join().unwrap().unwrap(), sample);
+
+        assert_eq!(lib::sds::dump_request(2, 300), vec![0xF0, 0x7E, 0x02, 0x03, 0x2C, 0x02, 0xF7]);
+
+        let path = std::env::temp_dir().join("midiprog-test-sample.wav");
+        lib::wav::write(&path, &sample).unwrap();
+        let wav = lib::wav::read(&path).unwrap();
+        assert_eq!(wav.bits, 16);
+        assert_eq!(wav.rate(), 31250);
+        assert_eq!((wav.loop_type, wav.loop_start, wav.loop_end), (LoopType::Forward, 100, 400));
+        assert!(wav.data.iter().zip(&sample.data).all(|(w, s)| *w == s << 4));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_handshake() {
+        use lib::handshake::{HandshakeError, Response};
+
+        // Answers each message sent with the next replies of a script
+        let receiver = |script: Vec<Vec<Vec<u8>>>| {
+            let mut script: std::collections::VecDeque<Vec<Vec<u8>>> = script.into();
+            FakeDevice::new(move |_| script.pop_front().unwrap_or_default())
+        };
+        let completed = vec![0xF0, 0x42, 0x30, 0x03, 0x23, 0xF7];
+        let error = vec![0xF0, 0x42, 0x30, 0x03, 0x24, 0xF7];
+        let busy = vec![0xF0, 0x42, 0x30, 0x03, 0x25, 0xF7];
+
+        let mut conf = lib::config::Config::new();
+        conf.run_file("data/dw-8000.json").unwrap();
+        let mut handshake = conf.get_synth("dw-8000").unwrap().handshake.clone().unwrap();
+        assert_eq!(handshake.ack[1].midi, "F0 42 3n 03 23 F7");
+        assert_eq!(handshake.classify(&completed), Some(Response::Ack));
+        assert_eq!(handshake.classify(&error), Some(Response::Error));
+
+        // Only dumps and Write Requests are answered
+        let knob = [0xF0, 0x42, 0x30, 0x03, 0x41, 0x0F, 0x28, 0xF7];
+        let write = [0xF0, 0x42, 0x30, 0x03, 0x11, 0x05, 0xF7];
+        assert_eq!(handshake.classify(&knob), None);
+        assert!(!handshake.applies_to(&knob));
+        assert!(handshake.applies_to(&write));
+        let start = std::time::Instant::now();
+        assert!(!handshake.send(&mut receiver(Vec::new()), &knob).unwrap());
+        assert!(start.elapsed() < handshake.timeout);
+        assert!(handshake.send(&mut receiver(vec![vec![vec![0xF0, 0x42, 0x30, 0x03, 0x21, 0xF7]]]), &write).unwrap());
+
+        handshake.timeout = std::time::Duration::from_millis(10);
+        handshake.wait = vec![lib::handshake::Handshake::reply("Busy", "F0 42 3n 03 25 F7")];
+        handshake.cancel = vec![lib::handshake::Handshake::reply("Cancel", "F0 42 3n 03 26 F7")];
+        let dump = vec![vec![0xF0, 0x42, 0x30, 0x03, 0x40, 0xF7]; 2];
+
+        // Busy then refused, accepted when sent again
+        let mut synth = receiver(vec![vec![busy.clone(), error.clone()], vec![completed.clone()], vec![completed.clone()]]);
+        assert_eq!(handshake.send_all(&mut synth, &dump).unwrap(), 2);
+        assert_eq!(synth.sent().len(), 3);
+
+        let mut synth = receiver(vec![vec![error.clone()], vec![error.clone()], vec![completed.clone()]]);
+        assert!(matches!(handshake.send(&mut synth, &dump[0]), Err(HandshakeError::Refused(2))));
+
+        let mut synth = receiver(vec![vec![vec![0xF0, 0x42, 0x30, 0x03, 0x26, 0xF7]]]);
+        assert!(matches!(handshake.send(&mut synth, &dump[0]), Err(HandshakeError::Cancelled)));
+
+        assert!(matches!(handshake.send(&mut receiver(Vec::new()), &dump[0]), Err(HandshakeError::NoReply)));
+        handshake.continue_on_timeout = true;
+        assert!(!handshake.send(&mut receiver(Vec::new()), &dump[0]).unwrap());
+    }
+
+    #[test]
+    fn test_address_map() {
+        use lib::address_map::{to_linear, AddressMap};
+
+        let mut conf = lib::config::Config::new();
+        conf.run_json(String::from(r#"{
+            "id" : "gs", "manufacturer" : "Roland",
+            "address-map" : {
+                "model" : "42", "device" : "10", "packet-size" : 128,
+                "areas" : [
+                    { "name" : "patch", "address" : "01 00 00", "size" : "00 02 00" },
+                    { "name" : "cutoff", "address" : "01 00 05", "size" : 1 }
+                ]
+            }
+        }"#)).unwrap();
+        let synth = conf.get_synth("gs").unwrap();
+        let map = synth.address_map.as_ref().unwrap();
+        let patch = map.area("patch").unwrap();
+        assert_eq!((patch.size, map.address_size), (256, 3));
+
+        // GS reset
+        let reset = map.writes(to_linear(&[0x40, 0x00, 0x7F]), &[0x00]);
+        assert_eq!(reset, vec![vec![0xF0, 0x41, 0x10, 0x42, 0x12, 0x40, 0x00, 0x7F, 0x00, 0x41, 0xF7]]);
+
+        let requests = map.requests(patch.address, patch.size);
+        assert_eq!(requests.len(), 2);
+        assert_eq!(requests[1][4..11], [0x11, 0x01, 0x01, 0x00, 0x00, 0x01, 0x00]);
+
+        // Memory from 01 00 00, sending back DT1 messages of 100 bytes at most
+        let memory = std::sync::Arc::new(std::sync::Mutex::new((0..512).map(|i| (i % 128) as u8).collect::<Vec<u8>>()));
+        let roland_map = AddressMap { packet_size: 100, ..map.clone() };
+        let roland_memory = memory.clone();
+        let mut roland = FakeDevice::new(move |data| {
+            let base = to_linear(&[0x01, 0x00, 0x00]);
+            let mut memory = roland_memory.lock().unwrap();
+            if data[4] == 0x11 {
+                let (address, size) = (to_linear(&data[5..8]), to_linear(&data[8..11]) as usize);
+                let start = (address - base) as usize;
+                return roland_map.writes(address, &memory[start..start + size]);
+            } else if let Some(Ok((address, bytes))) = roland_map.parse_dt1(data) {
+                let start = (address - base) as usize;
+                memory[start..start + bytes.len()].copy_from_slice(&bytes);
+            }
+            Vec::new()
+        });
+        let timeout = std::time::Duration::from_millis(10);
+        assert_eq!(map.read(&mut roland, patch.address, patch.size, timeout).unwrap(), memory.lock().unwrap()[..256].to_vec());
+
+        let data: Vec<u8> = (0..200).map(|i| (127 - i % 128) as u8).collect();
+        let pace = lib::handshake::Handshake::default();
+        assert_eq!(map.write(&mut roland, patch.address + 10, &data, &pace).unwrap(), 2);
+        assert_eq!(memory.lock().unwrap()[10..210].to_vec(), data);
+        assert_eq!(map.read(&mut roland, patch.address + 10, 200, timeout).unwrap(), data);
+
+        let cutoff = map.writes(patch.address + 5, &[0x40]).remove(0);
+        assert_eq!(lib::monitor::decode(vec![synth], &cutoff).unwrap().name, "DT1 cutoff");
+        let mut corrupted = map.writes(patch.address + 6, &[0x40]).remove(0);
+        assert_eq!(lib::monitor::decode(vec![synth], &corrupted).unwrap().name, "DT1 patch + 6");
+        corrupted[8] = 0x41;
+        assert!(map.parse_dt1(&corrupted).unwrap().is_err());
+
+        let bad = json::parse(r#"{ "model" : "42", "areas" : [ { "name" : "patch", "address" : "03 0G 00" } ] }"#).unwrap();
+        assert_eq!(AddressMap::from_json(&bad).unwrap_err(), "Bad address of patch");
+        let bad = json::parse(r#"{ "model" : "42", "areas" : [ { "name" : "patch", "address" : "03 00 00", "size" : "0X" } ] }"#).unwrap();
+        assert_eq!(AddressMap::from_json(&bad).unwrap_err(), "Bad size of patch");
+        assert!(AddressMap::from_json(&json::parse(r#"{ "model" : "4" }"#).unwrap()).is_err());
+        assert!(AddressMap::from_json(&json::parse(r#"{ "model" : "42", "device" : "ten" }"#).unwrap()).is_err());
+    }
+
+    #[test]
+    fn test_address_commands() {
+        use lib::interpreter::InterpreterCommand;
+
+        let mut interpreter = lib::interpreter::Interpreter::new();
+        match interpreter.parse_command(String::from("rq1 \"01 00 10\" 4")) {
+            Some(InterpreterCommand::AddressRead(target, size)) => {
+                assert_eq!(target, "01 00 10");
+                assert_eq!(size, Some(4));
+            }
+            _ => panic!("Expected an address read"),
+        }
+        match interpreter.parse_command(String::from("rq1 patch")) {
+            Some(InterpreterCommand::AddressRead(target, size)) => assert_eq!((target.as_str(), size), ("patch", None)),
+            _ => panic!("Expected an area read"),
+        }
+        match interpreter.parse_command(String::from("dt1 \"01 00 05\" \"40 41\"")) {
+            Some(InterpreterCommand::AddressWrite(target, data)) => assert_eq!((target.as_str(), data), ("01 00 05", vec![0x40, 0x41])),
+            _ => panic!("Expected an address write"),
+        }
+        match interpreter.parse_command(String::from("dt1 cutoff 40")) {
+            Some(InterpreterCommand::AddressWrite(target, data)) => assert_eq!((target.as_str(), data), ("cutoff", vec![0x40])),
+            _ => panic!("Expected an area write"),
+        }
+    }
+
+    #[test]
+    fn test_smf_player() {
+        use lib::smf::{EventKind, Smf};
+        use std::time::Duration;
+
+        let tempo = [0x00, 0xFF, 0x51, 0x03, 0x03, 0xD0, 0x90, 0x60, 0xFF, 0x51, 0x03, 0x07, 0xA1, 0x20, 0x60, 0xFF, 0x2F, 0x00];
+        let notes = [
+            0x00, 0x90, 0x3C, 0x64, 0x60, 0x3C, 0x00, 0x00, 0xF0, 0x04, 0x43, 0x10, 0x01, 0xF7, 0x60, 0xC0, 0x05, 0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let mut file = b"MThd".to_vec();
+        file.extend(&[0, 0, 0, 6, 0, 1, 0, 2, 0, 96]);
+        for track in [&tempo[..], &notes[..]].iter() {
+            file.extend(b"MTrk");
+            file.extend(&(track.len() as u32).to_be_bytes());
+            file.extend(*track);
+        }
+
+        let smf = Smf::parse(&file).unwrap();
+        assert_eq!((smf.format, smf.division, smf.tracks.len()), (1, 96, 2));
+        assert_eq!(smf.tracks[0][0].kind, EventKind::Tempo(250_000));
+        assert_eq!(smf.tracks[1][1].kind, EventKind::Midi(vec![0x90, 0x3C, 0x00]));
+
+        // 96 ticks at 240 BPM, then 96 ticks at 120 BPM
+        let (messages, length) = smf.timeline();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[1].0, Duration::from_millis(250));
+        assert_eq!(messages[2], (Duration::from_millis(250), vec![0xF0, 0x43, 0x10, 0x01, 0xF7]));
+        assert_eq!(messages[3], (Duration::from_millis(750), vec![0xC0, 0x05]));
+        assert_eq!(length, Duration::from_millis(750));
+
+        let sink = FakeDevice::new(|_| Vec::new());
+        let phrase = vec![
+            (Duration::from_millis(0), vec![0x92, 0x3C, 0x64]),
+            (Duration::from_millis(30), vec![0x82, 0x3C, 0x00]),
+            (Duration::from_millis(30), vec![0xF0, 0x43, 0x10, 0x01, 0xF7]),
+        ];
+        let mut player = lib::player::Player::start(sink.clone(), phrase.clone(), Duration::from_millis(40), Some(1), false);
+        while player.is_playing() {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        player.stop();
+
+        let sent = sink.sent();
+        assert_eq!(sent.len(), 3 + 16);
+        assert_eq!(sent[0].1, vec![0x91, 0x3C, 0x64]);
+        assert_eq!(sent[1].1, vec![0x81, 0x3C, 0x00]);
+        assert!(sent[1].0 - sent[0].0 >= Duration::from_millis(30));
+        assert_eq!(sent[2].1, phrase[2].1);
+        assert_eq!(sent[18].1, vec![0xBF, 0x7B, 0x00]);
+
+        // Loops until stopped, however long the machine takes to play it
+        let sink = FakeDevice::new(|_| Vec::new());
+        let notes = |sink: &FakeDevice| sink.sent().iter().filter(|(_, m)| m[0] == 0x92).count();
+        let mut player = lib::player::Player::start(sink.clone(), phrase, Duration::from_millis(20), None, true);
+        let deadline = std::time::Instant::now() + Duration::from_secs(10);
+        while notes(&sink) < 3 && std::time::Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        assert!(player.is_playing());
+        player.stop();
+        assert!(!player.is_playing());
+
+        let sent = sink.sent();
+        assert!(notes(&sink) >= 3);
+        assert!(sent[sent.len() - 16..].iter().all(|(_, m)| m[1] == 0x7B));
+    }
+
+    #[test]
+    fn test_record_midi_file() {
+        use lib::recorder::MidiFileRecorder;
+        use lib::smf::{EventKind, Smf};
+        use lib::state::Direction;
+
+        let path = std::env::temp_dir().join("midiprog-test-record.mid");
+        let patch = vec![0xF0, 0x42, 0x30, 0x03, 0x41, 0x05, 0x1F, 0xF7];
+
+        // 96 ticks per quarter note at 120 BPM, input timestamps in µs
+        let record = |format: u16| {
+            let mut recorder = MidiFileRecorder::start(&path, format, 96, 120.0).unwrap();
+            recorder.record(Some(7_000_000), Direction::Received, &[0x90, 0x3C, 0x64]);
+            recorder.record(None, Direction::Sent, &patch);
+            recorder.record(Some(7_100_000), Direction::Received, &[0xFE]);
+            recorder.record(Some(7_500_000), Direction::Received, &[0x80, 0x3C, 0x00]);
+            recorder.record(Some(8_250_000), Direction::Received, &[0xC0, 0x05]);
+            recorder
+        };
+
+        let recorder = record(1);
+        assert_eq!(recorder.messages(), 4);
+        recorder.finish().unwrap();
+
+        let smf = Smf::read(&path).unwrap();
+        assert_eq!((smf.format, smf.division, smf.tracks.len()), (1, 96, 3));
+        assert_eq!(smf.tracks[0][0].kind, EventKind::Tempo(500_000));
+
+        let received: Vec<(u64, EventKind)> = smf.tracks[1].iter().map(|e| (e.tick, e.kind.clone())).collect();
+        assert_eq!(received[1], (0, EventKind::Midi(vec![0x90, 0x3C, 0x64])));
+        assert_eq!(received[2], (96, EventKind::Midi(vec![0x80, 0x3C, 0x00])));
+        assert_eq!(received[3], (240, EventKind::Midi(vec![0xC0, 0x05])));
+        assert_eq!(received[4].1, EventKind::EndOfTrack);
+        assert_eq!(smf.tracks[2][1].kind, EventKind::Midi(patch.clone()));
+
+        let smf = Smf::parse(&record(0).to_smf().to_bytes()).unwrap();
+        assert_eq!(smf.tracks.len(), 1);
+        let (messages, length) = smf.timeline();
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[3].1, vec![0xC0, 0x05]);
+        assert_eq!(length, std::time::Duration::from_millis(1250));
+
+        // Tempo events hold 24 bits, down to about 3.58 BPM
+        assert_eq!(lib::smf::tempo(3.6), Some(16_666_666));
+        assert!(lib::smf::tempo(3.5).is_none());
+        assert!(MidiFileRecorder::start(&path, 1, 96, 3.5).is_err());
+        let mut interpreter = lib::interpreter::Interpreter::new();
+        assert!(interpreter.parse_command(String::from("record midi x.mid bpm=3")).is_none());
+        assert!(interpreter.parse_command(String::from("record midi x.mid bpm=4")).is_some());
+    }
+
+    #[test]
+    fn test_clock() {
+        use lib::clock::{format_position, ClockCommand, ClockFollower, ClockMaster};
+        use std::time::{Duration, Instant};
+
+        // 250 BPM : 10 ms between clock messages, the bounds leaving room
+        // for loaded machines
+        let sink = FakeDevice::new(|_| Vec::new());
+        let clock_count = |sink: &FakeDevice| sink.sent().iter().filter(|(_, m)| m[..] == [0xF8]).count();
+        let mut clock = ClockMaster::start(sink.clone(), 250.0);
+        clock.send(ClockCommand::Position(0x90));
+        clock.send(ClockCommand::Continue);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while clock_count(&sink) < 30 && Instant::now() < deadline {
+            std::thread::sleep(Duration::from_millis(10));
+        }
+        assert!(clock.is_playing());
+        clock.stop();
+
+        let sent = sink.sent();
+        let transport: Vec<Vec<u8>> = sent.iter().map(|(_, m)| m.clone()).filter(|m| m[..] != [0xF8]).collect();
+        assert_eq!(transport, vec![vec![0xF2, 0x10, 0x01], vec![0xFB], vec![0xFC]]);
+
+        let clocks: Vec<Instant> = sent.iter().filter(|(_, m)| m[..] == [0xF8]).map(|(at, _)| *at).collect();
+        assert!(clocks.len() >= 30);
+        let average = (*clocks.last().unwrap() - clocks[0]) / (clocks.len() as u32 - 1);
+        assert!(average > Duration::from_millis(8) && average < Duration::from_millis(20));
+        assert!(clock.position() >= 0x90 + 2);
+
+        let mut follower = ClockFollower::new();
+        follower.receive(0, &[0xF2, 0x08, 0x00]);
+        follower.receive(0, &[0xFB]);
+        assert_eq!(follower.bpm(), None);
+        for i in 0..48 {
+            // 125 BPM, with a little jitter
+            follower.receive(1_000_000 + i * 20_000 + (i % 3) * 300, &[0xF8]);
+        }
+        assert!((follower.bpm().unwrap() - 125.0).abs() < 0.5);
+        assert_eq!(format_position(follower.position()), "2.1.1");
+
+        follower.receive(3_000_000, &[0xFC]);
+        follower.receive(4_000_000, &[0xF8]);
+        assert!(!follower.playing);
+        assert_eq!(follower.position(), 16);
+
+        // Clock messages are dropped again only if they were before following
+        let mut interpreter = lib::interpreter::Interpreter::new();
+        for (line, filtered) in [("clock follow", false), ("clock follow off", true), ("realtime pass clock", false), ("clock follow", false), ("clock follow off", false)] {
+            let command = interpreter.parse_command(String::from(line)).unwrap();
+            interpreter.run_command(command).unwrap();
+            assert_eq!(interpreter.is_realtime_filtered(0xF8), filtered, "{}", line);
+        }
+    }
+
     #[test]
     fn test_parser() {
         let c = lib::command_parser::CommandParser::parse_command(String::from("command -name \"Program Parameter Request\" -midi \"F0 42 3n 0B 10 p F7\" -@parameter \"n : 1 : Channel\" -@parameter \"p : 2 : Parameter\" -alias \"pr\"")).unwrap();
@@ -81,9 +1340,9 @@ mod tests {
         let cp = lib::command_parser::CommandParser::parse_command(String::from("command -name \"Program Parameter Change\" -midi \"F0 41 3n 0B 10 p v F7\" -@parameter \"n : 0.5 : Channel\" -@parameter \"p : 1 : Parameter\" -@parameter \"v : 1 : Value\" -alias \"pc param-change\"")).unwrap();
         let mut conf = lib::config::Config::new();
 
-        conf.run_command(&cs);
-        conf.run_command(&cc);
-        conf.run_command(&cp);
+        conf.run_command(&cs).unwrap();
+        conf.run_command(&cc).unwrap();
+        conf.run_command(&cp).unwrap();
 
         let s: &lib::synth::Synth = conf.get_current_synth().expect("No synth loaded");
         assert_eq!(s.name, "Juno-2");
@@ -104,7 +1363,7 @@ mod tests {
             "synth -id \"ju-2\" -name \"Alpha Juno-2\" -manufacturer \"Roland\"",
             "command -name \"Program Parameter Request\" -midi \"F0 42 3n 0B 10 p F7\" -@parameter \"n : 1 : Channel\" -@parameter \"p : 2 : Parameter\" -alias \"pr\"",
             "command -name \"Program Parameter Change\" -midi \"F0 41 3n 0B 10 p v F7\" -@parameter \"n : 1 : Channel\" -@parameter \"p : 2 : Parameter\" -@parameter \"v : 2 : Value\" -alias \"pc param-change\""
-        ]);
+        ]).unwrap();
 
         let s: &lib::synth::Synth = conf.get_current_synth().expect("No synth loaded");
         assert_eq!(s.name, "Alpha Juno-2");
@@ -115,6 +1374,16 @@ mod tests {
         assert!(s.has_command("pc"));
         assert!(s.has_command("param-change"));
         assert!(s.has_command("Program Parameter Change"));
+
+        let mut conf = lib::config::Config::new();
+        let result = conf.run_commands_str(&[
+            "synth -id \"x\" -manufacturer \"Nobody\"",
+            "command -name \"Request\" -midi \"F0 {manufacturer} 10 F7\" -alias \"rq\""
+        ]);
+        match result {
+            Err(lib::config::ConfigError::CommonError(e)) => assert_eq!(e, "Unknown manufacturer Nobody"),
+            _ => panic!("Expected an unknown manufacturer"),
+        }
     }
 
     #[test]
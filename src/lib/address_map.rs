@@ -0,0 +1,243 @@
+use super::handshake::{Handshake, HandshakeError};
+use super::midi_interface::*;
+use super::utils::{btos, parse_hex, roland_checksum};
+use std::time::Duration;
+
+use derive_more::*;
+
+pub const ROLAND: u8 = 0x41;
+pub const RQ1: u8 = 0x11;
+pub const DT1: u8 = 0x12;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, From)]
+pub enum AddressMapError {
+    InterfaceError(MidiInterfaceError),
+    HandshakeError(HandshakeError),
+    CommonError(String),
+}
+
+impl std::fmt::Display for AddressMapError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AddressMapError::InterfaceError(e) => {
+                write!(f, "{}", e)
+            }
+
+            AddressMapError::HandshakeError(e) => {
+                write!(f, "{}", e)
+            }
+
+            AddressMapError::CommonError(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+/// Address as a number, from its 7 bit bytes
+pub fn to_linear(bytes: &[u8]) -> u32 {
+    bytes.iter().fold(0, |acc, &b| acc << 7 | (b & 0x7F) as u32)
+}
+
+/// Address as 7 bit bytes, most significant first
+pub fn from_linear(value: u32, size: usize) -> Vec<u8> {
+    (0..size).rev().map(|i| ((value >> (i * 7)) & 0x7F) as u8).collect()
+}
+
+/// Named block of the synth's memory, e.g. a patch or a single parameter
+#[derive(Debug, Clone)]
+pub struct Area {
+    pub name: String,
+    pub address: u32,
+    pub size: u32,
+}
+
+/// Memory of a Roland synth, read with RQ1 and written with DT1 :
+/// F0 41 device model 11 address size sum F7
+/// F0 41 device model 12 address data sum F7
+#[derive(Debug, Clone)]
+pub struct AddressMap {
+    pub model: Vec<u8>,
+    pub device: u8,
+    /// Bytes of an address, and of a size in RQ1
+    pub address_size: usize,
+    /// Most data bytes a single message carries
+    pub packet_size: u32,
+    pub areas: Vec<Area>,
+}
+
+impl AddressMap {
+    /// Parses an address map, sizes being numbers or 7 bit bytes like
+    /// addresses :
+    /// { "model" : "16", "device" : "10", "packet-size" : 128, "areas" : [
+    ///   { "name" : "patch", "address" : "03 00 00", "size" : "00 01 00" } ] }
+    pub fn from_json(val: &json::JsonValue) -> Result<AddressMap, String> {
+        let model = val["model"].as_str().ok_or("Missing address map model")?;
+        let model = parse_hex(model).ok_or(format!("Bad address map model {}", model))?;
+        let mut address_size = val["address-size"].as_usize().unwrap_or(0);
+        let mut areas = Vec::new();
+
+        for a in val["areas"].members() {
+            let name = a["name"].as_str().ok_or("Missing area name")?;
+            let address = a["address"].as_str().ok_or(format!("Missing address of {}", name))?;
+            let address = parse_hex(address).ok_or(format!("Bad address of {}", name))?;
+            if address_size == 0 {
+                address_size = address.len();
+            }
+
+            let size = match a["size"].as_str() {
+                Some(s) => to_linear(&parse_hex(s).ok_or(format!("Bad size of {}", name))?),
+                None => a["size"].as_u32().unwrap_or(1),
+            };
+            areas.push(Area { name: name.to_owned(), address: to_linear(&address), size });
+        }
+
+        let device = match val["device"].as_str() {
+            Some(d) => parse_hex(d).and_then(|d| d.first().copied()).ok_or(format!("Bad address map device {}", d))?,
+            None => 0x10,
+        };
+
+        Ok(AddressMap {
+            model,
+            device,
+            address_size: if address_size == 0 { 4 } else { address_size },
+            packet_size: val["packet-size"].as_u32().unwrap_or(128).max(1),
+            areas,
+        })
+    }
+
+    pub fn area(&self, name: &str) -> Option<&Area> {
+        self.areas.iter().find(|a| a.name == name)
+    }
+
+    /// Area covering an address, with the offset of the address in it
+    pub fn area_at(&self, address: u32) -> Option<(&Area, u32)> {
+        self.areas
+            .iter()
+            .filter(|a| address >= a.address && address < a.address + a.size)
+            .min_by_key(|a| a.size)
+            .map(|a| (a, address - a.address))
+    }
+
+    pub fn format_address(&self, address: u32) -> String {
+        btos(&from_linear(address, self.address_size))
+    }
+
+    fn message(&self, command: u8, body: &[u8]) -> Vec<u8> {
+        let mut m = vec![0xF0, ROLAND, self.device];
+        m.extend(&self.model);
+        m.push(command);
+        m.extend(body);
+        m.push(roland_checksum(body));
+        m.push(0xF7);
+        m
+    }
+
+    /// Splits a range into packets, as (address, size)
+    fn packets(&self, address: u32, size: u32) -> Vec<(u32, u32)> {
+        (0..size)
+            .step_by(self.packet_size as usize)
+            .map(|offset| (address + offset, self.packet_size.min(size - offset)))
+            .collect()
+    }
+
+    /// RQ1 messages reading a range, one per packet
+    pub fn requests(&self, address: u32, size: u32) -> Vec<Vec<u8>> {
+        self.packets(address, size)
+            .into_iter()
+            .map(|(a, s)| {
+                let mut body = from_linear(a, self.address_size);
+                body.extend(from_linear(s, self.address_size));
+                self.message(RQ1, &body)
+            })
+            .collect()
+    }
+
+    /// DT1 messages writing data from an address, one per packet
+    pub fn writes(&self, address: u32, data: &[u8]) -> Vec<Vec<u8>> {
+        self.packets(address, data.len() as u32)
+            .into_iter()
+            .map(|(a, s)| {
+                let offset = (a - address) as usize;
+                let mut body = from_linear(a, self.address_size);
+                body.extend(&data[offset..offset + s as usize]);
+                self.message(DT1, &body)
+            })
+            .collect()
+    }
+
+    /// Address and data of a DT1 message from this synth, None for other
+    /// messages. Fails when its checksum is wrong.
+    pub fn parse_dt1(&self, message: &[u8]) -> Option<Result<(u32, Vec<u8>), AddressMapError>> {
+        let header = 3 + self.model.len() + 1;
+        if message.len() < header + self.address_size + 2
+            || message[1] != ROLAND
+            || message[3..header - 1] != self.model[..]
+            || message[header - 1] != DT1
+        {
+            return None;
+        }
+
+        let end = message.len() - 2;
+        let body = &message[header..end];
+        if roland_checksum(body) != message[end] {
+            return Some(Err(AddressMapError::CommonError(format!(
+                "Bad checksum in DT1 at {}",
+                btos(&body[..self.address_size])
+            ))));
+        }
+
+        let address = to_linear(&body[..self.address_size]);
+        Some(Ok((address, body[self.address_size..].to_vec())))
+    }
+
+    /// Reads a range packet by packet, putting the DT1 replies back together
+    /// whatever the way the synth splits them
+    pub fn read<T: MidiTransport>(
+        &self,
+        transport: &mut T,
+        address: u32,
+        size: u32,
+        timeout: Duration,
+    ) -> Result<Vec<u8>, AddressMapError> {
+        let mut data = vec![0x00; size as usize];
+        let mut received = vec![false; size as usize];
+
+        for request in self.requests(address, size) {
+            for reply in transport.request(&request, timeout)? {
+                let (a, d) = match self.parse_dt1(&reply) {
+                    Some(r) => r?,
+                    None => continue,
+                };
+
+                for (i, b) in d.into_iter().enumerate() {
+                    let offset = (a + i as u32).wrapping_sub(address) as usize;
+                    if offset < data.len() {
+                        data[offset] = b;
+                        received[offset] = true;
+                    }
+                }
+            }
+        }
+
+        match received.iter().position(|r| !r) {
+            Some(missing) => Err(AddressMapError::CommonError(format!(
+                "No data received for {} and after",
+                self.format_address(address + missing as u32)
+            ))),
+            None => Ok(data),
+        }
+    }
+
+    /// Writes data packet by packet, each once the previous one is accepted
+    pub fn write<T: MidiTransport>(
+        &self,
+        transport: &mut T,
+        address: u32,
+        data: &[u8],
+        handshake: &Handshake,
+    ) -> Result<usize, AddressMapError> {
+        Ok(handshake.send_all(transport, &self.writes(address, data))?)
+    }
+}
@@ -0,0 +1,252 @@
+use super::handshake::{Handshake, HandshakeError};
+use super::midi_interface::*;
+use super::patch::Patch;
+use super::synth::Synth;
+use super::utils::*;
+use super::verify::*;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use derive_more::*;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, From)]
+pub enum BackupError {
+    IOError(std::io::Error),
+    JsonError(json::Error),
+    InterfaceError(MidiInterfaceError),
+    VerifyError(VerifyError),
+    HandshakeError(HandshakeError),
+    CommonError(String),
+}
+
+impl std::fmt::Display for BackupError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BackupError::IOError(e) => {
+                write!(f, "{}", e)
+            }
+
+            BackupError::JsonError(e) => {
+                write!(f, "{}", e)
+            }
+
+            BackupError::InterfaceError(e) => {
+                write!(f, "{}", e)
+            }
+
+            BackupError::VerifyError(e) => {
+                write!(f, "{}", e)
+            }
+
+            BackupError::HandshakeError(e) => {
+                write!(f, "{}", e)
+            }
+
+            BackupError::CommonError(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+/// A dump saved in a backup directory
+#[derive(Debug, Clone, PartialEq)]
+pub struct BackupEntry {
+    pub dump: String,
+    pub file: String,
+    pub messages: usize,
+    pub bytes: usize,
+}
+
+/// Content of a backup directory, saved as manifest.json
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub synth: String,
+    pub date: String,
+    pub channel: Option<usize>,
+    pub entries: Vec<BackupEntry>,
+}
+
+impl Manifest {
+    pub const FILE: &'static str = "manifest.json";
+
+    pub fn new(synth: &str, channel: Option<usize>) -> Manifest {
+        Manifest {
+            synth: synth.to_owned(),
+            date: timestamp(),
+            channel,
+            entries: Vec::new(),
+        }
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut val = json::JsonValue::new_object();
+        val["synth"] = self.synth.as_str().into();
+        val["date"] = self.date.as_str().into();
+        val["channel"] = self.channel.into();
+
+        let mut entries = json::JsonValue::new_array();
+        for e in &self.entries {
+            let mut entry = json::JsonValue::new_object();
+            entry["dump"] = e.dump.as_str().into();
+            entry["file"] = e.file.as_str().into();
+            entry["messages"] = e.messages.into();
+            entry["bytes"] = e.bytes.into();
+            let _ = entries.push(entry);
+        }
+        val["dumps"] = entries;
+        val
+    }
+
+    pub fn from_json(val: &json::JsonValue) -> Manifest {
+        Manifest {
+            synth: val["synth"].as_str().unwrap_or("").to_owned(),
+            date: val["date"].as_str().unwrap_or("").to_owned(),
+            channel: val["channel"].as_usize(),
+            entries: val["dumps"]
+                .members()
+                .map(|e| BackupEntry {
+                    dump: e["dump"].as_str().unwrap_or("").to_owned(),
+                    file: e["file"].as_str().unwrap_or("").to_owned(),
+                    messages: e["messages"].as_usize().unwrap_or(0),
+                    bytes: e["bytes"].as_usize().unwrap_or(0),
+                })
+                .collect(),
+        }
+    }
+
+    pub fn load(dir: &Path) -> Result<Manifest, BackupError> {
+        let contents = fs::read_to_string(dir.join(Manifest::FILE))?;
+        Ok(Manifest::from_json(&json::parse(&contents)?))
+    }
+
+    pub fn save(&self, dir: &Path) -> Result<(), BackupError> {
+        fs::write(dir.join(Manifest::FILE), self.to_json().pretty(4))?;
+        Ok(())
+    }
+}
+
+/// Dated directory for a new backup of a synth, e.g. backups/dw-8000_2020-06-21_18-30-05
+pub fn backup_directory(root: &str, synth: &str) -> PathBuf {
+    Path::new(root).join(format!("{}_{}", synth, timestamp()))
+}
+
+/// Requests every dump declared by the synth and saves the replies as .syx
+/// files in the directory, along with a manifest. Fails once every dump has
+/// been requested when some of them couldn't be, the manifest listing the
+/// others.
+pub fn backup<T: MidiTransport>(
+    transport: &mut T,
+    synth: &Synth,
+    channel: Option<usize>,
+    dir: &Path,
+    timeout: Duration,
+) -> Result<Manifest, BackupError> {
+    if synth.dumps.is_empty() {
+        return Err(BackupError::CommonError(format!("Synth {} declares no dumps", synth.id)));
+    }
+
+    fs::create_dir_all(dir)?;
+    let mut manifest = Manifest::new(synth.id.as_str(), channel);
+    let mut failed: Vec<String> = Vec::new();
+
+    for (i, dump) in synth.dumps.iter().enumerate() {
+        let request = synth.dump_request_bytes(dump, channel)?;
+
+        match transport.request(&request, timeout) {
+            Ok(replies) => {
+                let data: Vec<u8> = replies.concat();
+                let file = format!("{}.syx", dump.name);
+                fs::write(dir.join(&file), &data)?;
+
+                println!(
+                    "[{}/{}] {} : {} messages, {} bytes",
+                    i + 1,
+                    synth.dumps.len(),
+                    dump.name,
+                    replies.len(),
+                    data.len()
+                );
+
+                manifest.entries.push(BackupEntry {
+                    dump: dump.name.clone(),
+                    file,
+                    messages: replies.len(),
+                    bytes: data.len(),
+                });
+            }
+
+            Err(e) => {
+                println!("[{}/{}] {} : {}", i + 1, synth.dumps.len(), dump.name, e);
+                failed.push(dump.name.clone());
+            }
+        }
+    }
+
+    manifest.save(dir)?;
+    if failed.is_empty() {
+        Ok(manifest)
+    } else {
+        Err(BackupError::CommonError(format!("Backup incomplete, failed to get {}", failed.join(", "))))
+    }
+}
+
+/// Sends back the dumps of a backup directory, pausing between messages.
+/// When verifying, each dump the synth can send back is requested again and
+/// compared to the data sent.
+pub fn restore<T: MidiTransport>(
+    transport: &mut T,
+    synth: &Synth,
+    dir: &Path,
+    pace: Duration,
+    verifier: Option<&Verifier>,
+) -> Result<Manifest, BackupError> {
+    let manifest = Manifest::load(dir)?;
+    let mut failed: Vec<String> = Vec::new();
+
+    for (i, entry) in manifest.entries.iter().enumerate() {
+        let data = fs::read(dir.join(&entry.file))?;
+        let messages: Vec<Vec<u8>> = split_sysex(&data).into_iter().map(|m| m.to_vec()).collect();
+
+        println!(
+            "[{}/{}] {} : sending {} messages",
+            i + 1,
+            manifest.entries.len(),
+            entry.dump,
+            messages.len()
+        );
+
+        match (verifier, synth.get_dump(&entry.dump)) {
+            (Some(v), Some(dump)) => {
+                let v = Verifier { pace, ..v.clone() };
+                let mut expected = Patch::new(synth.id.as_str(), entry.dump.as_str());
+                expected.data = data.clone();
+
+                match v.upload(transport, synth, dump, manifest.channel, &messages, &expected) {
+                    Ok(()) => {
+                        println!("[{}/{}] {} : verified", i + 1, manifest.entries.len(), entry.dump);
+                    }
+                    Err(VerifyError::Mismatch(d)) => {
+                        println!("[{}/{}] {} : verification failed", i + 1, manifest.entries.len(), entry.dump);
+                        print!("{}", d);
+                        failed.push(entry.dump.clone());
+                    }
+                    Err(e) => return Err(e.into()),
+                }
+            }
+
+            _ => {
+                let handshake = Handshake { pace, ..synth.handshake.clone().unwrap_or_default() };
+                handshake.send_all(transport, &messages)?;
+            }
+        }
+    }
+
+    if failed.is_empty() {
+        Ok(manifest)
+    } else {
+        Err(BackupError::CommonError(format!("Verification failed for {}", failed.join(", "))))
+    }
+}
@@ -0,0 +1,247 @@
+use super::midi_interface::MidiTransport;
+use super::midi_parser::{CLOCK, CONTINUE, START, STOP};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Clock messages per quarter note
+pub const PPQN: u32 = 24;
+pub const DEFAULT_BPM: f64 = 120.0;
+pub const SONG_POSITION: u8 = 0xF2;
+
+/// Clock messages per sixteenth note, the unit of song positions
+const CLOCKS_PER_SIXTEENTH: u32 = PPQN / 4;
+/// Time spent busy waiting before each clock message rather than sleeping,
+/// as sleeps may overshoot by a millisecond or more
+const SPIN: Duration = Duration::from_micros(1500);
+/// Longest time between two clock messages received, 10 BPM
+const MAX_INTERVAL: u64 = 250_000;
+/// Clock intervals received before the tempo is known
+const MIN_INTERVALS: usize = 6;
+
+/// Time between two clock messages at a tempo
+fn clock_period(bpm: f64) -> Duration {
+    Duration::from_secs_f64(60.0 / (bpm * PPQN as f64))
+}
+
+/// Song position in sixteenth notes as bar.beat.sixteenth, in 4/4
+pub fn format_position(sixteenths: u32) -> String {
+    format!("{}.{}.{}", sixteenths / 16 + 1, sixteenths / 4 % 4 + 1, sixteenths % 4 + 1)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ClockCommand {
+    Tempo(f64),
+    /// Starts the song from the beginning
+    Start,
+    Stop,
+    /// Starts the song from the current position
+    Continue,
+    /// Moves to a song position, in sixteenth notes
+    Position(u16),
+}
+
+/// Length of a sweep, in milliseconds or in beats at the clock's tempo
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Length {
+    Millis(u64),
+    Beats(f32),
+}
+
+/// Sends MIDI clock on a thread of its own, with the song transport
+pub struct ClockMaster {
+    pub bpm: f64,
+    commands: Option<Sender<ClockCommand>>,
+    playing: Arc<AtomicBool>,
+    /// Clock messages sent since the start of the song
+    clocks: Arc<AtomicU32>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ClockMaster {
+    /// Starts sending clock messages at a tempo, the song being stopped.
+    /// Each message is sent at its time from the start rather than from the
+    /// previous one, so that the tempo doesn't drift.
+    pub fn start<T>(mut output: T, bpm: f64) -> ClockMaster
+    where
+        T: MidiTransport + Send + 'static,
+    {
+        let (commands, received) = mpsc::channel::<ClockCommand>();
+        let playing = Arc::new(AtomicBool::new(false));
+        let clocks = Arc::new(AtomicU32::new(0));
+        let (thread_playing, thread_clocks) = (playing.clone(), clocks.clone());
+
+        let thread = thread::spawn(move || {
+            let mut period = clock_period(bpm);
+            let mut next = Instant::now();
+
+            loop {
+                let now = Instant::now();
+                if now + SPIN < next {
+                    let command = match received.recv_timeout(next - SPIN - now) {
+                        Ok(c) => c,
+                        Err(RecvTimeoutError::Timeout) => continue,
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    };
+
+                    let message = match command {
+                        ClockCommand::Tempo(bpm) => {
+                            period = clock_period(bpm);
+                            continue;
+                        }
+                        ClockCommand::Start => {
+                            thread_clocks.store(0, Ordering::Relaxed);
+                            thread_playing.store(true, Ordering::Relaxed);
+                            vec![START]
+                        }
+                        ClockCommand::Stop => {
+                            thread_playing.store(false, Ordering::Relaxed);
+                            vec![STOP]
+                        }
+                        ClockCommand::Continue => {
+                            thread_playing.store(true, Ordering::Relaxed);
+                            vec![CONTINUE]
+                        }
+                        ClockCommand::Position(p) => {
+                            thread_clocks.store(p as u32 * CLOCKS_PER_SIXTEENTH, Ordering::Relaxed);
+                            vec![SONG_POSITION, (p & 0x7F) as u8, (p >> 7 & 0x7F) as u8]
+                        }
+                    };
+
+                    if let Err(e) = output.send(&message) {
+                        println!("Clock stopped : {}", e);
+                        break;
+                    }
+                    continue;
+                }
+
+                while Instant::now() < next {
+                    std::hint::spin_loop();
+                }
+                if let Err(e) = output.send(&[CLOCK]) {
+                    println!("Clock stopped : {}", e);
+                    break;
+                }
+                if thread_playing.load(Ordering::Relaxed) {
+                    thread_clocks.fetch_add(1, Ordering::Relaxed);
+                }
+
+                // Skips the clocks missed when far behind instead of bursting
+                next += period;
+                if Instant::now() > next + period {
+                    next = Instant::now();
+                }
+            }
+        });
+
+        ClockMaster {
+            bpm,
+            commands: Some(commands),
+            playing,
+            clocks,
+            thread: Some(thread),
+        }
+    }
+
+    pub fn send(&mut self, command: ClockCommand) {
+        if let ClockCommand::Tempo(bpm) = command {
+            self.bpm = bpm;
+        }
+        if let Some(c) = &self.commands {
+            let _ = c.send(command);
+        }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+
+    /// Song position in sixteenth notes
+    pub fn position(&self) -> u32 {
+        self.clocks.load(Ordering::Relaxed) / CLOCKS_PER_SIXTEENTH
+    }
+
+    /// Stops the song if it is playing, then the clock
+    pub fn stop(&mut self) {
+        if self.is_playing() {
+            self.send(ClockCommand::Stop);
+        }
+        self.commands = None;
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for ClockMaster {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Follows the tempo and the song transport of the clock received
+#[derive(Debug, Default)]
+pub struct ClockFollower {
+    last: Option<u64>,
+    /// Time between the clock messages of the last beat, in µs
+    intervals: VecDeque<u64>,
+    pub playing: bool,
+    clocks: u32,
+}
+
+impl ClockFollower {
+    pub fn new() -> ClockFollower {
+        ClockFollower::default()
+    }
+
+    /// Follows a message received at an input timestamp in µs, ignoring
+    /// the messages other than clock, transport and song position
+    pub fn receive(&mut self, stamp: u64, message: &[u8]) {
+        match message {
+            [CLOCK] => {
+                if let Some(last) = self.last {
+                    let interval = stamp.saturating_sub(last);
+                    if interval > MAX_INTERVAL {
+                        self.intervals.clear();
+                    } else {
+                        self.intervals.push_back(interval);
+                        if self.intervals.len() > PPQN as usize {
+                            self.intervals.pop_front();
+                        }
+                    }
+                }
+                self.last = Some(stamp);
+
+                if self.playing {
+                    self.clocks += 1;
+                }
+            }
+            [START] => {
+                self.playing = true;
+                self.clocks = 0;
+            }
+            [CONTINUE] => self.playing = true,
+            [STOP] => self.playing = false,
+            [SONG_POSITION, lsb, msb] => self.clocks = (*lsb as u32 | (*msb as u32) << 7) * CLOCKS_PER_SIXTEENTH,
+            _ => {}
+        }
+    }
+
+    /// Tempo averaged over the last beat, once enough clock messages have
+    /// been received
+    pub fn bpm(&self) -> Option<f64> {
+        if self.intervals.len() < MIN_INTERVALS {
+            return None;
+        }
+        let average = self.intervals.iter().sum::<u64>() as f64 / self.intervals.len() as f64;
+        Some(60_000_000.0 / (average * PPQN as f64))
+    }
+
+    /// Song position in sixteenth notes
+    pub fn position(&self) -> u32 {
+        self.clocks / CLOCKS_PER_SIXTEENTH
+    }
+}
@@ -1,6 +1,10 @@
+use super::address_map::AddressMap;
 use super::command_parser::*;
+use super::handshake::Handshake;
+use super::identity::IdentitySignature;
+use super::manufacturers;
 use super::midi_command::*;
-use super::synth::Synth;
+use super::synth::{Dump, Synth, SynthParameter};
 use std::collections::HashMap;
 use std::fs::File;
 use std::io::prelude::*;
@@ -45,6 +49,24 @@ impl Config {
         Ok(())
     }
 
+    /// Loads every .json definition of a directory, returning how many were loaded
+    pub fn run_directory(&mut self, dir: &str) -> Result<usize, ConfigError> {
+        let mut paths: Vec<std::path::PathBuf> = std::fs::read_dir(dir)?
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .filter(|p| p.extension().map(|e| e == "json").unwrap_or(false))
+            .collect();
+        paths.sort();
+
+        let mut loaded = 0;
+        for p in paths {
+            match self.run_file(&p.to_string_lossy()) {
+                Ok(()) => loaded += 1,
+                Err(e) => println!("Failed to load {} : {:?}", p.display(), e),
+            }
+        }
+        Ok(loaded)
+    }
+
     pub fn run_json(&mut self, contents: String) -> Result<(), ConfigError> {
         let parsed = json::parse(&contents)?;
         self.create_synth_json(parsed)?;
@@ -63,12 +85,43 @@ impl Config {
                 synth.manufacturer = manufacturer.to_string();
             }
 
+            if let Some(alias) = val["parameter-change"].as_str() {
+                synth.parameter_change = Some(alias.to_string());
+            }
+
+            if let Some(alias) = val["parameter-request"].as_str() {
+                synth.parameter_request = Some(alias.to_string());
+            }
+
+            if val["identity"].is_object() {
+                synth.identity = Some(IdentitySignature::from_json(&val["identity"], &synth.manufacturer)?);
+            }
+
+            if val["handshake"].is_object() {
+                synth.handshake = Some(Handshake::from_json(&val["handshake"]));
+            }
+
+            if val["address-map"].is_object() {
+                synth.address_map = Some(AddressMap::from_json(&val["address-map"])?);
+            }
+
             // Commands
             for c_val in val["commands"].members() {
                 let c = MidiCommand::from_json(c_val);
                 synth.commands.push(c);
             }
 
+            for p_val in val["parameters"].members() {
+                synth.parameters.push(SynthParameter::from_json(p_val));
+            }
+
+            for d_val in val["dumps"].members() {
+                synth.dumps.push(Dump::from_json(d_val));
+            }
+
+            Config::expand_manufacturers(&mut synth)?;
+            Config::scope_handshake(&mut synth, &val["handshake"]["commands"])?;
+
             self.current_synth = id.to_string();
             self.synths.insert(id.to_string(), synth);
 
@@ -79,26 +132,63 @@ impl Config {
         
     }
 
-    pub fn run_commands_str<T: AsRef<str>>(&mut self, content: &[T]) {
+    /// Replaces the manufacturer references of the synth's midi templates
+    /// (e.g. "F0 {manufacturer} 3n 41 p v F7") by their ID
+    fn expand_manufacturers(synth: &mut Synth) -> Result<(), ConfigError> {
+        let manufacturer = synth.manufacturer.clone();
+        let headers = synth.dumps.iter_mut().filter_map(|d| d.header.as_mut());
+        let replies = synth.handshake.iter_mut().flat_map(|h| h.replies_mut());
+
+        for c in synth.commands.iter_mut().chain(headers).chain(replies) {
+            if c.midi.contains('{') {
+                c.midi = manufacturers::expand(&c.midi, &manufacturer)?;
+                c.update_mask();
+            }
+        }
+        Ok(())
+    }
+
+    /// Limits the handshake to the messages the synth answers : its dumps and
+    /// the commands named, e.g. "commands" : ["write-request"]
+    fn scope_handshake(synth: &mut Synth, commands: &json::JsonValue) -> Result<(), ConfigError> {
+        let mut scope: Vec<MidiCommand> = synth.dumps.iter().filter_map(|d| d.header.clone()).collect();
+        for name in commands.members().filter_map(|n| n.as_str()) {
+            match synth.get_command(name) {
+                Some(c) => scope.push(c.clone()),
+                None => return Err(ConfigError::CommonError(format!("Unknown handshake command {}", name))),
+            }
+        }
+
+        if let Some(h) = synth.handshake.as_mut() {
+            h.scope = scope;
+        }
+        Ok(())
+    }
+
+    pub fn run_commands_str<T: AsRef<str>>(&mut self, content: &[T]) -> Result<(), ConfigError> {
         let c = CommandParser::parse_commands(content);
 
-        self.run_commands(&c);
+        self.run_commands(&c)
     }
 
-    pub fn run_command_str(&mut self, content: &str) {
+    pub fn run_command_str(&mut self, content: &str) -> Result<(), ConfigError> {
         match CommandParser::parse_command(content.to_owned()) {
             Some(c) => self.run_command(&c),
-            None => println!("Failed to parse command"),
+            None => {
+                println!("Failed to parse command");
+                Ok(())
+            }
         }
     }
 
-    pub fn run_commands(&mut self, commands: &[Command]) {
+    pub fn run_commands(&mut self, commands: &[Command]) -> Result<(), ConfigError> {
         for c in commands {
-            self.run_command(c);
+            self.run_command(c)?;
         }
+        Ok(())
     }
 
-    pub fn run_command(&mut self, command: &Command) {
+    pub fn run_command(&mut self, command: &Command) -> Result<(), ConfigError> {
         match command.name.as_str() {
             "synth" => {
                 let id = command.get_parameter("id").expect("Expected synth ID");
@@ -147,6 +237,8 @@ impl Config {
                         midi_command.add_parameter(MidiParameter::new_parse(param));
                     }
                     let s: &mut Synth = self.get_current_synth_mut().expect("");
+                    midi_command.midi = manufacturers::expand(&midi_command.midi, &s.manufacturer)?;
+                    midi_command.update_mask();
                     s.commands.push(midi_command);
                 }
             }
@@ -189,6 +281,7 @@ impl Config {
 
             _ => {}
         }
+        Ok(())
     }
 
     pub fn load_synth(&self, synth: &str) -> Option<HashMap<String, MidiCommand>> {
@@ -219,6 +312,17 @@ impl Config {
         return v;
     }
 
+    pub fn get_synth(&self, synth: &str) -> Option<&Synth> {
+        self.synths.get(synth)
+    }
+
+    /// Every synth loaded, by id
+    pub fn synths(&self) -> Vec<&Synth> {
+        let mut synths: Vec<&Synth> = self.synths.values().collect();
+        synths.sort_by(|a, b| a.id.cmp(&b.id));
+        synths
+    }
+
     pub fn has_synth(&self, synth: &str) -> bool {
         self.synths.contains_key(synth)
     }
@@ -0,0 +1,226 @@
+use super::midi_command::MidiCommand;
+use super::midi_interface::*;
+use std::time::{Duration, Instant};
+
+use derive_more::*;
+
+#[derive(Debug, From)]
+pub enum HandshakeError {
+    InterfaceError(MidiInterfaceError),
+    /// Message refused by the receiver, even after the retries
+    #[from(ignore)]
+    Refused(usize),
+    #[from(ignore)]
+    Cancelled,
+    #[from(ignore)]
+    NoReply,
+    CommonError(String),
+}
+
+impl std::fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandshakeError::InterfaceError(e) => {
+                write!(f, "{}", e)
+            }
+
+            HandshakeError::Refused(tries) => {
+                write!(f, "Message refused {} times", tries)
+            }
+
+            HandshakeError::Cancelled => {
+                write!(f, "Transfer cancelled by the receiver")
+            }
+
+            HandshakeError::NoReply => {
+                write!(f, "No reply received")
+            }
+
+            HandshakeError::CommonError(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+/// What a reply says about the message just sent
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Response {
+    /// Accepted, the next message can be sent
+    Ack,
+    /// Refused, the message must be sent again
+    Error,
+    /// Still processing, the reply will come later
+    Wait,
+    /// The whole transfer is given up
+    Cancel,
+}
+
+/// Flow control of bulk sends : the receiver answers each message, the next
+/// one being sent only once the previous one is acknowledged. Without any
+/// reply declared, messages are just sent at a fixed pace.
+#[derive(Debug, Clone)]
+pub struct Handshake {
+    pub ack: Vec<MidiCommand>,
+    pub error: Vec<MidiCommand>,
+    pub wait: Vec<MidiCommand>,
+    pub cancel: Vec<MidiCommand>,
+    /// Messages the receiver answers, matched by their start, every message
+    /// when empty. Others are sent at the pace without waiting for a reply.
+    pub scope: Vec<MidiCommand>,
+    /// Time the receiver has to answer each message
+    pub timeout: Duration,
+    /// Longest wait after a wait reply
+    pub wait_timeout: Duration,
+    /// Times a refused message is sent again
+    pub retries: usize,
+    /// Whether to go on with the next message when no reply comes
+    pub continue_on_timeout: bool,
+    /// Pause after each message
+    pub pace: Duration,
+}
+
+impl Default for Handshake {
+    fn default() -> Self {
+        Handshake {
+            ack: Vec::new(),
+            error: Vec::new(),
+            wait: Vec::new(),
+            cancel: Vec::new(),
+            scope: Vec::new(),
+            timeout: Duration::from_millis(1000),
+            wait_timeout: Duration::from_secs(60),
+            retries: 3,
+            continue_on_timeout: false,
+            pace: Duration::from_millis(0),
+        }
+    }
+}
+
+impl Handshake {
+    /// Command matching a reply, e.g. "F0 42 3n 03 23 F7"
+    pub fn reply(name: &str, template: &str) -> MidiCommand {
+        let mut c = MidiCommand::new(name.to_owned());
+        c.midi = template.to_owned();
+        c.update_mask();
+        c
+    }
+
+    /// Parses a handshake definition, each reply being a template or a list
+    /// of them and times in milliseconds :
+    /// { "ack" : "F0 42 3n 03 23 F7", "error" : ["F0 42 3n 03 24 F7"],
+    ///   "timeout" : 1000, "retries" : 3, "on-timeout" : "fail" }
+    /// Its scope is set by the synth, from its dumps and "commands".
+    pub fn from_json(val: &json::JsonValue) -> Handshake {
+        let replies = |name: &str| -> Vec<MidiCommand> {
+            match val[name].as_str() {
+                Some(t) => vec![Handshake::reply(name, t)],
+                None => val[name]
+                    .members()
+                    .filter_map(|t| t.as_str())
+                    .map(|t| Handshake::reply(name, t))
+                    .collect(),
+            }
+        };
+        let default = Handshake::default();
+        let millis = |name: &str, default: Duration| val[name].as_u64().map(Duration::from_millis).unwrap_or(default);
+
+        Handshake {
+            ack: replies("ack"),
+            error: replies("error"),
+            wait: replies("wait"),
+            cancel: replies("cancel"),
+            scope: Vec::new(),
+            timeout: millis("timeout", default.timeout),
+            wait_timeout: millis("wait-timeout", default.wait_timeout),
+            retries: val["retries"].as_usize().unwrap_or(default.retries),
+            continue_on_timeout: val["on-timeout"].as_str() == Some("continue"),
+            pace: millis("pace", default.pace),
+        }
+    }
+
+    /// Every reply command, to fill in their templates
+    pub fn replies_mut(&mut self) -> impl Iterator<Item = &mut MidiCommand> {
+        self.ack
+            .iter_mut()
+            .chain(self.error.iter_mut())
+            .chain(self.wait.iter_mut())
+            .chain(self.cancel.iter_mut())
+    }
+
+    pub fn expects_replies(&self) -> bool {
+        !(self.ack.is_empty() && self.error.is_empty() && self.wait.is_empty() && self.cancel.is_empty())
+    }
+
+    /// Whether the receiver answers a message
+    pub fn applies_to(&self, message: &[u8]) -> bool {
+        self.expects_replies() && (self.scope.is_empty() || self.scope.iter().any(|c| c.matches_prefix(message)))
+    }
+
+    pub fn classify(&self, message: &[u8]) -> Option<Response> {
+        let matches = |commands: &[MidiCommand]| commands.iter().any(|c| c.matches(message));
+
+        if matches(&self.ack) {
+            Some(Response::Ack)
+        } else if matches(&self.error) {
+            Some(Response::Error)
+        } else if matches(&self.wait) {
+            Some(Response::Wait)
+        } else if matches(&self.cancel) {
+            Some(Response::Cancel)
+        } else {
+            None
+        }
+    }
+
+    /// Waits for a reply other than wait, ignoring other messages
+    fn wait_reply<T: MidiTransport>(&self, transport: &mut T) -> Option<Response> {
+        let mut deadline = Instant::now() + self.timeout;
+
+        loop {
+            let message = transport.receive(deadline.saturating_duration_since(Instant::now()))?;
+            match self.classify(&message) {
+                Some(Response::Wait) => deadline = Instant::now() + self.wait_timeout,
+                Some(r) => return Some(r),
+                None => {}
+            }
+        }
+    }
+
+    /// Sends a message until the receiver accepts it. Returns whether it was
+    /// acknowledged, false when no reply came and the transfer can go on.
+    pub fn send<T: MidiTransport>(&self, transport: &mut T, message: &[u8]) -> Result<bool, HandshakeError> {
+        if !self.applies_to(message) {
+            transport.send(message)?;
+            std::thread::sleep(self.pace);
+            return Ok(false);
+        }
+
+        for _ in 0..=self.retries {
+            transport.flush();
+            transport.send(message)?;
+
+            let response = self.wait_reply(transport);
+            std::thread::sleep(self.pace);
+
+            match response {
+                Some(Response::Ack) => return Ok(true),
+                Some(Response::Cancel) => return Err(HandshakeError::Cancelled),
+                None if self.continue_on_timeout => return Ok(false),
+                None => return Err(HandshakeError::NoReply),
+                _ => {}
+            }
+        }
+
+        Err(HandshakeError::Refused(self.retries + 1))
+    }
+
+    /// Sends messages one by one, each once the previous one is accepted
+    pub fn send_all<T: MidiTransport>(&self, transport: &mut T, messages: &[Vec<u8>]) -> Result<usize, HandshakeError> {
+        for (i, m) in messages.iter().enumerate() {
+            self.send(transport, m)
+                .map_err(|e| HandshakeError::CommonError(format!("Message {}/{} : {}", i + 1, messages.len(), e)))?;
+        }
+        Ok(messages.len())
+    }
+}
@@ -0,0 +1,115 @@
+use super::manufacturers;
+use super::midi_interface::*;
+use super::synth::Synth;
+use super::utils::{btos, parse_hex};
+use std::time::Duration;
+
+/// Universal Non-Realtime Identity Request, sent to every device
+pub const IDENTITY_REQUEST: [u8; 6] = [0xF0, 0x7E, 0x7F, 0x06, 0x01, 0xF7];
+
+/// Reply to an Identity Request :
+/// F0 7E device 06 02 manufacturer family(2) model(2) version(4) F7
+#[derive(Debug, Clone, PartialEq)]
+pub struct IdentityReply {
+    pub device: u8,
+    pub manufacturer: Vec<u8>,
+    pub family: Vec<u8>,
+    pub model: Vec<u8>,
+    pub version: Vec<u8>,
+}
+
+impl IdentityReply {
+    pub fn parse(message: &[u8]) -> Option<IdentityReply> {
+        if message.len() < 6 || message[1] != 0x7E || message[3..5] != [0x06, 0x02] {
+            return None;
+        }
+
+        let manufacturer_size = if message[5] == 0x00 { 3 } else { 1 };
+        if message.len() != 5 + manufacturer_size + 9 {
+            return None;
+        }
+
+        let body = &message[5 + manufacturer_size..];
+        if body[8] != 0xF7 {
+            return None;
+        }
+
+        Some(IdentityReply {
+            device: message[2],
+            manufacturer: message[5..5 + manufacturer_size].to_vec(),
+            family: body[0..2].to_vec(),
+            model: body[2..4].to_vec(),
+            version: body[4..8].to_vec(),
+        })
+    }
+}
+
+impl std::fmt::Display for IdentityReply {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{}, family {}, model {}, version {}",
+            manufacturers::describe(&self.manufacturer),
+            btos(&self.family),
+            btos(&self.model),
+            btos(&self.version)
+        )
+    }
+}
+
+/// Identity a synth replies with. Missing fields match anything.
+#[derive(Debug, Clone, Default)]
+pub struct IdentitySignature {
+    pub manufacturer: Option<Vec<u8>>,
+    pub family: Option<Vec<u8>>,
+    pub model: Option<Vec<u8>>,
+}
+
+impl IdentitySignature {
+    /// Parses a signature, the manufacturer defaulting to the synth's :
+    /// { "family" : "19 00", "model" : "03 00" }
+    pub fn from_json(val: &json::JsonValue, manufacturer: &str) -> Result<IdentitySignature, String> {
+        let hex = |field: &str| match val[field].as_str() {
+            Some(s) => parse_hex(s).map(Some).ok_or(format!("Bad identity {} {}", field, s)),
+            None => Ok(None),
+        };
+
+        let manufacturer = match val["manufacturer"].as_str() {
+            Some(m) => match manufacturers::id(m) {
+                Some(id) => Some(id.to_vec()),
+                None => hex("manufacturer")?,
+            },
+            None => manufacturers::id(manufacturer).map(|id| id.to_vec()),
+        };
+
+        Ok(IdentitySignature {
+            manufacturer,
+            family: hex("family")?,
+            model: hex("model")?,
+        })
+    }
+
+    pub fn matches(&self, reply: &IdentityReply) -> bool {
+        let field = |expected: &Option<Vec<u8>>, actual: &[u8]| expected.as_ref().map(|e| e == actual).unwrap_or(true);
+
+        field(&self.manufacturer, &reply.manufacturer)
+            && field(&self.family, &reply.family)
+            && field(&self.model, &reply.model)
+    }
+}
+
+/// Sends an Identity Request and collects the replies
+pub fn request<T: MidiTransport>(transport: &mut T, timeout: Duration) -> Result<Vec<IdentityReply>, MidiInterfaceError> {
+    let replies = transport.request(&IDENTITY_REQUEST, timeout)?;
+    Ok(replies.iter().filter_map(|r| IdentityReply::parse(r)).collect())
+}
+
+/// First synth whose identity signature matches a reply
+pub fn find_synth<'a, I>(synths: I, reply: &IdentityReply) -> Option<&'a Synth>
+where
+    I: IntoIterator<Item = &'a Synth>,
+{
+    synths
+        .into_iter()
+        .find(|s| s.identity.as_ref().map(|i| i.matches(reply)).unwrap_or(false))
+}
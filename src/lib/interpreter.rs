@@ -1,7 +1,29 @@
+use super::address_map::{to_linear, AddressMapError};
+use super::backup::*;
+use super::clock::{self, ClockCommand, ClockFollower, ClockMaster, Length};
 use super::config::*;
+use super::handshake::{Handshake, HandshakeError};
+use super::identity;
 use super::midi_command::*;
 use super::midi_interface::*;
+use super::midi_parser;
+use super::monitor::*;
+use super::morph::Morpher;
+use super::patch::*;
+use super::player::Player;
+use super::randomizer::*;
+use super::recorder::{MidiFileRecorder, Recorder};
+use super::sds::{self, SdsError};
+use super::smf::{self, Smf};
+use super::state::{self, *};
+use super::synth::{Synth, SynthParameter};
+use super::syx::{self, SyxError};
+use super::utils::{btos, parse_hex, stob};
+use super::verify::*;
+use super::wav;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
 
 use rustyline::error::ReadlineError;
 use rustyline::Editor;
@@ -10,11 +32,25 @@ use std::fs::File;
 use std::io::prelude::*;
 
 use derive_more::*;
+/// Pause between DT1 messages for synths without handshake, in ms
+const DT1_PACE: u64 = 20;
+/// Pause between parameter changes for synths without handshake, in ms
+const PARAMETER_PACE: u64 = 20;
+
 #[derive(Debug, From)]
 pub enum InterpreterError {
     SimpleError(String),
     MidiSendError(midir::SendError),
-    InterfaceError(MidiInterfaceError)
+    InterfaceError(MidiInterfaceError),
+    PatchError(PatchError),
+    BackupError(BackupError),
+    VerifyError(VerifyError),
+    StateError(StateError),
+    IOError(std::io::Error),
+    SyxError(SyxError),
+    SdsError(SdsError),
+    HandshakeError(HandshakeError),
+    AddressMapError(AddressMapError)
 }
 
 impl std::fmt::Display for InterpreterError {
@@ -32,6 +68,42 @@ impl std::fmt::Display for InterpreterError {
             InterpreterError::InterfaceError(e) => {
                 write!(f, "{}", e)
             }
+
+            InterpreterError::PatchError(e) => {
+                write!(f, "{}", e)
+            }
+
+            InterpreterError::BackupError(e) => {
+                write!(f, "{}", e)
+            }
+
+            InterpreterError::VerifyError(e) => {
+                write!(f, "{}", e)
+            }
+
+            InterpreterError::StateError(e) => {
+                write!(f, "{}", e)
+            }
+
+            InterpreterError::IOError(e) => {
+                write!(f, "{}", e)
+            }
+
+            InterpreterError::SyxError(e) => {
+                write!(f, "{}", e)
+            }
+
+            InterpreterError::SdsError(e) => {
+                write!(f, "{}", e)
+            }
+
+            InterpreterError::HandshakeError(e) => {
+                write!(f, "{}", e)
+            }
+
+            InterpreterError::AddressMapError(e) => {
+                write!(f, "{}", e)
+            }
         }
     }
 }
@@ -46,37 +118,205 @@ pub enum InterpreterCommand{
     Channel(i8),
     Receive(u32),
     Send(Vec<u8>),
-    Sysex(String, Vec<usize>),
-    Midi(String, Vec<usize>),
+    Sysex(String, Vec<Argument>),
+    Midi(String, Vec<Argument>),
     MidiList,
     SysexList,
     Source(String),
+    Diff(String, String),
+    /// Randomizer, base patch file, send as a dump, file to save the patch to
+    Randomize(Randomizer, Option<String>, bool, Option<String>),
+    /// Patch files to morph between, target position (0-100), duration, step count
+    Morph(Option<(String, String)>, Option<f32>, Option<Length>, usize),
+    /// Synth id, root directory, reply timeout in ms
+    Backup(Option<String>, String, u64),
+    /// Backup directory, pause between messages in ms, verification settings
+    Restore(String, u64, Option<Verifier>),
+    /// Verification settings, None to stop verifying uploads
+    Verify(Option<Verifier>),
+    /// Patch file, send as a dump
+    Upload(String, bool),
+    /// File to save the current synth's state to as a patch, None to show the state
+    State(Option<String>),
+    ClearState,
+    /// Parameter id, number of steps to move its value by
+    Step(String, isize),
+    Toggle(String),
+    /// Whether to sync automatically, None to sync now
+    Sync(Option<bool>),
+    /// Whether to print the changes received from the synth
+    Echo(bool),
+    /// Realtime status bytes, whether to drop them
+    RealtimeFilter(Vec<u8>, bool),
+    /// Whether to monitor, new filter if any
+    Monitor(bool, Option<MonitorFilter>),
+    /// File or directory to record SysEx to, one file per message, None to stop
+    Record(Option<(String, bool)>),
+    /// MIDI file to record the performance to, its type, ticks per quarter note and BPM
+    RecordMidi(String, u16, u16, f64),
+    /// .syx file, pause between messages in ms, refuse messages no synth definition matches
+    SendFile(String, u64, bool),
+    /// .syx file, directory of the definitions to match its messages against
+    SyxInfo(String, String),
+    /// Sample number to request, .wav file to save it to
+    SampleReceive(u16, String),
+    /// .wav file, sample number to send it as
+    SampleSend(String, u16),
+    /// Area name or address, size to read if not the area's
+    AddressRead(String, Option<u32>),
+    /// Area name or address, data to write there
+    AddressWrite(String, Vec<u8>),
+    /// MIDI file, whether to loop, whether to keep its channels instead of
+    /// playing it on the interpreter's
+    Play(String, bool, bool),
+    Stop,
+    /// Time to wait for an Identity Reply on each pair of ports, in ms
+    Detect(u64),
+    /// Tempo or transport change for the clock master, started if it isn't running
+    Clock(ClockCommand),
+    ClockOff,
+    /// Whether to follow the tempo of the clock received
+    ClockFollow(bool),
+    ClockStatus,
 }
 
 #[derive(Default)]
 pub struct Interpreter {
     channel: i8,
+    synth: String,
     config: Config,
     interface: MidiInterface,
     midi: HashMap<String, MidiCommand>,
     sysex: HashMap<String, MidiCommand>,
+    morpher: Option<Morpher>,
+    verifier: Option<Verifier>,
+    state: Arc<Mutex<SessionState>>,
+    auto_sync: bool,
+    echo: Arc<AtomicBool>,
+    monitor: Arc<Mutex<Monitor>>,
+    recorder: Arc<Mutex<Option<Recorder>>>,
+    midi_recorder: Arc<Mutex<Option<MidiFileRecorder>>>,
+    player: Option<Player>,
+    clock: Option<ClockMaster>,
+    follower: Arc<Mutex<Option<ClockFollower>>>,
+    /// Whether clock messages were dropped before the clock was followed
+    clock_filtered: bool,
 }
 
 impl Interpreter {
     pub fn new() -> Interpreter {
         Interpreter {
             channel: -1,
+            synth: String::new(),
             config: Config::new(),
             interface: MidiInterface::new(),
             midi: HashMap::new(),
             sysex: HashMap::new(),
+            morpher: None,
+            verifier: None,
+            state: Arc::new(Mutex::new(SessionState::new())),
+            auto_sync: false,
+            echo: Arc::new(AtomicBool::new(false)),
+            monitor: Arc::new(Mutex::new(Monitor::new())),
+            recorder: Arc::new(Mutex::new(None)),
+            midi_recorder: Arc::new(Mutex::new(None)),
+            player: None,
+            clock: None,
+            follower: Arc::new(Mutex::new(None)),
+            clock_filtered: true,
+        }
+    }
+
+    /// Input callback applying the messages received to the session state,
+    /// printing the changes when echo is on
+    fn tracker(&self) -> impl FnMut(u64, &[u8], &mut ()) + Send + 'static {
+        let state = self.state.clone();
+        let echo = self.echo.clone();
+        let monitor = self.monitor.clone();
+        let recorder = self.recorder.clone();
+        let midi_recorder = self.midi_recorder.clone();
+        let follower = self.follower.clone();
+        let synth = self.current_synth().cloned();
+        let midi = self.midi.clone();
+        let channel = self.channel_argument().unwrap_or(0);
+
+        move |stamp, message, _| {
+            let mut monitor = monitor.lock().unwrap();
+            monitor.log(Some(stamp), Direction::Received, message);
+
+            if let Some(r) = recorder.lock().unwrap().as_mut() {
+                let name = monitor.decode(message).map(|d| d.name);
+                if let Err(e) = r.record(message, name.as_deref()) {
+                    println!("Failed to record to {} : {}", r.path.display(), e);
+                }
+            }
+            drop(monitor);
+
+            if let Some(r) = midi_recorder.lock().unwrap().as_mut() {
+                r.record(Some(stamp), Direction::Received, message);
+            }
+            if let Some(f) = follower.lock().unwrap().as_mut() {
+                f.receive(stamp, message);
+            }
+
+            let changes = state
+                .lock()
+                .unwrap()
+                .apply(synth.as_ref(), &midi, channel, message, Direction::Received);
+
+            if echo.load(Ordering::Relaxed) {
+                for c in changes {
+                    println!("{}", c.describe(synth.as_ref()));
+                }
+            }
+        }
+    }
+
+    /// Logs the messages sent to the monitor, and records them to the MIDI
+    /// file being recorded if any
+    fn observe_output(&mut self) {
+        let monitor = self.monitor.clone();
+        let midi_recorder = self.midi_recorder.clone();
+
+        self.interface.set_output_observer(move |message| {
+            monitor.lock().unwrap().log(None, Direction::Sent, message);
+            if let Some(r) = midi_recorder.lock().unwrap().as_mut() {
+                r.record(None, Direction::Sent, message);
+            }
+        });
+    }
+
+    /// Connects the input and output ports back as they were, closing the
+    /// ones that weren't connected
+    fn restore_ports(&mut self, ports: (Option<usize>, Option<usize>)) -> Result<(), InterpreterError> {
+        match ports.0 {
+            Some(i) => self.interface.set_input_port(i, self.tracker())?,
+            None => self.interface.close_input(),
+        }
+        match ports.1 {
+            Some(o) => self.interface.set_output_port(o)?,
+            None => self.interface.close_output(),
+        }
+        Ok(())
+    }
+
+    /// Reconnects the input with the current synth and channel
+    fn update_tracker(&mut self) -> Result<(), InterpreterError> {
+        self.monitor.lock().unwrap().update_definitions(&self.config);
+
+        if self.interface.has_input() {
+            self.interface.update_callback(self.tracker())?;
         }
+        Ok(())
     }
 
     pub fn set_input_callback<F>(&mut self, callback: F) -> Result<(), InterpreterError> where F: Fn(&[String], &[u8]) + Send + 'static {
         let s: HashMap<String, MidiCommand> = self.sysex.clone();
+        let mut track = self.tracker();
+
+        self.interface.update_callback(move |stamp, message, data| {
+            track(stamp, message, data);
 
-        self.interface.update_callback(move |_stamp, message, ()| {
             let mut v: Vec<String> = Vec::new();
             let mut d: Vec<u8> = Vec::new();
             
@@ -97,8 +337,251 @@ impl Interpreter {
 
     pub fn run_file(&mut self, contents: String) {}
 
+    pub fn current_synth(&self) -> Option<&Synth> {
+        self.config.get_synth(self.synth.as_str())
+    }
+
+    fn channel_argument(&self) -> Option<usize> {
+        if self.channel >= 0 {
+            Some(self.channel as usize)
+        } else {
+            None
+        }
+    }
+
+    /// Tempo of the clock followed, or else of the clock master
+    pub fn tempo(&self) -> Option<f64> {
+        match self.follower.lock().unwrap().as_ref() {
+            Some(f) => f.bpm(),
+            None => self.clock.as_ref().map(|c| c.bpm),
+        }
+    }
+
+    /// Whether a realtime message (F8 to FF) received is dropped
+    pub fn is_realtime_filtered(&self, status: u8) -> bool {
+        self.interface.is_realtime_filtered(status)
+    }
+
+    /// Length in milliseconds, beats following the tempo of the clock
+    fn millis(&self, length: Length) -> Result<u64, InterpreterError> {
+        match length {
+            Length::Millis(ms) => Ok(ms),
+            Length::Beats(beats) => match self.tempo() {
+                Some(bpm) => Ok((beats as f64 * 60_000.0 / bpm) as u64),
+                None => Err(InterpreterError::SimpleError(String::from("No tempo, start or follow a clock first"))),
+            },
+        }
+    }
+
+    pub fn state(&self) -> SessionState {
+        self.state.lock().unwrap().clone()
+    }
+
+    /// Updates the state with the values of a message sent or received.
+    /// SysEx messages are decoded with the current synth, other messages
+    /// with the MIDI standard.
+    pub fn record(&mut self, message: &[u8], direction: Direction) -> Vec<StateChange> {
+        let channel = self.channel_argument().unwrap_or(0);
+        let synth = self.config.get_synth(self.synth.as_str());
+
+        self.state
+            .lock()
+            .unwrap()
+            .apply(synth, &self.midi, channel, message, direction)
+    }
+
+    fn reply_timeout(&self) -> std::time::Duration {
+        self.verifier
+            .as_ref()
+            .map(|v| v.timeout)
+            .unwrap_or(std::time::Duration::from_millis(2000))
+    }
+
+    /// Requests the values of every parameter from the synth
+    pub fn sync(&mut self) -> Result<Vec<StateChange>, InterpreterError> {
+        let channel = self.channel_argument();
+        let timeout = self.reply_timeout();
+        let synth = self
+            .config
+            .get_synth(self.synth.as_str())
+            .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?;
+
+        // The input tracker records the replies as they come, the state
+        // staying unlocked while waiting for them
+        let before = self.state();
+        state::request_all(&mut self.interface, synth, channel, timeout)?;
+        Ok(self.state.lock().unwrap().changes_since(&before, synth.id.as_str(), channel.unwrap_or(0)))
+    }
+
+    /// Syncs when enabled, once both a synth and an output port are set
+    fn auto_sync(&mut self) {
+        if self.auto_sync && !self.synth.is_empty() && self.interface.has_output() {
+            match self.sync() {
+                Ok(changes) => println!("Synced {} ({} values changed)", self.synth, changes.len()),
+                Err(e) => println!("Failed to sync {} : {}", self.synth, e),
+            }
+        }
+    }
+
+    /// Value of a parameter of the current synth, requesting it when it
+    /// isn't known yet
+    pub fn current_value(&mut self, id: &str) -> Result<ParameterValue, InterpreterError> {
+        let channel = self.channel_argument();
+        let timeout = self.reply_timeout();
+
+        if self.state.lock().unwrap().value(self.synth.as_str(), channel.unwrap_or(0), id).is_none() {
+            let synth = self
+                .config
+                .get_synth(self.synth.as_str())
+                .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?;
+            let p = synth
+                .get_parameter(id)
+                .ok_or(InterpreterError::SimpleError(format!("Parameter {} was not found", id)))?;
+            state::request(&mut self.interface, synth, channel, p, timeout)?;
+        }
+
+        self.state
+            .lock()
+            .unwrap()
+            .value(self.synth.as_str(), channel.unwrap_or(0), id)
+            .cloned()
+            .ok_or(InterpreterError::SimpleError(format!("Value of {} is unknown", id)))
+    }
+
+    /// Sends a new value computed from the current one, failing with a reason
+    /// when there is none
+    fn edit<F>(&mut self, id: &str, reason: &str, f: F) -> Result<(), InterpreterError> where F: Fn(&SynthParameter, &ParameterValue) -> Option<ParameterValue> {
+        let p = self
+            .current_synth()
+            .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?
+            .get_parameter(id)
+            .cloned()
+            .ok_or(InterpreterError::SimpleError(format!("Parameter {} was not found", id)))?;
+
+        let old = self.current_value(id)?;
+        let new = f(&p, &old).ok_or(InterpreterError::SimpleError(format!("Parameter {} {}", id, reason)))?;
+
+        self.send_parameters(&[(p.id.clone(), new.clone())])?;
+        println!("{} : {} -> {}", p.id, p.label(&old), p.label(&new));
+        Ok(())
+    }
+
+    /// Sends parameter values through the synth's parameter change command
+    pub fn send_parameters(&mut self, values: &[(String, ParameterValue)]) -> Result<(), InterpreterError> {
+        let channel = self.channel_argument();
+        let synth = self
+            .config
+            .get_synth(self.synth.as_str())
+            .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?;
+
+        let mut messages: Vec<Vec<u8>> = Vec::new();
+        let mut expected = Patch::new(synth.id.as_str(), "");
+        for (id, value) in values {
+            if let Some(p) = synth.get_parameter(id) {
+                if p.number.is_some() {
+                    messages.push(synth.parameter_change_bytes(channel, p, value)?);
+                    expected.set(id, value.clone());
+                }
+            }
+        }
+
+        self.upload(messages, &expected)
+    }
+
+    /// Sends messages, reading the program back from the synth to compare
+    /// it with the expected patch when verification is on
+    fn upload(&mut self, messages: Vec<Vec<u8>>, expected: &Patch) -> Result<(), InterpreterError> {
+        let channel = self.channel_argument();
+        let synth = self
+            .config
+            .get_synth(self.synth.as_str())
+            .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?;
+
+        let handshake = synth.handshake.clone().unwrap_or(Handshake {
+            pace: std::time::Duration::from_millis(PARAMETER_PACE),
+            ..Handshake::default()
+        });
+
+        match (&self.verifier, synth.program_dump()) {
+            (Some(verifier), Some(dump)) if synth.has_layout() || !expected.data.is_empty() => {
+                let verifier = Verifier { pace: handshake.pace, ..verifier.clone() };
+                verifier.upload(&mut self.interface, synth, dump, channel, &messages, expected)?;
+                println!("Upload verified");
+
+                let mut state = self.state.lock().unwrap();
+                for m in &messages {
+                    state.apply_sysex(synth, channel.unwrap_or(0), m, Direction::Sent);
+                }
+            }
+
+            (verifier, _) => {
+                if verifier.is_some() {
+                    println!("Synth {} can't send back its program, skipping verification", synth.id);
+                }
+
+                for m in messages {
+                    handshake.send(&mut self.interface, &m)?;
+                    self.state
+                        .lock()
+                        .unwrap()
+                        .apply_sysex(synth, channel.unwrap_or(0), &m, Direction::Sent);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends a patch as a program dump, or parameter by parameter
+    pub fn send_patch(&mut self, patch: &Patch, as_dump: bool) -> Result<(), InterpreterError> {
+        if as_dump {
+            let channel = self.channel_argument();
+            let synth = self
+                .current_synth()
+                .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?;
+            let data = synth.program_dump_bytes(channel, patch)?;
+            let expected = Patch::from_dump(synth, patch.name.as_str(), &data);
+            let size = data.len();
+
+            self.upload(vec![data], &expected)?;
+            println!("Send program dump {} ({} bytes)", patch.name, size);
+            Ok(())
+        } else {
+            self.send_parameters(&patch.values)?;
+            println!("Send patch {} ({} parameters)", patch.name, patch.values.len());
+            Ok(())
+        }
+    }
+
+    /// Splits a command line on whitespace, keeping quoted strings whole
+    pub fn tokenize(command: &str) -> Vec<String> {
+        let mut tokens: Vec<String> = Vec::new();
+        let mut current: String = String::new();
+        let mut quoted = false;
+
+        for c in command.chars() {
+            if c == '"' {
+                quoted = !quoted;
+                current.push(c);
+            } else if c.is_whitespace() && !quoted {
+                if !current.is_empty() {
+                    tokens.push(current.clone());
+                    current.clear();
+                }
+            } else {
+                current.push(c);
+            }
+        }
+
+        if !current.is_empty() {
+            tokens.push(current);
+        }
+
+        tokens
+    }
+
     pub fn parse_command(&mut self, command: String) -> Option<InterpreterCommand> {
-        let mut tokens = command.split_whitespace();
+        let token_list = Interpreter::tokenize(&command);
+        let mut tokens = token_list.iter().map(|t| t.as_str());
 
         match tokens.next() {
             Some(command) => match command {
@@ -216,12 +699,12 @@ impl Interpreter {
 
                 "sysex" => match tokens.next() {
                     Some(alias) => {
-                        let mut data: Vec<usize> = Vec::new();
+                        let mut data: Vec<Argument> = Vec::new();
                         while let Some(t) = tokens.next() {
-                            if let Ok(a) = t.parse::<usize>() {
+                            if let Some(a) = Argument::parse(t) {
                                 data.push(a);
                             } else {
-                                println!("Failed to parse argument");
+                                println!("Failed to parse argument {}", t);
                                 return None;
                             }
                         }
@@ -236,12 +719,12 @@ impl Interpreter {
 
                 "midi" => match tokens.next() {
                     Some(alias) => {
-                        let mut data: Vec<usize> = Vec::new();
+                        let mut data: Vec<Argument> = Vec::new();
                         while let Some(t) = tokens.next() {
-                            if let Ok(a) = t.parse::<usize>() {
+                            if let Some(a) = Argument::parse(t) {
                                 data.push(a);
                             } else {
-                                println!("Failed to parse argument");
+                                println!("Failed to parse argument {}", t);
                                 return None;
                             }
                         }
@@ -254,135 +737,764 @@ impl Interpreter {
                     }
                 },
 
-                _ => {
-                    println!("Command {} does not exist", command);
-                }
-            },
-
-            None => {
-                println!("Expected command name, got empty command");
-            }
-        }
-
-        None
-    }
-
-    pub fn run_commands_str(&mut self, commands: &[String]) -> Result<(), InterpreterError> {
-        for command in commands {
-            if let Some(c) = self.parse_command(String::from(command.as_str())) {
-                self.run_command(c)?;
-            }
-        }
-        Ok(())
-    }
+                "diff" => match (tokens.next(), tokens.next()) {
+                    (Some(a), Some(b)) => {
+                        return Some(InterpreterCommand::Diff(String::from(a), String::from(b)));
+                    }
+                    _ => {
+                        println!("Expected two patch files");
+                        return None;
+                    }
+                },
 
-    pub fn run_command_str(&mut self, command: &str) -> Result<(), InterpreterError> {
-        let c = self.parse_command(String::from(command))
-            .ok_or(InterpreterError::SimpleError(format!("Failed to parse command")))?;
-        self.run_command(c)?;
-        Ok(())
-    }
+                "randomize" | "random" => {
+                    let mut randomizer = Randomizer::new(Random::clock_seed());
+                    let mut base: Option<String> = None;
+                    let mut dump = false;
+                    let mut save: Option<String> = None;
 
-    pub fn run_command(&mut self, command: InterpreterCommand) -> Result<(), InterpreterError> {
-        match command {
-            InterpreterCommand::Interactive => {
-                self.repl();
-                Ok(())
-            }
+                    for t in tokens {
+                        let (key, value) = match t.find('=') {
+                            Some(i) => (&t[..i], &t[i + 1..]),
+                            None => (t, ""),
+                        };
 
-            InterpreterCommand::Source(file) => {
-                match File::open(file.clone()) {
-                    Ok(mut file) => {
-                        let mut contents: String = String::new();
-                        file.read_to_string(&mut contents);
-                        let commands = contents.split('\n');
-                        for command in commands {
-                            self.run_command_str(command.trim());
+                        match key {
+                            "seed" => match value.parse::<u64>() {
+                                Ok(s) => randomizer.seed = s,
+                                Err(_) => {
+                                    println!("Failed to parse the seed");
+                                    return None;
+                                }
+                            },
+                            "mutate" => match value.trim_end_matches('%').parse::<f32>() {
+                                Ok(m) => randomizer.mutate(m),
+                                Err(_) => {
+                                    println!("Failed to parse the mutation percentage");
+                                    return None;
+                                }
+                            },
+                            "lock" => {
+                                for id in value.split(',') {
+                                    randomizer.lock(id);
+                                }
+                            }
+                            "ratio" => {
+                                for r in value.split(',') {
+                                    let mut parts = r.split(':');
+                                    match (parts.next(), parts.next().and_then(|x| x.parse::<f32>().ok())) {
+                                        (Some(id), Some(ratio)) => randomizer.ratio(id, ratio / 100.0),
+                                        _ => {
+                                            println!("Expected ratio=parameter:percent, got {}", r);
+                                            return None;
+                                        }
+                                    }
+                                }
+                            }
+                            "from" => base = Some(value.to_owned()),
+                            "save" => save = Some(value.to_owned()),
+                            "dump" => dump = true,
+                            _ => {
+                                println!("Unknown randomize option {}", key);
+                                return None;
+                            }
                         }
                     }
 
-                    Err(e) => {
-                        return Err(InterpreterError::SimpleError(format!("Error sourcing file {} : {}", file.as_str(), e.to_string())));
-                    }
+                    return Some(InterpreterCommand::Randomize(randomizer, base, dump, save));
                 }
-                self.run_file(file);
-                Ok(())
-            }
 
-            InterpreterCommand::Config(file) => {
-                match self.config.run_file(file.as_str()) {
-                    Ok(_a) => {
+                "morph" => {
+                    let mut files: Vec<String> = Vec::new();
+                    let mut position: Option<f32> = None;
+                    let mut duration: Option<Length> = None;
+                    let mut steps: usize = 10;
 
+                    for t in tokens {
+                        if let Some(d) = t.strip_prefix("time=") {
+                            match d.parse::<u64>() {
+                                Ok(d) => duration = Some(Length::Millis(d)),
+                                Err(_) => {
+                                    println!("Failed to parse the morph duration");
+                                    return None;
+                                }
+                            }
+                        } else if let Some(b) = t.strip_prefix("beats=") {
+                            match b.parse::<f32>() {
+                                Ok(b) if b > 0.0 => duration = Some(Length::Beats(b)),
+                                _ => {
+                                    println!("Failed to parse the morph beat count");
+                                    return None;
+                                }
+                            }
+                        } else if let Some(s) = t.strip_prefix("steps=") {
+                            match s.parse::<usize>() {
+                                Ok(s) if s > 0 => steps = s,
+                                _ => {
+                                    println!("Failed to parse the morph step count");
+                                    return None;
+                                }
+                            }
+                        } else if let Ok(p) = t.parse::<f32>() {
+                            position = Some(p);
+                        } else {
+                            files.push(String::from(t));
+                        }
                     }
 
-                    Err(_e) => {
-                        return Err(InterpreterError::SimpleError(format!("Error loading config file")));
-                    }
-                };
-                Ok(())
-            }
+                    let files = match files.len() {
+                        0 => None,
+                        2 => Some((files[0].clone(), files[1].clone())),
+                        _ => {
+                            println!("Expected two patch files to morph between");
+                            return None;
+                        }
+                    };
 
-            InterpreterCommand::Synth(id) => {
-                if self.config.has_synth(id.as_str()) {
-                    let sysex = self.config.load_synth(id.as_str()).ok_or(InterpreterError::SimpleError(format!("Failed to load synth {} sysex configuration", id)))?; 
-                    self.sysex = sysex;
-                    Ok(())
-                } else {
-                    Err(InterpreterError::SimpleError(format!("Synth {} was not found", id)))
+                    return Some(InterpreterCommand::Morph(files, position, duration, steps));
                 }
-            }
 
-            InterpreterCommand::MidiConfig(file) => {
-                self.config.run_file(file.as_str());
-                if self.config.has_synth("midi") {
-                    let midi = self.config.load_synth("midi").ok_or(InterpreterError::SimpleError(format!("Failed to load MIDI standard")))?; 
-                    self.midi = midi;
-                    Ok(())
-                } else {
-                    Err(InterpreterError::SimpleError(format!("MIDI standard configuration was not found")))
-                }
-            }
+                "backup" => {
+                    let mut synth: Option<String> = None;
+                    let mut root = String::from("backups");
+                    let mut timeout: u64 = 2000;
 
-            InterpreterCommand::Port(midi_in, midi_out) => {
-                self.interface.set_input_port(midi_in, |_stamp, message, _|{
-                    println!("{:?}", message);
-                })?;
+                    for t in tokens {
+                        if let Some(d) = t.strip_prefix("dir=") {
+                            root = String::from(d);
+                        } else if let Some(ms) = t.strip_prefix("timeout=") {
+                            match ms.parse::<u64>() {
+                                Ok(ms) => timeout = ms,
+                                Err(_) => {
+                                    println!("Failed to parse the timeout");
+                                    return None;
+                                }
+                            }
+                        } else {
+                            synth = Some(String::from(t));
+                        }
+                    }
 
-                if let Some(o) = midi_out {
-                    self.interface.set_output_port(o)?;
+                    return Some(InterpreterCommand::Backup(synth, root, timeout));
                 }
-                Ok(())
-            }
-
-            InterpreterCommand::PortList => {
-                println!("{:?}", MidiInterface::list_input_ports().unwrap_or("Error listing the MIDI input ports".to_owned()));
-                println!("{:?}", MidiInterface::list_output_ports().unwrap_or("Error listing the MIDI output ports".to_owned()));
-                Ok(())
-            }
-
-            InterpreterCommand::Channel(channel) => {
-                self.channel = channel;
-                Ok(())
-            }
 
-            InterpreterCommand::Receive(timeout) => {
-                Ok(())
-            }
-
-            InterpreterCommand::Send(bytes) => {
-                self.interface.send_midi(&bytes)?;
-                Ok(())
-            }
+                "restore" => match tokens.next() {
+                    Some(dir) => {
+                        let mut pace: u64 = 100;
+                        let mut verify: Option<Verifier> = Some(Verifier::new(2, std::time::Duration::from_millis(2000)));
+
+                        for t in tokens {
+                            if t == "noverify" {
+                                verify = None;
+                            } else if let Some(n) = t.strip_prefix("retries=") {
+                                match (n.parse::<usize>(), verify.as_mut()) {
+                                    (Ok(n), Some(v)) => v.retries = n,
+                                    (Ok(_), None) => {}
+                                    (Err(_), _) => {
+                                        println!("Failed to parse the retry count");
+                                        return None;
+                                    }
+                                }
+                            } else if let Some(ms) = t.strip_prefix("pace=") {
+                                match ms.parse::<u64>() {
+                                    Ok(ms) => pace = ms,
+                                    Err(_) => {
+                                        println!("Failed to parse the pace");
+                                        return None;
+                                    }
+                                }
+                            } else if let Some(ms) = t.strip_prefix("timeout=") {
+                                match (ms.parse::<u64>(), verify.as_mut()) {
+                                    (Ok(ms), Some(v)) => v.timeout = std::time::Duration::from_millis(ms),
+                                    (Ok(_), None) => {}
+                                    (Err(_), _) => {
+                                        println!("Failed to parse the timeout");
+                                        return None;
+                                    }
+                                }
+                            } else {
+                                println!("Unknown restore option {}", t);
+                                return None;
+                            }
+                        }
+
+                        return Some(InterpreterCommand::Restore(String::from(dir), pace, verify));
+                    }
+                    None => {
+                        println!("Missing 'backup directory' argument");
+                        return None;
+                    }
+                },
+
+                "verify" => {
+                    let mut verifier = Some(Verifier::new(2, std::time::Duration::from_millis(2000)));
+
+                    for t in tokens {
+                        if t == "off" {
+                            verifier = None;
+                        } else if t == "on" {
+                            verifier = verifier.or(Some(Verifier::new(2, std::time::Duration::from_millis(2000))));
+                        } else if let Some(n) = t.strip_prefix("retries=") {
+                            match (n.parse::<usize>(), verifier.as_mut()) {
+                                (Ok(n), Some(v)) => v.retries = n,
+                                (Ok(_), None) => {}
+                                (Err(_), _) => {
+                                    println!("Failed to parse the retry count");
+                                    return None;
+                                }
+                            }
+                        } else if let Some(ms) = t.strip_prefix("timeout=") {
+                            match (ms.parse::<u64>(), verifier.as_mut()) {
+                                (Ok(ms), Some(v)) => v.timeout = std::time::Duration::from_millis(ms),
+                                (Ok(_), None) => {}
+                                (Err(_), _) => {
+                                    println!("Failed to parse the timeout");
+                                    return None;
+                                }
+                            }
+                        } else {
+                            println!("Unknown verify option {}", t);
+                            return None;
+                        }
+                    }
+
+                    return Some(InterpreterCommand::Verify(verifier));
+                }
+
+                "state" => match tokens.next() {
+                    Some("save") => match tokens.next() {
+                        Some(file) => {
+                            return Some(InterpreterCommand::State(Some(String::from(file))));
+                        }
+                        None => {
+                            println!("Missing 'patch file' argument");
+                            return None;
+                        }
+                    },
+                    Some("clear") => {
+                        return Some(InterpreterCommand::ClearState);
+                    }
+                    Some(t) => {
+                        println!("Unknown state option {}", t);
+                        return None;
+                    }
+                    None => {
+                        return Some(InterpreterCommand::State(None));
+                    }
+                },
+
+                "inc" | "dec" => match tokens.next() {
+                    Some(id) => {
+                        let steps = match tokens.next().map(|t| t.parse::<isize>()) {
+                            Some(Ok(s)) => s,
+                            Some(Err(_)) => {
+                                println!("Failed to parse the step count");
+                                return None;
+                            }
+                            None => 1,
+                        };
+                        let steps = if command == "dec" { -steps } else { steps };
+                        return Some(InterpreterCommand::Step(String::from(id), steps));
+                    }
+                    None => {
+                        println!("Missing 'parameter' argument");
+                        return None;
+                    }
+                },
+
+                "toggle" => match tokens.next() {
+                    Some(id) => {
+                        return Some(InterpreterCommand::Toggle(String::from(id)));
+                    }
+                    None => {
+                        println!("Missing 'parameter' argument");
+                        return None;
+                    }
+                },
+
+                "sync" => match (tokens.next(), tokens.next()) {
+                    (None, _) => {
+                        return Some(InterpreterCommand::Sync(None));
+                    }
+                    (Some("auto"), Some("on")) => {
+                        return Some(InterpreterCommand::Sync(Some(true)));
+                    }
+                    (Some("auto"), Some("off")) => {
+                        return Some(InterpreterCommand::Sync(Some(false)));
+                    }
+                    _ => {
+                        println!("Expected 'sync' or 'sync auto on|off'");
+                        return None;
+                    }
+                },
+
+                "echo" => match tokens.next() {
+                    Some("on") => {
+                        return Some(InterpreterCommand::Echo(true));
+                    }
+                    Some("off") => {
+                        return Some(InterpreterCommand::Echo(false));
+                    }
+                    _ => {
+                        println!("Expected 'echo on' or 'echo off'");
+                        return None;
+                    }
+                },
+
+                "realtime" => {
+                    let filtered = match tokens.next() {
+                        Some("drop") => true,
+                        Some("pass") => false,
+                        _ => {
+                            println!("Expected 'realtime drop|pass messages'");
+                            return None;
+                        }
+                    };
+
+                    let mut status: Vec<u8> = Vec::new();
+                    for t in tokens {
+                        let s = match t {
+                            "clock" => midi_parser::CLOCK,
+                            "start" => midi_parser::START,
+                            "continue" => midi_parser::CONTINUE,
+                            "stop" => midi_parser::STOP,
+                            "sensing" => midi_parser::ACTIVE_SENSING,
+                            "reset" => midi_parser::RESET,
+                            _ => match u8::from_str_radix(t, 16) {
+                                Ok(s) if s >= 0xF8 => s,
+                                _ => {
+                                    println!("Unknown realtime message {}", t);
+                                    return None;
+                                }
+                            },
+                        };
+                        status.push(s);
+                    }
+
+                    return Some(InterpreterCommand::RealtimeFilter(status, filtered));
+                }
+
+                "monitor" => {
+                    let mut enabled = true;
+                    let mut filter: Option<MonitorFilter> = None;
+
+                    for t in tokens {
+                        let (key, value) = match t.find('=') {
+                            Some(i) => (&t[..i], &t[i + 1..]),
+                            None => (t, ""),
+                        };
+
+                        match key {
+                            "on" => enabled = true,
+                            "off" => enabled = false,
+                            "all" => filter = Some(MonitorFilter::default()),
+                            "type" => {
+                                let f = filter.get_or_insert_with(MonitorFilter::default);
+                                for k in value.split(',') {
+                                    match MonitorFilter::kind_from_str(k) {
+                                        Some(k) => f.kinds.push(k),
+                                        None => {
+                                            println!("Unknown message type {}, expected channel, common, realtime or sysex", k);
+                                            return None;
+                                        }
+                                    }
+                                }
+                            }
+                            "channel" => {
+                                let f = filter.get_or_insert_with(MonitorFilter::default);
+                                for c in value.split(',') {
+                                    match c.parse::<usize>() {
+                                        Ok(c) => f.channels.push(c),
+                                        Err(_) => {
+                                            println!("Failed to parse the channel {}", c);
+                                            return None;
+                                        }
+                                    }
+                                }
+                            }
+                            "synth" => {
+                                let f = filter.get_or_insert_with(MonitorFilter::default);
+                                f.synths.extend(value.split(',').map(String::from));
+                            }
+                            _ => {
+                                println!("Unknown monitor option {}", key);
+                                return None;
+                            }
+                        }
+                    }
+
+                    return Some(InterpreterCommand::Monitor(enabled, filter));
+                }
+
+                "record" => match (tokens.next(), tokens.next()) {
+                    (Some("stop"), _) => {
+                        return Some(InterpreterCommand::Record(None));
+                    }
+                    (Some("midi"), Some(file)) => {
+                        let mut format: u16 = 1;
+                        let mut ppq: u16 = 480;
+                        let mut bpm: f64 = 120.0;
+
+                        for t in tokens {
+                            let (key, value) = match t.find('=') {
+                                Some(i) => (&t[..i], &t[i + 1..]),
+                                None => (t, ""),
+                            };
+
+                            let parsed = match key {
+                                "type" => value.parse().ok().filter(|f| *f <= 1).map(|f| format = f),
+                                "ppq" => value.parse().ok().filter(|p| *p > 0 && *p < 0x8000).map(|p| ppq = p),
+                                "bpm" => value.parse().ok().filter(|b| smf::tempo(*b).is_some()).map(|b| bpm = b),
+                                _ => None,
+                            };
+
+                            if parsed.is_none() {
+                                println!("Bad record option {}, expected type=0|1, ppq=ticks or bpm=tempo", t);
+                                return None;
+                            }
+                        }
+                        return Some(InterpreterCommand::RecordMidi(String::from(file), format, ppq, bpm));
+                    }
+                    (Some("split"), Some(dir)) => {
+                        return Some(InterpreterCommand::Record(Some((String::from(dir), true))));
+                    }
+                    (Some(file), None) if file != "midi" && file != "split" => {
+                        return Some(InterpreterCommand::Record(Some((String::from(file), false))));
+                    }
+                    _ => {
+                        println!("Expected 'record file.syx', 'record split directory', 'record midi file.mid' or 'record stop'");
+                        return None;
+                    }
+                },
+
+                "sendfile" => match tokens.next() {
+                    Some(file) => {
+                        let mut pace: u64 = 50;
+                        let mut identify = false;
+
+                        for t in tokens {
+                            if t == "identify" {
+                                identify = true;
+                            } else if let Some(ms) = t.strip_prefix("pace=") {
+                                match ms.parse::<u64>() {
+                                    Ok(ms) => pace = ms,
+                                    Err(_) => {
+                                        println!("Failed to parse the pace");
+                                        return None;
+                                    }
+                                }
+                            } else {
+                                println!("Unknown sendfile option {}", t);
+                                return None;
+                            }
+                        }
+
+                        return Some(InterpreterCommand::SendFile(String::from(file), pace, identify));
+                    }
+                    None => {
+                        println!("Missing 'syx file' argument");
+                        return None;
+                    }
+                },
+
+                "syx-info" => match tokens.next() {
+                    Some(file) => {
+                        let mut dir = String::from("data");
+                        for t in tokens {
+                            if let Some(d) = t.strip_prefix("dir=") {
+                                dir = String::from(d);
+                            } else {
+                                println!("Unknown syx-info option {}", t);
+                                return None;
+                            }
+                        }
+
+                        return Some(InterpreterCommand::SyxInfo(String::from(file), dir));
+                    }
+                    None => {
+                        println!("Missing 'syx file' argument");
+                        return None;
+                    }
+                },
+
+                "sds" => match (tokens.next(), tokens.next(), tokens.next()) {
+                    (Some("get"), Some(n), Some(file)) => match n.parse::<u16>() {
+                        Ok(n) => return Some(InterpreterCommand::SampleReceive(n, String::from(file))),
+                        Err(_) => {
+                            println!("Failed to parse the sample number");
+                            return None;
+                        }
+                    },
+                    (Some("send"), Some(file), n) => match n.unwrap_or("0").parse::<u16>() {
+                        Ok(n) => return Some(InterpreterCommand::SampleSend(String::from(file), n)),
+                        Err(_) => {
+                            println!("Failed to parse the sample number");
+                            return None;
+                        }
+                    },
+                    _ => {
+                        println!("Expected 'sds get number file' or 'sds send file [number]'");
+                        return None;
+                    }
+                },
+
+                "rq1" => match tokens.next() {
+                    Some(target) => match tokens.next().map(|s| s.parse::<u32>()) {
+                        Some(Err(_)) => {
+                            println!("Failed to parse the size");
+                            return None;
+                        }
+                        size => {
+                            let target = String::from(target.trim_matches('"'));
+                            return Some(InterpreterCommand::AddressRead(target, size.and_then(Result::ok)));
+                        }
+                    },
+                    None => {
+                        println!("Missing 'area or address' argument");
+                        return None;
+                    }
+                },
+
+                "dt1" => match tokens.next() {
+                    Some(target) => match parse_hex(&tokens.map(|t| t.trim_matches('"')).collect::<Vec<&str>>().join(" ")) {
+                        Some(data) => return Some(InterpreterCommand::AddressWrite(String::from(target.trim_matches('"')), data)),
+                        None => {
+                            println!("Expected the data as hex bytes");
+                            return None;
+                        }
+                    },
+                    None => {
+                        println!("Missing 'area or address' argument");
+                        return None;
+                    }
+                },
+
+                "play" => match tokens.next() {
+                    Some(file) => {
+                        let mut looping = false;
+                        let mut keep = false;
+                        for t in tokens {
+                            match t {
+                                "loop" => looping = true,
+                                "keep" => keep = true,
+                                _ => {
+                                    println!("Unknown play option {}", t);
+                                    return None;
+                                }
+                            }
+                        }
+                        return Some(InterpreterCommand::Play(String::from(file), looping, keep));
+                    }
+                    None => {
+                        println!("Missing 'MIDI file' argument");
+                        return None;
+                    }
+                },
+
+                "stop" => {
+                    return Some(InterpreterCommand::Stop);
+                }
+
+                "clock" => {
+                    let command = match (tokens.next(), tokens.next()) {
+                        (None, _) => InterpreterCommand::ClockStatus,
+                        (Some("start"), None) => InterpreterCommand::Clock(ClockCommand::Start),
+                        (Some("stop"), None) => InterpreterCommand::Clock(ClockCommand::Stop),
+                        (Some("continue"), None) => InterpreterCommand::Clock(ClockCommand::Continue),
+                        (Some("off"), None) => InterpreterCommand::ClockOff,
+                        (Some("follow"), None) => InterpreterCommand::ClockFollow(true),
+                        (Some("follow"), Some("off")) => InterpreterCommand::ClockFollow(false),
+                        (Some("position"), Some(p)) => match p.parse::<u16>() {
+                            Ok(p) if p < 0x4000 => InterpreterCommand::Clock(ClockCommand::Position(p)),
+                            _ => {
+                                println!("Failed to parse the song position, expected sixteenth notes from 0 to 16383");
+                                return None;
+                            }
+                        },
+                        (Some(bpm), None) => match bpm.parse::<f64>() {
+                            Ok(bpm) if (20.0..=300.0).contains(&bpm) => InterpreterCommand::Clock(ClockCommand::Tempo(bpm)),
+                            _ => {
+                                println!("Failed to parse the tempo, expected 20 to 300 BPM");
+                                return None;
+                            }
+                        },
+                        _ => {
+                            println!("Expected 'clock [bpm|start|stop|continue|off]', 'clock position sixteenths' or 'clock follow [off]'");
+                            return None;
+                        }
+                    };
+                    return Some(command);
+                }
+
+                "detect" => {
+                    let mut timeout: u64 = 500;
+                    for t in tokens {
+                        match t.strip_prefix("timeout=").map(|ms| ms.parse::<u64>()) {
+                            Some(Ok(ms)) => timeout = ms,
+                            Some(Err(_)) => {
+                                println!("Failed to parse the timeout");
+                                return None;
+                            }
+                            None => {
+                                println!("Unknown detect option {}", t);
+                                return None;
+                            }
+                        }
+                    }
+
+                    return Some(InterpreterCommand::Detect(timeout));
+                }
+
+                "upload" => match tokens.next() {
+                    Some(file) => {
+                        let dump = tokens.next() == Some("dump");
+                        return Some(InterpreterCommand::Upload(String::from(file), dump));
+                    }
+                    None => {
+                        println!("Missing 'patch file' argument");
+                        return None;
+                    }
+                },
+
+                _ => {
+                    println!("Command {} does not exist", command);
+                }
+            },
+
+            None => {
+                println!("Expected command name, got empty command");
+            }
+        }
+
+        None
+    }
+
+    pub fn run_commands_str(&mut self, commands: &[String]) -> Result<(), InterpreterError> {
+        for command in commands {
+            if let Some(c) = self.parse_command(String::from(command.as_str())) {
+                self.run_command(c)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn run_command_str(&mut self, command: &str) -> Result<(), InterpreterError> {
+        let c = self.parse_command(String::from(command))
+            .ok_or(InterpreterError::SimpleError(format!("Failed to parse command")))?;
+        self.run_command(c)?;
+        Ok(())
+    }
+
+    pub fn run_command(&mut self, command: InterpreterCommand) -> Result<(), InterpreterError> {
+        match command {
+            InterpreterCommand::Interactive => {
+                self.repl();
+                Ok(())
+            }
+
+            InterpreterCommand::Source(file) => {
+                match File::open(file.clone()) {
+                    Ok(mut file) => {
+                        let mut contents: String = String::new();
+                        file.read_to_string(&mut contents);
+                        let commands = contents.split('\n');
+                        for command in commands {
+                            self.run_command_str(command.trim());
+                        }
+                    }
+
+                    Err(e) => {
+                        return Err(InterpreterError::SimpleError(format!("Error sourcing file {} : {}", file.as_str(), e.to_string())));
+                    }
+                }
+                self.run_file(file);
+                Ok(())
+            }
+
+            InterpreterCommand::Config(file) => {
+                match self.config.run_file(file.as_str()) {
+                    Ok(_a) => {
+
+                    }
+
+                    Err(_e) => {
+                        return Err(InterpreterError::SimpleError(format!("Error loading config file")));
+                    }
+                };
+                self.update_tracker()
+            }
+
+            InterpreterCommand::Synth(id) => {
+                if self.config.has_synth(id.as_str()) {
+                    let sysex = self.config.load_synth(id.as_str()).ok_or(InterpreterError::SimpleError(format!("Failed to load synth {} sysex configuration", id)))?; 
+                    self.sysex = sysex;
+                    self.synth = id;
+                    self.update_tracker()?;
+                    self.auto_sync();
+                    Ok(())
+                } else {
+                    Err(InterpreterError::SimpleError(format!("Synth {} was not found", id)))
+                }
+            }
+
+            InterpreterCommand::MidiConfig(file) => {
+                self.config.run_file(file.as_str());
+                if self.config.has_synth("midi") {
+                    let midi = self.config.load_synth("midi").ok_or(InterpreterError::SimpleError(format!("Failed to load MIDI standard")))?; 
+                    self.midi = midi;
+                    self.update_tracker()
+                } else {
+                    Err(InterpreterError::SimpleError(format!("MIDI standard configuration was not found")))
+                }
+            }
+
+            InterpreterCommand::Port(midi_in, midi_out) => {
+                self.interface.set_input_port(midi_in, self.tracker())?;
+
+                if let Some(o) = midi_out {
+                    self.interface.set_output_port(o)?;
+                    self.auto_sync();
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::PortList => {
+                println!("{:?}", MidiInterface::list_input_ports().unwrap_or("Error listing the MIDI input ports".to_owned()));
+                println!("{:?}", MidiInterface::list_output_ports().unwrap_or("Error listing the MIDI output ports".to_owned()));
+                Ok(())
+            }
+
+            InterpreterCommand::Channel(channel) => {
+                self.channel = channel;
+                self.update_tracker()
+            }
+
+            InterpreterCommand::Receive(timeout) => {
+                let timeout = std::time::Duration::from_millis(timeout as u64);
+                while let Some(message) = self.interface.receive(timeout) {
+                    println!("Received {:?}", message);
+                    for c in self.record(&message, Direction::Received) {
+                        println!("{}", c.describe(self.current_synth()));
+                    }
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::Send(bytes) => {
+                self.interface.send_midi(&bytes)?;
+                Ok(())
+            }
 
             InterpreterCommand::Sysex(command, mut data) => {
                 if self.channel >= 0 {
-                    data.insert(0, self.channel as usize);
+                    data.insert(0, Argument::Positional(ParameterValue::Integer(self.channel as usize)));
                 }
 
                 if let Some(sysex) = self.sysex.get(command.as_str()) {
-                    let data = sysex.generate_bytes(&data);
+                    let data = stob(sysex.generate_arguments(&data)?.as_str());
                     self.interface.send_midi(&data)?;
                     println!("Send SYSEX {} with data {:?}", command, data);
+                    self.record(&data, Direction::Sent);
                     Ok(())
                 } else {
                     Err(InterpreterError::SimpleError(format!("SYSEX command {} not found", command)))
@@ -391,13 +1503,14 @@ impl Interpreter {
 
             InterpreterCommand::Midi(command, mut data) => {
                 if self.channel >= 0 {
-                    data.insert(0, self.channel as usize);
+                    data.insert(0, Argument::Positional(ParameterValue::Integer(self.channel as usize)));
                 }
 
                 if let Some(midi) = self.midi.get(command.as_str()) {
-                    let data = midi.generate_bytes(&data);
+                    let data = stob(midi.generate_arguments(&data)?.as_str());
                     self.interface.send_midi(&data)?;
                     println!("Send MIDI {} with data {:?}", command, data);
+                    self.record(&data, Direction::Sent);
                     Ok(())
                 } else {
                     Err(InterpreterError::SimpleError(format!("MIDI command {} not found", command)))
@@ -415,6 +1528,506 @@ impl Interpreter {
                 }
             }
 
+            InterpreterCommand::Diff(a, b) => {
+                let synth = self.current_synth();
+                let a = Patch::load(a.as_str(), synth)?;
+                let b = Patch::load(b.as_str(), synth)?;
+                let d = diff(synth, &a, &b);
+
+                if d.is_empty() {
+                    println!("Patches {} and {} are identical", a.name, b.name);
+                } else {
+                    print!("{}", d);
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::Randomize(randomizer, base, dump, save) => {
+                let synth = self
+                    .current_synth()
+                    .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?;
+                let base = match base {
+                    Some(file) => Some(Patch::load(file.as_str(), Some(synth))?),
+                    None => None,
+                };
+                let patch = randomizer.generate(synth, base.as_ref());
+
+                println!("Random patch with seed {}", randomizer.seed);
+                if let Some(file) = save {
+                    patch.save(file.as_str())?;
+                }
+                self.send_patch(&patch, dump)
+            }
+
+            InterpreterCommand::Morph(files, position, duration, steps) => {
+                let duration = duration.map(|d| self.millis(d)).transpose()?;
+                if let Some((a, b)) = files {
+                    let synth = self.current_synth();
+                    let a = Patch::load(a.as_str(), synth)?;
+                    let b = Patch::load(b.as_str(), synth)?;
+                    self.morpher = Some(Morpher::new(a, b));
+                }
+
+                let synth = self
+                    .current_synth()
+                    .cloned()
+                    .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?;
+                let mut morpher = self
+                    .morpher
+                    .take()
+                    .ok_or(InterpreterError::SimpleError(String::from("No patches to morph between")))?;
+
+                let start = morpher.position;
+                let target = position.map(|p| p / 100.0).unwrap_or(if duration.is_some() { 1.0 } else { start });
+                let positions: Vec<f32> = match duration {
+                    Some(_) => (1..=steps)
+                        .map(|i| start + (target - start) * i as f32 / steps as f32)
+                        .collect(),
+                    None => vec![target],
+                };
+                let pause = std::time::Duration::from_millis(duration.unwrap_or(0) / steps as u64);
+
+                let mut result = Ok(());
+                for (i, p) in positions.iter().enumerate() {
+                    match morpher.step(&synth, *p, |changes| self.send_parameters(changes)) {
+                        Ok(count) => println!("Morph {:.0}% ({} parameters)", p * 100.0, count),
+                        Err(e) => {
+                            result = Err(e);
+                            break;
+                        }
+                    }
+
+                    if i + 1 < positions.len() {
+                        std::thread::sleep(pause);
+                    }
+                }
+
+                self.morpher = Some(morpher);
+                result
+            }
+
+            InterpreterCommand::Backup(synth, root, timeout) => {
+                let id = synth.unwrap_or(self.synth.clone());
+                let channel = self.channel_argument();
+                let synth = self
+                    .config
+                    .get_synth(id.as_str())
+                    .ok_or(InterpreterError::SimpleError(format!("Synth {} was not found", id)))?;
+
+                let dir = backup_directory(root.as_str(), id.as_str());
+                let manifest = backup(
+                    &mut self.interface,
+                    synth,
+                    channel,
+                    &dir,
+                    std::time::Duration::from_millis(timeout),
+                )?;
+                println!("Saved {} dumps to {}", manifest.entries.len(), dir.display());
+                Ok(())
+            }
+
+            InterpreterCommand::Restore(dir, pace, verify) => {
+                let dir = std::path::Path::new(dir.as_str());
+                let manifest = Manifest::load(dir)?;
+                let synth = self
+                    .config
+                    .get_synth(manifest.synth.as_str())
+                    .ok_or(InterpreterError::SimpleError(format!("Synth {} was not found", manifest.synth)))?;
+
+                let manifest = restore(
+                    &mut self.interface,
+                    synth,
+                    dir,
+                    std::time::Duration::from_millis(pace),
+                    verify.as_ref(),
+                )?;
+                println!("Restored {} dumps from {}", manifest.entries.len(), dir.display());
+                Ok(())
+            }
+
+            InterpreterCommand::Verify(verifier) => {
+                match &verifier {
+                    Some(v) => println!("Verifying uploads ({} retries, {} ms timeout)", v.retries, v.timeout.as_millis()),
+                    None => println!("Not verifying uploads"),
+                }
+                self.verifier = verifier;
+                Ok(())
+            }
+
+            InterpreterCommand::Upload(file, dump) => {
+                let patch = Patch::load(file.as_str(), self.current_synth())?;
+                self.send_patch(&patch, dump)
+            }
+
+            InterpreterCommand::State(None) => {
+                let state = self.state();
+                if state.sessions().is_empty() {
+                    println!("No parameter values sent or received");
+                }
+
+                for (id, channel) in state.sessions() {
+                    println!("{} (channel {})", id, channel);
+                    let synth = self.config.get_synth(id);
+
+                    for (key, s) in state.parameters(id, channel).into_iter().flatten() {
+                        let p = synth.and_then(|synth| synth.get_parameter(key));
+                        let show = |v: &Option<ParameterValue>| match (v, p) {
+                            (Some(v), Some(p)) => p.label(v),
+                            (Some(v), None) => v.to_string(),
+                            (None, _) => String::from("-"),
+                        };
+                        println!("  {} : {} (sent {}, received {})", key, show(&s.value), show(&s.sent), show(&s.received));
+                    }
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::State(Some(file)) => {
+                let channel = self.channel_argument().unwrap_or(0);
+                let synth = self
+                    .current_synth()
+                    .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?;
+                let name = std::path::Path::new(file.as_str())
+                    .file_stem()
+                    .map(|s| s.to_string_lossy().into_owned())
+                    .unwrap_or_default();
+
+                let patch = self.state.lock().unwrap().to_patch(synth, channel, name.as_str());
+                patch.save(file.as_str())?;
+                println!("Saved {} parameters to {}", patch.values.len(), file);
+                Ok(())
+            }
+
+            InterpreterCommand::ClearState => {
+                self.state.lock().unwrap().clear();
+                Ok(())
+            }
+
+            InterpreterCommand::Step(id, steps) => self.edit(id.as_str(), "is not numeric", |p, v| p.step(v, steps)),
+
+            InterpreterCommand::Toggle(id) => self.edit(id.as_str(), "can't be toggled", |p, v| p.toggle(v)),
+
+            InterpreterCommand::Sync(None) => {
+                let changes = self.sync()?;
+                println!("Synced {} ({} values changed)", self.synth, changes.len());
+                Ok(())
+            }
+
+            InterpreterCommand::Sync(Some(auto)) => {
+                self.auto_sync = auto;
+                self.auto_sync();
+                Ok(())
+            }
+
+            InterpreterCommand::RealtimeFilter(status, filtered) => {
+                for s in status {
+                    self.interface.filter_realtime(s, filtered);
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::Monitor(enabled, filter) => {
+                self.observe_output();
+
+                let mut monitor = self.monitor.lock().unwrap();
+                monitor.update_definitions(&self.config);
+                monitor.enabled = enabled;
+                if let Some(f) = filter {
+                    monitor.filter = f;
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::Record(Some((path, split))) => {
+                let recorder = Recorder::start(std::path::Path::new(path.as_str()), split)?;
+                self.monitor.lock().unwrap().update_definitions(&self.config);
+                *self.recorder.lock().unwrap() = Some(recorder);
+                println!("Recording SysEx to {}", path);
+                Ok(())
+            }
+
+            InterpreterCommand::RecordMidi(path, format, ppq, bpm) => {
+                let recorder = MidiFileRecorder::start(std::path::Path::new(path.as_str()), format, ppq, bpm)?;
+                self.observe_output();
+                *self.midi_recorder.lock().unwrap() = Some(recorder);
+                println!("Recording MIDI to {} (type {}, {} PPQ, {} BPM)", path, format, ppq, bpm);
+                Ok(())
+            }
+
+            InterpreterCommand::Record(None) => {
+                let syx = self.recorder.lock().unwrap().take();
+                let midi = self.midi_recorder.lock().unwrap().take();
+
+                if let Some(r) = &syx {
+                    println!("Recorded {} messages ({} bytes) to {}", r.messages, r.bytes, r.path.display());
+                }
+                if let Some(r) = &midi {
+                    r.finish()?;
+                    println!("Recorded {} messages to {}", r.messages(), r.path.display());
+                }
+                if syx.is_none() && midi.is_none() {
+                    println!("Not recording");
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::SendFile(file, pace, identify) => {
+                let messages = syx::load(std::path::Path::new(file.as_str()))?;
+                let synths = self.config.synths();
+                let handshake = Handshake {
+                    pace: std::time::Duration::from_millis(pace),
+                    ..self.current_synth().and_then(|s| s.handshake.clone()).unwrap_or_default()
+                };
+                let sent = syx::send_messages(
+                    &mut self.interface,
+                    &messages,
+                    &handshake,
+                    if identify { Some(&synths) } else { None },
+                )?;
+                println!("Sent {} messages from {}", sent, file);
+                Ok(())
+            }
+
+            InterpreterCommand::SyxInfo(file, dir) => {
+                let data = std::fs::read(file.as_str())?;
+                let mut definitions = Config::new();
+                definitions
+                    .run_directory(dir.as_str())
+                    .map_err(|e| InterpreterError::SimpleError(format!("Error loading {} : {:?}", dir, e)))?;
+
+                let infos = syx::info(&data, &definitions.synths());
+                for (i, m) in infos.iter().enumerate() {
+                    println!("[{}] {}", i + 1, m);
+                }
+
+                let invalid = infos.iter().filter(|m| !m.is_valid()).count();
+                let unknown = infos.iter().filter(|m| m.data.first() == Some(&0xF0) && m.identified.is_none()).count();
+                println!("{} : {} entries, {} unknown, {} with problems", file, infos.len(), unknown, invalid);
+                Ok(())
+            }
+
+            InterpreterCommand::SampleReceive(number, file) => {
+                let channel = self.channel_argument().unwrap_or(0) as u8;
+                let sample = sds::receive(&mut self.interface, channel, Some(number))?;
+                wav::write(std::path::Path::new(file.as_str()), &sample)?;
+                println!(
+                    "Saved sample {} to {} : {} words, {} bits, {} Hz",
+                    number,
+                    file,
+                    sample.data.len(),
+                    sample.bits,
+                    sample.rate()
+                );
+                Ok(())
+            }
+
+            InterpreterCommand::SampleSend(file, number) => {
+                let channel = self.channel_argument().unwrap_or(0) as u8;
+                let mut sample = wav::read(std::path::Path::new(file.as_str()))?;
+                sample.number = number;
+                let packets = sds::send(&mut self.interface, channel, &sample)?;
+                println!("Sent {} as sample {} : {} packets", file, number, packets);
+                Ok(())
+            }
+
+            InterpreterCommand::AddressRead(target, size) => {
+                let synth = self
+                    .config
+                    .get_synth(self.synth.as_str())
+                    .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?;
+                let map = synth
+                    .address_map
+                    .as_ref()
+                    .ok_or(InterpreterError::SimpleError(format!("Synth {} has no address map", synth.id)))?;
+
+                let (address, area_size) = match map.area(target.as_str()) {
+                    Some(a) => (a.address, Some(a.size)),
+                    None => (
+                        parse_hex(target.as_str())
+                            .map(|a| to_linear(&a))
+                            .ok_or(InterpreterError::SimpleError(format!("Unknown area {}", target)))?,
+                        None,
+                    ),
+                };
+
+                let timeout = self.reply_timeout();
+                let data = map.read(&mut self.interface, address, size.or(area_size).unwrap_or(1), timeout)?;
+                for (i, row) in data.chunks(16).enumerate() {
+                    println!("{} : {}", map.format_address(address + i as u32 * 16), btos(row));
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::AddressWrite(target, data) => {
+                let synth = self
+                    .config
+                    .get_synth(self.synth.as_str())
+                    .ok_or(InterpreterError::SimpleError(String::from("No synth selected")))?;
+                let map = synth
+                    .address_map
+                    .as_ref()
+                    .ok_or(InterpreterError::SimpleError(format!("Synth {} has no address map", synth.id)))?;
+
+                let address = match map.area(target.as_str()) {
+                    Some(a) if data.len() as u32 > a.size => {
+                        return Err(InterpreterError::SimpleError(format!("{} is only {} bytes long", a.name, a.size)))
+                    }
+                    Some(a) => a.address,
+                    None => parse_hex(target.as_str())
+                        .map(|a| to_linear(&a))
+                        .ok_or(InterpreterError::SimpleError(format!("Unknown area {}", target)))?,
+                };
+
+                let handshake = synth.handshake.clone().unwrap_or(Handshake {
+                    pace: std::time::Duration::from_millis(DT1_PACE),
+                    ..Handshake::default()
+                });
+                let messages = map.write(&mut self.interface, address, &data, &handshake)?;
+                println!("Wrote {} bytes at {} in {} messages", data.len(), map.format_address(address), messages);
+                Ok(())
+            }
+
+            InterpreterCommand::Play(file, looping, keep) => {
+                if !self.interface.has_output() {
+                    return Err(InterpreterError::SimpleError(String::from("No MIDI output port selected")));
+                }
+                if let Some(mut p) = self.player.take() {
+                    p.stop();
+                }
+
+                let smf = Smf::read(std::path::Path::new(file.as_str()))?;
+                let (messages, length) = smf.timeline();
+                let channel = if keep { None } else { self.channel_argument().map(|c| c as u8) };
+
+                println!("Playing {} : {} events, {:.1} s", file, messages.len(), length.as_secs_f64());
+                self.player = Some(Player::start(self.interface.output_handle(), messages, length, channel, looping));
+                Ok(())
+            }
+
+            InterpreterCommand::Stop => {
+                match self.player.take() {
+                    Some(mut p) if p.is_playing() => {
+                        p.stop();
+                        println!("Playback stopped");
+                    }
+                    _ => println!("Not playing"),
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::Clock(command) => {
+                if self.clock.is_none() {
+                    if !self.interface.has_output() {
+                        return Err(InterpreterError::SimpleError(String::from("No MIDI output port selected")));
+                    }
+                    let bpm = match command {
+                        ClockCommand::Tempo(bpm) => bpm,
+                        _ => clock::DEFAULT_BPM,
+                    };
+                    self.clock = Some(ClockMaster::start(self.interface.output_handle(), bpm));
+                    println!("Clock started at {} BPM", bpm);
+                }
+
+                if let Some(c) = self.clock.as_mut() {
+                    c.send(command);
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::ClockOff => {
+                match self.clock.take() {
+                    Some(mut c) => {
+                        c.stop();
+                        println!("Clock stopped");
+                    }
+                    None => println!("No clock running"),
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::ClockFollow(follow) => {
+                let mut follower = self.follower.lock().unwrap();
+                match (follow, follower.is_some()) {
+                    (true, false) => {
+                        self.clock_filtered = self.interface.is_realtime_filtered(midi_parser::CLOCK);
+                        self.interface.filter_realtime(midi_parser::CLOCK, false);
+                        *follower = Some(ClockFollower::new());
+                    }
+                    (false, true) => {
+                        self.interface.filter_realtime(midi_parser::CLOCK, self.clock_filtered);
+                        *follower = None;
+                    }
+                    _ => {}
+                }
+                println!("{}", if follow { "Following the clock received" } else { "Not following the clock" });
+                Ok(())
+            }
+
+            InterpreterCommand::ClockStatus => {
+                let transport = |playing: bool| if playing { "playing" } else { "stopped" };
+
+                if let Some(f) = self.follower.lock().unwrap().as_ref() {
+                    match f.bpm() {
+                        Some(bpm) => print!("Following {:.1} BPM", bpm),
+                        None => print!("Following, no clock received"),
+                    }
+                    println!(", {} at {}", transport(f.playing), clock::format_position(f.position()));
+                }
+                if let Some(c) = &self.clock {
+                    println!("Clock {} BPM, {} at {}", c.bpm, transport(c.is_playing()), clock::format_position(c.position()));
+                }
+                if self.clock.is_none() && self.follower.lock().unwrap().is_none() {
+                    println!("No clock");
+                }
+                Ok(())
+            }
+
+            InterpreterCommand::Detect(timeout) => {
+                let previous = self.interface.ports();
+                let timeout = std::time::Duration::from_millis(timeout);
+                let outputs = MidiInterface::output_port_count()?;
+                let mut detected: Option<String> = None;
+
+                // Ports that can't be opened, e.g. used by another program, are skipped
+                'ports: for i in 0..MidiInterface::input_port_count()? {
+                    if let Err(e) = self.interface.set_input_port(i, self.tracker()) {
+                        println!("Input {} : {}", i, e);
+                        continue;
+                    }
+
+                    for o in 0..outputs {
+                        if let Err(e) = self.interface.set_output_port(o) {
+                            println!("Output {} : {}", o, e);
+                            continue;
+                        }
+
+                        for reply in identity::request(&mut self.interface, timeout).unwrap_or_default() {
+                            match identity::find_synth(self.config.synths(), &reply) {
+                                Some(synth) => {
+                                    println!("Input {}, output {} : {} -> {}", i, o, reply, synth.id);
+                                    detected = Some(synth.id.clone());
+                                    break 'ports;
+                                }
+                                None => println!("Input {}, output {} : {}", i, o, reply),
+                            }
+                        }
+                    }
+                }
+
+                match detected {
+                    Some(id) => self.run_command(InterpreterCommand::Synth(id)),
+                    None => {
+                        self.restore_ports(previous)?;
+                        Err(InterpreterError::SimpleError(String::from("No known synth detected")))
+                    }
+                }
+            }
+
+            InterpreterCommand::Echo(echo) => {
+                self.echo.store(echo, Ordering::Relaxed);
+                Ok(())
+            }
+
             InterpreterCommand::SysexList => {
                 if !self.sysex.is_empty() {
                     for (alias, command) in &self.sysex {
@@ -0,0 +1,137 @@
+use super::utils::btos;
+
+/// MIDI manufacturer IDs, one byte or three starting with 00
+const MANUFACTURERS: &[(&[u8], &str)] = &[
+    (&[0x01], "Sequential Circuits"),
+    (&[0x02], "Big Briar"),
+    (&[0x03], "Octave/Plateau"),
+    (&[0x04], "Moog"),
+    (&[0x05], "Passport Designs"),
+    (&[0x06], "Lexicon"),
+    (&[0x07], "Kurzweil"),
+    (&[0x08], "Fender"),
+    (&[0x09], "Gulbransen"),
+    (&[0x0A], "AKG Acoustics"),
+    (&[0x0B], "Voyce Music"),
+    (&[0x0C], "Waveframe"),
+    (&[0x0D], "ADA"),
+    (&[0x0E], "Garfield Electronics"),
+    (&[0x0F], "Ensoniq"),
+    (&[0x10], "Oberheim"),
+    (&[0x11], "Apple"),
+    (&[0x12], "Grey Matter Response"),
+    (&[0x13], "Digidesign"),
+    (&[0x14], "Palmtree Instruments"),
+    (&[0x15], "JLCooper"),
+    (&[0x16], "Lowrey"),
+    (&[0x17], "Adams-Smith"),
+    (&[0x18], "E-mu"),
+    (&[0x19], "Harmony Systems"),
+    (&[0x1A], "ART"),
+    (&[0x1B], "Baldwin"),
+    (&[0x1C], "Eventide"),
+    (&[0x1D], "Inventronics"),
+    (&[0x1F], "Clarity"),
+    (&[0x20], "Passac"),
+    (&[0x21], "SIEL"),
+    (&[0x22], "Synthaxe"),
+    (&[0x24], "Hohner"),
+    (&[0x25], "Twister"),
+    (&[0x26], "Solton"),
+    (&[0x27], "Jellinghaus"),
+    (&[0x28], "Southworth"),
+    (&[0x29], "PPG"),
+    (&[0x2A], "JEN"),
+    (&[0x2B], "Solid State Logic"),
+    (&[0x2C], "Audio Veritrieb"),
+    (&[0x2F], "Elka"),
+    (&[0x30], "Dynacord"),
+    (&[0x31], "Viscount"),
+    (&[0x33], "Clavia"),
+    (&[0x34], "Audio Architecture"),
+    (&[0x35], "GeneralMusic"),
+    (&[0x39], "Soundcraft"),
+    (&[0x3B], "Wersi"),
+    (&[0x3C], "Avab"),
+    (&[0x3D], "Digigram"),
+    (&[0x3E], "Waldorf"),
+    (&[0x3F], "Quasimidi"),
+    (&[0x40], "Kawai"),
+    (&[0x41], "Roland"),
+    (&[0x42], "Korg"),
+    (&[0x43], "Yamaha"),
+    (&[0x44], "Casio"),
+    (&[0x46], "Kamiya"),
+    (&[0x47], "Akai"),
+    (&[0x48], "Victor"),
+    (&[0x4B], "Fujitsu"),
+    (&[0x4C], "Sony"),
+    (&[0x4E], "Teac"),
+    (&[0x50], "Matsushita"),
+    (&[0x51], "Fostex"),
+    (&[0x52], "Zoom"),
+    (&[0x55], "Suzuki"),
+    (&[0x56], "Fuji Sound"),
+    (&[0x7D], "Non-Commercial"),
+    (&[0x7E], "Universal Non-Realtime"),
+    (&[0x7F], "Universal Realtime"),
+    (&[0x00, 0x00, 0x0E], "Alesis"),
+    (&[0x00, 0x00, 0x1B], "Peavey"),
+    (&[0x00, 0x00, 0x41], "Microsoft"),
+    (&[0x00, 0x20, 0x29], "Focusrite/Novation"),
+    (&[0x00, 0x20, 0x32], "Behringer"),
+    (&[0x00, 0x20, 0x33], "Access Music"),
+    (&[0x00, 0x20, 0x3C], "Elektron"),
+    (&[0x00, 0x20, 0x6B], "Arturia"),
+    (&[0x00, 0x21, 0x09], "Native Instruments"),
+];
+
+/// Name of a manufacturer from its ID
+pub fn name(id: &[u8]) -> Option<&'static str> {
+    MANUFACTURERS.iter().find(|(i, _)| *i == id).map(|(_, n)| *n)
+}
+
+fn normalize(name: &str) -> String {
+    name.chars().filter(|c| c.is_ascii_alphanumeric()).collect::<String>().to_lowercase()
+}
+
+/// ID of a manufacturer from its name, ignoring case and punctuation.
+/// Either part of a name such as "Focusrite/Novation" can be used.
+pub fn id(name: &str) -> Option<&'static [u8]> {
+    let name = normalize(name);
+
+    MANUFACTURERS
+        .iter()
+        .find(|(_, n)| normalize(n) == name || n.split('/').any(|p| normalize(p) == name))
+        .map(|(i, _)| *i)
+}
+
+/// Manufacturer as shown to the user, e.g. "Korg (42)"
+pub fn describe(id: &[u8]) -> String {
+    format!("{} ({})", name(id).unwrap_or("Unknown"), btos(id))
+}
+
+/// Replaces the manufacturer references of a midi template by their ID :
+/// "{Korg}" by 42, and "{manufacturer}" by the ID of the synth's manufacturer
+pub fn expand(template: &str, manufacturer: &str) -> Result<String, String> {
+    let mut result = String::new();
+    let mut rest = template;
+
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|e| start + e)
+            .ok_or(format!("Unclosed manufacturer reference in {}", template))?;
+
+        let reference = &rest[start + 1..end];
+        let reference = if reference == "manufacturer" { manufacturer } else { reference };
+        let bytes = id(reference).ok_or(format!("Unknown manufacturer {}", reference))?;
+
+        result.push_str(&rest[..start]);
+        result.push_str(&btos(bytes));
+        rest = &rest[end + 1..];
+    }
+
+    result.push_str(rest);
+    Ok(result)
+}
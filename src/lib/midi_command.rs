@@ -1,28 +1,210 @@
 use super::utils::*;
 
+/// Value given to, or decoded from, a command parameter
+#[derive(Debug, Clone, PartialEq)]
+pub enum ParameterValue {
+    Integer(usize),
+    Text(String),
+}
+
+impl ParameterValue {
+    /// Parses an interpreter argument, quoted arguments being text
+    pub fn parse(s: &str) -> Option<ParameterValue> {
+        if s.len() >= 2 && s.starts_with('"') && s.ends_with('"') {
+            Some(ParameterValue::Text(s[1..s.len() - 1].to_owned()))
+        } else {
+            s.parse::<usize>().ok().map(ParameterValue::Integer)
+        }
+    }
+
+    pub fn as_integer(&self) -> Option<usize> {
+        match self {
+            ParameterValue::Integer(v) => Some(*v),
+            ParameterValue::Text(_) => None,
+        }
+    }
+}
+
+impl std::fmt::Display for ParameterValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParameterValue::Integer(v) => write!(f, "{}", v),
+            ParameterValue::Text(t) => write!(f, "\"{}\"", t),
+        }
+    }
+}
+
+/// Command argument, either given in order or by parameter key/name
+/// (e.g. `name="Fat Brass"`)
+#[derive(Debug, Clone, PartialEq)]
+pub enum Argument {
+    Positional(ParameterValue),
+    Named(String, ParameterValue),
+}
+
+impl Argument {
+    pub fn parse(s: &str) -> Option<Argument> {
+        if !s.starts_with('"') {
+            if let Some(i) = s.find('=') {
+                let value = ParameterValue::parse(&s[i + 1..])?;
+                return Some(Argument::Named(s[..i].to_owned(), value));
+            }
+        }
+
+        ParameterValue::parse(s).map(Argument::Positional)
+    }
+}
+
+/// Encoding of a fixed length text parameter, such as a program name
+#[derive(Debug, Clone)]
+pub struct TextFormat {
+    pub length: usize,
+    pub padding: char,
+    /// Character table indexed by the transmitted code, ASCII when empty
+    pub charset: Vec<char>,
+}
+
+impl TextFormat {
+    pub fn new(length: usize) -> TextFormat {
+        TextFormat {
+            length,
+            padding: ' ',
+            charset: Vec::new(),
+        }
+    }
+
+    fn encode_char(&self, c: char) -> Option<u8> {
+        if self.charset.is_empty() {
+            if c.is_ascii() && !c.is_ascii_control() {
+                Some(c as u8)
+            } else {
+                None
+            }
+        } else {
+            self.charset.iter().position(|&x| x == c).map(|i| i as u8)
+        }
+    }
+
+    /// Encodes the text, truncated or padded to the format length
+    pub fn encode(&self, text: &str) -> Vec<u8> {
+        let pad = self.encode_char(self.padding).unwrap_or(0x20);
+
+        let mut bytes: Vec<u8> = text
+            .chars()
+            .take(self.length)
+            .map(|c| self.encode_char(c).unwrap_or(pad))
+            .collect();
+        bytes.resize(self.length, pad);
+
+        bytes
+    }
+
+    /// Decodes the text, removing the trailing padding
+    pub fn decode(&self, data: &[u8]) -> String {
+        let text: String = data
+            .iter()
+            .map(|&b| {
+                if self.charset.is_empty() {
+                    b as char
+                } else {
+                    *self.charset.get(b as usize).unwrap_or(&'?')
+                }
+            })
+            .collect();
+
+        text.trim_end_matches(self.padding).to_owned()
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct MidiParameter {
     key: String,
     size: usize,
+    /// Bit position and width when the parameter shares a byte with others
+    bits: Option<(u8, u8)>,
+    text: Option<TextFormat>,
     pub name: String,
 }
 
 impl MidiParameter {
     pub fn new(key: String, size: usize, name: String) -> MidiParameter {
-        MidiParameter { key, size, name }
+        MidiParameter { key, size, bits: None, text: None, name }
     }
 
     pub fn new_str(key: &str, size: usize, name: &str) -> MidiParameter {
         MidiParameter {
             key: key.to_owned(),
             size: size,
+            bits: None,
+            text: None,
             name: name.to_owned(),
         }
     }
 
+    pub fn new_bits(key: &str, position: u8, width: u8, name: &str) -> MidiParameter {
+        MidiParameter {
+            key: key.to_owned(),
+            size: 2,
+            bits: Some((position, width)),
+            text: None,
+            name: name.to_owned(),
+        }
+    }
+
+    pub fn new_text(key: &str, format: TextFormat, name: &str) -> MidiParameter {
+        MidiParameter {
+            key: key.to_owned(),
+            size: format.length * 2,
+            bits: None,
+            text: Some(format),
+            name: name.to_owned(),
+        }
+    }
+
+    /// Parses a parameter given either as a string, or as an object for text
+    /// parameters needing a padding character or a character table :
+    /// { "key" : "m", "size" : "s10", "name" : "Name", "padding" : " ", "charset" : "..." }
+    pub fn from_json(val: &json::JsonValue) -> MidiParameter {
+        if let Some(s) = val.as_str() {
+            return MidiParameter::new_parse(s);
+        }
+
+        let key = val["key"].as_str().expect("Expected parameter key");
+        let name = val["name"].as_str().unwrap_or(key);
+        let size = val["size"].as_str().map(|s| s.to_owned()).unwrap_or(val["size"].dump());
+        let mut p = MidiParameter::new_parse(format!("{} : {} : {}", key, size, name).as_str());
+
+        if let Some(format) = p.text.as_mut() {
+            if let Some(padding) = val["padding"].as_str().and_then(|s| s.chars().next()) {
+                format.padding = padding;
+            }
+
+            if let Some(charset) = val["charset"].as_str() {
+                format.charset = charset.chars().collect();
+            }
+        }
+
+        p
+    }
+
+    /// Parses "key : size : Name", "key : bX-Y : Name" for a bit field
+    /// spanning bits X to Y of a byte (e.g. "w : b0-2 : Waveform", "s : b3 : Sync"),
+    /// or "key : sN : Name" for an ASCII text of N characters
     pub fn new_parse(data: &str) -> MidiParameter {
         let tokens: Vec<&str> = data.split(':').collect();
         let trimmed = tokens.iter().map(|&x| x.trim()).collect::<Vec<_>>();
+
+        if let Some(length) = trimmed[1].strip_prefix('s') {
+            let length = length.parse::<usize>().expect("Failed to parse text length");
+            return MidiParameter::new_text(trimmed[0], TextFormat::new(length), trimmed[2]);
+        }
+
+        if let Some(range) = trimmed[1].strip_prefix('b') {
+            let (position, width) =
+                MidiParameter::parse_bits(range).expect("Failed to parse parameter bits");
+            return MidiParameter::new_bits(trimmed[0], position, width, trimmed[2]);
+        }
+
         MidiParameter::new_str(
             trimmed[0],
             trimmed[1]
@@ -32,6 +214,65 @@ impl MidiParameter {
         )
     }
 
+    fn parse_bits(range: &str) -> Option<(u8, u8)> {
+        let mut bounds = range.split('-');
+        let low = bounds.next()?.trim().parse::<u8>().ok()?;
+        let high = match bounds.next() {
+            Some(h) => h.trim().parse::<u8>().ok()?,
+            None => low,
+        };
+
+        if high < low || high > 7 {
+            return None;
+        }
+
+        Some((low, high - low + 1))
+    }
+
+    pub fn key(&self) -> &str {
+        self.key.as_str()
+    }
+
+    pub fn is_bit_field(&self) -> bool {
+        self.bits.is_some()
+    }
+
+    pub fn text_format(&self) -> Option<&TextFormat> {
+        self.text.as_ref()
+    }
+
+    /// Number of message bytes taken by the parameter's template token
+    pub fn width(&self) -> usize {
+        match &self.text {
+            Some(format) => format.length,
+            None => 1,
+        }
+    }
+
+    /// Mask of the bits occupied by the parameter in its byte
+    pub fn bit_mask(&self) -> u8 {
+        match self.bits {
+            Some((position, width)) => (((1u16 << width) - 1) << position) as u8,
+            None => 0xFF,
+        }
+    }
+
+    /// Places a value in the parameter's bits, truncating it to its width
+    pub fn pack_bits(&self, value: usize) -> u8 {
+        match self.bits {
+            Some((position, _)) => ((value << position) as u8) & self.bit_mask(),
+            None => value as u8,
+        }
+    }
+
+    /// Reads the parameter's value back from a packed byte
+    pub fn unpack_bits(&self, byte: u8) -> usize {
+        match self.bits {
+            Some((position, _)) => ((byte & self.bit_mask()) >> position) as usize,
+            None => byte as usize,
+        }
+    }
+
     pub fn characters(&self) -> usize {
         self.size
     }
@@ -75,18 +316,115 @@ impl MidiCommand {
         c.add_aliases(val["alias"].as_str().unwrap().to_owned());
 
         for param_val in val["parameters"].members() {
-            c.add_parameter(MidiParameter::new_parse(
-                param_val.as_str().unwrap()
-            ));
+            c.add_parameter(MidiParameter::from_json(param_val));
         }
 
-        c.mask = MidiCommand::maskify(&c.midi);
-        c.masked_val = MidiCommand::destringify(&c.midi);
+        c.update_mask();
 
         return c;
     }
 
+    /// Recomputes the matching mask, taking bit field parameters into account
+    pub fn update_mask(&mut self) {
+        let mut mask: Vec<u8> = Vec::new();
+        let mut masked_val: Vec<u8> = Vec::new();
+
+        for token in self.midi.split(" ") {
+            if let Some(fields) = self.bit_fields(token) {
+                // Bits that no field covers must be cleared
+                let used = fields.iter().fold(0x00, |acc, p| acc | p.bit_mask());
+                mask.push(!used);
+                masked_val.push(0x00);
+            } else if let Some(format) = self.text_field(token) {
+                for _ in 0..format.length {
+                    mask.push(0x00);
+                    masked_val.push(0x00);
+                }
+            } else {
+                mask.push(MidiCommand::maskify_byte(token));
+                masked_val.push(MidiCommand::destringify_byte(token));
+            }
+        }
+
+        self.mask = mask;
+        self.masked_val = masked_val;
+    }
+
+    /// Returns the bit field parameters packed in a template byte, if the
+    /// byte is only made of bit field keys (e.g. "ws")
+    fn bit_fields(&self, token: &str) -> Option<Vec<&MidiParameter>> {
+        if token.is_empty() {
+            return None;
+        }
+
+        let mut fields: Vec<&MidiParameter> = Vec::new();
+        for c in token.chars() {
+            match self.parameters.get(&c.to_string()) {
+                Some(p) if p.is_bit_field() => fields.push(p),
+                _ => return None,
+            }
+        }
+
+        Some(fields)
+    }
+
+    /// Returns the text format of a template token made of a text parameter
+    fn text_field(&self, token: &str) -> Option<&TextFormat> {
+        self.parameters.get(token).and_then(|p| p.text_format())
+    }
+
     pub fn generate(&self, values: &[usize]) -> String {
+        let v: Vec<ParameterValue> = values.iter().map(|&x| ParameterValue::Integer(x)).collect();
+        self.generate_values(&v)
+    }
+
+    /// Generates the command from positional and named arguments, named
+    /// arguments referring to a parameter by key or by name
+    pub fn generate_arguments(&self, arguments: &[Argument]) -> Result<String, String> {
+        let mut values: Vec<Option<ParameterValue>> = vec![None; self.parameter_names.len()];
+
+        for a in arguments {
+            if let Argument::Named(name, value) = a {
+                let index = self
+                    .parameter_names
+                    .iter()
+                    .position(|k| k == name || self.parameters[k].name.eq_ignore_ascii_case(name))
+                    .ok_or(format!("Command {} has no parameter {}", self.name, name))?;
+                values[index] = Some(value.clone());
+            }
+        }
+
+        let mut positional = arguments.iter().filter_map(|a| match a {
+            Argument::Positional(v) => Some(v.clone()),
+            Argument::Named(_, _) => None,
+        });
+
+        for v in values.iter_mut().filter(|v| v.is_none()) {
+            *v = positional.next();
+        }
+
+        if positional.next().is_some() {
+            return Err(format!("Too many arguments for command {}", self.name));
+        }
+
+        let values: Vec<ParameterValue> = values.into_iter().collect::<Option<_>>().ok_or(format!(
+            "Command {} expected {} parameters",
+            self.name,
+            self.parameter_names.len()
+        ))?;
+
+        // Only text parameters take text
+        for (k, v) in self.parameter_names.iter().zip(&values) {
+            let p = &self.parameters[k];
+            if p.text.is_none() && v.as_integer().is_none() {
+                return Err(format!("Parameter {} of command {} expects a number, got {}", p.name, self.name, v));
+            }
+        }
+
+        Ok(self.generate_values(&values))
+    }
+
+    pub fn generate_values(&self, values: &[ParameterValue]) -> String {
         if values.len() != self.parameters.len() {
             println!(
                 "Command {} expected {} parameters, got {}",
@@ -97,16 +435,60 @@ impl MidiCommand {
             return String::from("");
         } else {
             let mut val = std::collections::HashMap::<String, String>::new();
+            let mut packed = std::collections::HashMap::<String, ParameterValue>::new();
 
             for i in 0..values.len() {
-                let s = self.parameters[&self.parameter_names[i]].midi(values[i]);
-                val.insert(self.parameter_names[i].clone(), s);
+                let p = &self.parameters[&self.parameter_names[i]];
+                if p.is_bit_field() || p.text.is_some() {
+                    packed.insert(self.parameter_names[i].clone(), values[i].clone());
+                } else {
+                    let v = values[i].as_integer().unwrap_or(0);
+                    val.insert(self.parameter_names[i].clone(), p.midi(v));
+                }
             }
 
-            return self.generate_map(val);
+            return self.pack_tokens(&packed).generate_map(val);
         }
     }
 
+    /// Returns a copy of the command whose bit field and text tokens are
+    /// replaced by their encoded bytes
+    fn pack_tokens(&self, values: &std::collections::HashMap<String, ParameterValue>) -> MidiCommand {
+        let mut c = self.clone();
+
+        if values.is_empty() {
+            return c;
+        }
+
+        let tokens: Vec<String> = self
+            .midi
+            .split(" ")
+            .map(|token| {
+                if let Some(fields) = self.bit_fields(token) {
+                    let byte = fields.iter().fold(0x00, |acc, p| {
+                        let v = values.get(p.key()).and_then(|v| v.as_integer());
+                        acc | p.pack_bits(v.unwrap_or(0))
+                    });
+                    format!("{:02X}", byte)
+                } else if let Some(format) = self.text_field(token) {
+                    let text = match values.get(token) {
+                        Some(ParameterValue::Text(t)) => t.clone(),
+                        Some(ParameterValue::Integer(v)) => v.to_string(),
+                        None => String::new(),
+                    };
+                    let bytes: Vec<String> =
+                        format.encode(&text).iter().map(|b| format!("{:02X}", b)).collect();
+                    bytes.join(" ")
+                } else {
+                    token.to_owned()
+                }
+            })
+            .collect();
+
+        c.midi = tokens.join(" ");
+        c
+    }
+
     pub fn generate_map(&self, values: std::collections::HashMap<String, String>) -> String {
         let mut midi: String = self.midi.clone();
 
@@ -156,6 +538,48 @@ impl MidiCommand {
         return result;
     }
 
+    /// Decodes the value of every parameter from a message matching the
+    /// command, in declaration order
+    pub fn decode(&self, data: &[u8]) -> Option<Vec<(String, ParameterValue)>> {
+        let mut values = std::collections::HashMap::<String, ParameterValue>::new();
+        let mut i: usize = 0;
+
+        for token in self.midi.split(" ") {
+            if let Some(format) = self.text_field(token) {
+                let text = data.get(i..i + format.length)?;
+                values.insert(token.to_owned(), ParameterValue::Text(format.decode(text)));
+                i += format.length;
+                continue;
+            }
+
+            let byte = *data.get(i)?;
+            i += 1;
+
+            if let Some(fields) = self.bit_fields(token) {
+                for p in fields {
+                    values.insert(p.key.clone(), ParameterValue::Integer(p.unpack_bits(byte)));
+                }
+            } else if let Some(p) = self.parameters.get(token) {
+                values.insert(p.key.clone(), ParameterValue::Integer(byte as usize));
+            } else if token.len() == 2 {
+                // Nibble parameters such as the channel in "3n"
+                for (j, c) in token.chars().enumerate() {
+                    if let Some(p) = self.parameters.get(&c.to_string()) {
+                        let nibble = if j == 0 { byte >> 4 } else { byte & 0x0F };
+                        values.insert(p.key.clone(), ParameterValue::Integer(nibble as usize));
+                    }
+                }
+            }
+        }
+
+        Some(
+            self.parameter_names
+                .iter()
+                .filter_map(|k| values.get(k).map(|v| (k.clone(), v.clone())))
+                .collect(),
+        )
+    }
+
     pub fn destringify_byte(s: &str) -> u8 {
         if s.len() == 1 {
             let c = s.chars().next().unwrap();
@@ -249,4 +673,17 @@ impl MidiCommand {
 
         return true;
     }
-}
\ No newline at end of file
+
+    /// Whether the message starts with the command, as for dump headers
+    pub fn matches_prefix(&self, data: &[u8]) -> bool {
+        if data.len() < self.masked_val.len() {
+            return false;
+        }
+
+        self.mask
+            .iter()
+            .zip(self.masked_val.iter())
+            .zip(data.iter())
+            .all(|((m, v), d)| d & m == *v)
+    }
+}
@@ -1,6 +1,19 @@
+use super::midi_parser::MIDIParser;
 use derive_more::*;
 use midir::{InitError, PortInfoError};
 use std::fmt;
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Number of incoming messages kept for `receive`, new ones being dropped
+/// while the queue is full
+const RECEIVE_QUEUE_SIZE: usize = 1024;
+
+/// Silence after which a multi-message reply is considered complete
+const REPLY_GAP: Duration = Duration::from_millis(200);
+
+/// Called with every message sent
+type OutputObserver = Box<dyn FnMut(&[u8]) + Send>;
 
 #[derive(Debug, From)]
 pub enum MidiInterfaceError {
@@ -8,7 +21,10 @@ pub enum MidiInterfaceError {
     ConnectErrorMidiOutput(midir::ConnectError<midir::MidiOutput>),
     PortDoesNotExist(String),
     PortInfoError(PortInfoError),
-    PortInitError(InitError)
+    PortInitError(InitError),
+    SendError(midir::SendError),
+    #[from(ignore)]
+    Timeout(String)
 }
 
 impl std::fmt::Display for MidiInterfaceError {
@@ -34,56 +50,157 @@ impl std::fmt::Display for MidiInterfaceError {
             MidiInterfaceError::PortInitError(e) => {
                 write!(f, "{}", e)
             }
+
+            MidiInterfaceError::SendError(e) => {
+                write!(f, "{}", e)
+            }
+
+            MidiInterfaceError::Timeout(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+/// Anything MIDI messages can be sent to and received from
+pub trait MidiTransport {
+    fn send(&mut self, data: &[u8]) -> Result<(), MidiInterfaceError>;
+
+    /// Waits for the next incoming message
+    fn receive(&mut self, timeout: Duration) -> Option<Vec<u8>>;
+
+    /// Drops the messages received so far
+    fn flush(&mut self) {
+        while self.receive(Duration::from_millis(0)).is_some() {}
+    }
+
+    /// Sends a request and collects the SysEx messages sent back, until no
+    /// message comes for a while
+    fn request(&mut self, data: &[u8], timeout: Duration) -> Result<Vec<Vec<u8>>, MidiInterfaceError> {
+        self.flush();
+        self.send(data)?;
+
+        let mut replies: Vec<Vec<u8>> = Vec::new();
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let wait = if replies.is_empty() {
+                deadline.saturating_duration_since(Instant::now())
+            } else {
+                REPLY_GAP
+            };
+
+            match self.receive(wait) {
+                Some(m) => {
+                    if m.first() == Some(&0xF0) {
+                        replies.push(m);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        if replies.is_empty() {
+            Err(MidiInterfaceError::Timeout(String::from("No reply received")))
+        } else {
+            Ok(replies)
         }
     }
 }
 
+/// Output connection, and what watches the messages sent through it
+#[derive(Default)]
+struct Output {
+    conn: Option<midir::MidiOutputConnection>,
+    observer: Option<OutputObserver>,
+}
+
+impl Output {
+    fn observe(&mut self, data: &[u8]) {
+        if let Some(o) = self.observer.as_mut() {
+            o(data);
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), MidiInterfaceError> {
+        self.observe(data);
+        match self.conn.as_mut() {
+            Some(c) => Ok(c.send(data)?),
+            None => Err(MidiInterfaceError::PortDoesNotExist(String::from("No MIDI output port selected"))),
+        }
+    }
+}
+
+/// Output port shared with the threads sending on their own. Nothing is
+/// ever received through it.
+#[derive(Clone)]
+pub struct OutputHandle(Arc<Mutex<Output>>);
+
+impl MidiTransport for OutputHandle {
+    fn send(&mut self, data: &[u8]) -> Result<(), MidiInterfaceError> {
+        self.0.lock().unwrap().send(data)
+    }
+
+    fn receive(&mut self, _timeout: Duration) -> Option<Vec<u8>> {
+        None
+    }
+}
+
 #[derive(Default)]
 pub struct MidiInterface {
     in_conn: Option<midir::MidiInputConnection<()>>,
-    out_conn: Option<midir::MidiOutputConnection>,
-    in_port: usize
+    output: Arc<Mutex<Output>>,
+    in_port: usize,
+    out_port: usize,
+    receiver: Option<mpsc::Receiver<Vec<u8>>>,
+    parser: Arc<Mutex<MIDIParser>>,
 }
 
 impl MidiInterface {
     pub fn new() -> MidiInterface {
         MidiInterface {
             in_conn: None,
-            out_conn: None,
+            output: Arc::new(Mutex::new(Output::default())),
             in_port: 0,
+            out_port: 0,
+            receiver: None,
+            parser: Arc::new(Mutex::new(MIDIParser::new())),
         }
     }
 
-    pub fn update_callback<F>(&mut self, callback: F) -> Result<(), MidiInterfaceError> where F: FnMut(u64, &[u8], &mut ()) + Send + 'static {
-        let in_m = midir::MidiInput::new("midi-prog")?;
-        let in_ports = in_m.ports();
-        if let Some(p) = in_ports.get(self.in_port) {
-            self.in_conn = Some(
-                in_m.connect(
-                    p,
-                    "midi-in",
-                    callback,
-                    (),
-                )?
-            );
-            Ok(())
-        } else {
-            Err(MidiInterfaceError::PortDoesNotExist(format!("MIDI input port {} doesn't exist", self.in_port)))
-        }
-    }
-
-    pub fn set_input_port<F>(&mut self, midi_in: usize, callback: F) -> Result<(), MidiInterfaceError> where F: FnMut(u64, &[u8], &mut ()) + Send + 'static {
+    /// Connects the input port, queuing every message for `receive` before
+    /// handing it to the callback. The input is split into complete messages
+    /// first.
+    fn connect_input<F>(&mut self, midi_in: usize, mut callback: F) -> Result<(), MidiInterfaceError> where F: FnMut(u64, &[u8], &mut ()) + Send + 'static {
         let in_m = midir::MidiInput::new("midi-prog")?;
         let in_ports = in_m.ports();
         if let Some(p) = in_ports.get(midi_in) {
+            let (sender, receiver) = mpsc::sync_channel::<Vec<u8>>(RECEIVE_QUEUE_SIZE);
+
+            let parser = self.parser.clone();
+
             self.in_conn = Some(
                 in_m.connect(
                     p,
                     "midi-in",
-                    callback,
+                    move |stamp, chunk, data| {
+                        let messages = parser.lock().unwrap().push(chunk);
+                        for message in messages {
+                            match message {
+                                Ok(message) => {
+                                    // Handled before being queued, so that
+                                    // the state is up to date for requests
+                                    callback(stamp, &message, data);
+                                    let _ = sender.try_send(message);
+                                }
+                                Err(e) => println!("MIDI input error : {}", e),
+                            }
+                        }
+                    },
                     (),
                 )?
             );
+            self.receiver = Some(receiver);
             self.in_port = midi_in;
             Ok(())
         } else {
@@ -91,14 +208,23 @@ impl MidiInterface {
         }
     }
 
+    pub fn update_callback<F>(&mut self, callback: F) -> Result<(), MidiInterfaceError> where F: FnMut(u64, &[u8], &mut ()) + Send + 'static {
+        self.connect_input(self.in_port, callback)
+    }
+
+    pub fn set_input_port<F>(&mut self, midi_in: usize, callback: F) -> Result<(), MidiInterfaceError> where F: FnMut(u64, &[u8], &mut ()) + Send + 'static {
+        self.connect_input(midi_in, callback)
+    }
+
     pub fn set_output_port(&mut self, midi_out: usize) -> Result<(), MidiInterfaceError> {
         let out_m = midir::MidiOutput::new("midi-prog")?;
         let out_ports = out_m.ports();
         if let Some(p) = out_ports.get(midi_out) {
-            self.out_conn = Some(
+            self.output.lock().unwrap().conn = Some(
                 out_m
                     .connect(p, "midi-out")?
             );
+            self.out_port = midi_out;
             Ok(())
         } else {
             Err(MidiInterfaceError::PortDoesNotExist(format!("MIDI output port {} doesn't exist", midi_out)))
@@ -136,7 +262,69 @@ impl MidiInterface {
         Ok(s)
     }
 
+    pub fn input_port_count() -> Result<usize, MidiInterfaceError> {
+        Ok(midir::MidiInput::new("midi-prog")?.port_count())
+    }
+
+    pub fn output_port_count() -> Result<usize, MidiInterfaceError> {
+        Ok(midir::MidiOutput::new("midi-prog")?.port_count())
+    }
+
+    /// Input and output ports connected
+    pub fn ports(&self) -> (Option<usize>, Option<usize>) {
+        (
+            self.in_conn.as_ref().map(|_| self.in_port),
+            self.has_output().then_some(self.out_port),
+        )
+    }
+
+    /// Drops or keeps a realtime message (F8 to FF) received
+    pub fn filter_realtime(&mut self, status: u8, filtered: bool) {
+        self.parser.lock().unwrap().filter(status, filtered);
+    }
+
+    pub fn is_realtime_filtered(&self, status: u8) -> bool {
+        self.parser.lock().unwrap().is_filtered(status)
+    }
+
+    pub fn close_input(&mut self) {
+        self.in_conn = None;
+        self.receiver = None;
+    }
+
+    pub fn close_output(&mut self) {
+        self.output.lock().unwrap().conn = None;
+    }
+
+    pub fn has_input(&self) -> bool {
+        self.in_conn.is_some()
+    }
+
+    pub fn has_output(&self) -> bool {
+        self.output.lock().unwrap().conn.is_some()
+    }
+
+    pub fn set_output_observer<F>(&mut self, observer: F) where F: FnMut(&[u8]) + Send + 'static {
+        self.output.lock().unwrap().observer = Some(Box::new(observer));
+    }
+
+    pub fn output_handle(&self) -> OutputHandle {
+        OutputHandle(self.output.clone())
+    }
+
     pub fn send_midi(&mut self, data: &[u8]) -> Result<(), midir::SendError> {
-        self.out_conn.as_mut().unwrap().send(data)
+        let mut output = self.output.lock().unwrap();
+        output.observe(data);
+        output.conn.as_mut().unwrap().send(data)
+    }
+}
+
+impl MidiTransport for MidiInterface {
+    fn send(&mut self, data: &[u8]) -> Result<(), MidiInterfaceError> {
+        self.output.lock().unwrap().send(data)
+    }
+
+    fn receive(&mut self, timeout: Duration) -> Option<Vec<u8>> {
+        self.receiver.as_ref()?.recv_timeout(timeout).ok()
     }
 }
@@ -1,15 +1,269 @@
 use super::config::*;
 
+/// Largest SysEx message accepted from the input, in bytes
+pub const MAX_SYSEX_SIZE: usize = 65536;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SysexError {
+    /// A status byte came before the F7 ending the message received so far
+    Truncated(Vec<u8>),
+    /// The message grew past the maximum size before its F7
+    Overlong(usize),
+}
+
+impl std::fmt::Display for SysexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SysexError::Truncated(data) => {
+                write!(f, "SysEx message truncated after {} bytes", data.len())
+            }
+
+            SysexError::Overlong(size) => {
+                write!(f, "SysEx message longer than {} bytes dropped", size)
+            }
+        }
+    }
+}
+
+/// Rebuilds SysEx messages delivered in several chunks by the MIDI backend.
+/// Realtime bytes are passed through on their own, other bytes outside a
+/// SysEx message are left to `MIDIParser` and dropped here.
+pub struct SysexAssembler {
+    buffer: Vec<u8>,
+    max_size: usize,
+    receiving: bool,
+    /// Dropping the rest of an overlong message, until its F7
+    skipping: bool,
+}
+
+impl SysexAssembler {
+    pub fn new(max_size: usize) -> SysexAssembler {
+        SysexAssembler {
+            buffer: Vec::new(),
+            max_size,
+            receiving: false,
+            skipping: false,
+        }
+    }
+
+    /// Whether a message was started and not ended yet
+    pub fn in_message(&self) -> bool {
+        self.receiving || self.skipping
+    }
+
+    /// Drops the message being received, reporting it as truncated
+    pub fn abort(&mut self) -> Option<SysexError> {
+        let truncated = if self.receiving {
+            Some(SysexError::Truncated(std::mem::take(&mut self.buffer)))
+        } else {
+            None
+        };
+
+        self.receiving = false;
+        self.skipping = false;
+        truncated
+    }
+
+    /// Feeds a chunk, returning the complete messages and the errors in the
+    /// order they were found
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Result<Vec<u8>, SysexError>> {
+        let mut out: Vec<Result<Vec<u8>, SysexError>> = Vec::new();
+
+        for &b in chunk {
+            match b {
+                0xF8..=0xFF => out.push(Ok(vec![b])),
+
+                0xF0 => {
+                    if self.receiving {
+                        out.push(Err(SysexError::Truncated(std::mem::take(&mut self.buffer))));
+                    }
+                    self.buffer = vec![0xF0];
+                    self.receiving = true;
+                    self.skipping = false;
+                }
+
+                0xF7 => {
+                    if self.receiving {
+                        self.buffer.push(0xF7);
+                        out.push(Ok(std::mem::take(&mut self.buffer)));
+                    }
+                    self.receiving = false;
+                    self.skipping = false;
+                }
+
+                0x80..=0xF6 => {
+                    if let Some(e) = self.abort() {
+                        out.push(Err(e));
+                    }
+                }
+
+                _ => {
+                    if self.receiving {
+                        self.buffer.push(b);
+                        if self.buffer.len() >= self.max_size {
+                            self.buffer.clear();
+                            self.receiving = false;
+                            self.skipping = true;
+                            out.push(Err(SysexError::Overlong(self.max_size)));
+                        }
+                    }
+                }
+            }
+        }
+
+        out
+    }
+}
+
 pub struct MIDICallbackCommand {
-    identifier: String,
-    data: Vec<usize>
+    pub identifier: String,
+    pub data: Vec<usize>
+}
+
+/// Realtime status bytes, from F8 to FF
+pub const CLOCK: u8 = 0xF8;
+pub const START: u8 = 0xFA;
+pub const CONTINUE: u8 = 0xFB;
+pub const STOP: u8 = 0xFC;
+pub const ACTIVE_SENSING: u8 = 0xFE;
+pub const RESET: u8 = 0xFF;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MessageKind {
+    Channel,
+    SystemCommon,
+    Realtime,
+    Sysex,
 }
 
+impl MessageKind {
+    pub fn of(message: &[u8]) -> Option<MessageKind> {
+        match message.first()? {
+            0x80..=0xEF => Some(MessageKind::Channel),
+            0xF0 => Some(MessageKind::Sysex),
+            0xF1..=0xF7 => Some(MessageKind::SystemCommon),
+            0xF8..=0xFF => Some(MessageKind::Realtime),
+            _ => None,
+        }
+    }
+}
+
+/// Number of data bytes following a status byte
+fn data_length(status: u8) -> usize {
+    match status {
+        0xC0..=0xDF => 1,
+        0x80..=0xEF => 2,
+        0xF1 | 0xF3 => 1,
+        0xF2 => 2,
+        _ => 0,
+    }
+}
+
+/// Splits an input byte stream into complete messages : channel messages
+/// (running status being expanded), system common, realtime and SysEx
 pub struct MIDIParser {
+    running_status: Option<u8>,
+    pending: Vec<u8>,
+    sysex: SysexAssembler,
+    /// Realtime messages dropped, indexed from F8
+    filtered: [bool; 8],
+}
 
+impl Default for MIDIParser {
+    fn default() -> Self {
+        MIDIParser::new()
+    }
 }
 
 impl MIDIParser {
+    /// A parser dropping clock and active sensing messages
+    pub fn new() -> MIDIParser {
+        let mut parser = MIDIParser {
+            running_status: None,
+            pending: Vec::new(),
+            sysex: SysexAssembler::new(MAX_SYSEX_SIZE),
+            filtered: [false; 8],
+        };
+        parser.filter(CLOCK, true);
+        parser.filter(ACTIVE_SENSING, true);
+        parser
+    }
+
+    /// Drops or keeps a realtime message
+    pub fn filter(&mut self, status: u8, filtered: bool) {
+        if status >= 0xF8 {
+            self.filtered[(status - 0xF8) as usize] = filtered;
+        }
+    }
+
+    pub fn is_filtered(&self, status: u8) -> bool {
+        status >= 0xF8 && self.filtered[(status - 0xF8) as usize]
+    }
+
+    /// Feeds bytes from the input, returning the messages completed and the
+    /// errors in the order they were found
+    pub fn push(&mut self, data: &[u8]) -> Vec<Result<Vec<u8>, SysexError>> {
+        let mut out: Vec<Result<Vec<u8>, SysexError>> = Vec::new();
+
+        for &b in data {
+            if b >= 0xF8 {
+                if !self.is_filtered(b) {
+                    out.push(Ok(vec![b]));
+                }
+                continue;
+            }
+
+            if self.sysex.in_message() && (b < 0x80 || b == 0xF7) {
+                out.extend(self.sysex.push(&[b]));
+                continue;
+            }
+
+            if let Some(e) = self.sysex.abort() {
+                out.push(Err(e));
+            }
+
+            match b {
+                0xF0 => {
+                    self.running_status = None;
+                    self.pending.clear();
+                    out.extend(self.sysex.push(&[b]));
+                }
+
+                0x80..=0xEF => {
+                    self.running_status = Some(b);
+                    self.pending = vec![b];
+                }
+
+                0xF1..=0xF7 => {
+                    self.running_status = None;
+                    self.pending = vec![b];
+                }
+
+                _ => {
+                    if self.pending.is_empty() {
+                        match self.running_status {
+                            Some(status) => self.pending.push(status),
+                            None => continue,
+                        }
+                    }
+                    self.pending.push(b);
+                }
+            }
+
+            if let Some(&status) = self.pending.first() {
+                if self.pending.len() > data_length(status) {
+                    let message = std::mem::take(&mut self.pending);
+                    // A stray F7 isn't a message
+                    if status != 0xF7 {
+                        out.push(Ok(message));
+                    }
+                }
+            }
+        }
+
+        out
+    }
+
     pub fn destringify(code: &str) -> Vec<u8> {
         let s = code.replace(" ", "");
         let mut data: Vec<u8> = Vec::new();
@@ -64,8 +318,19 @@ impl MIDIParser {
         return data;
     }
 
-    pub fn parse(data: &[u8], config: &Config) -> MIDICallbackCommand {
+    /// Finds the command a message is an instance of, among the commands
+    /// of every synth loaded
+    pub fn parse(data: &[u8], config: &Config) -> Option<MIDICallbackCommand> {
         let commands = config.get_all_midi_commands();
-        todo!();
+        let command = commands.iter().find(|c| c.matches(data))?;
+
+        Some(MIDICallbackCommand {
+            identifier: command.name.clone(),
+            data: command
+                .decode(data)?
+                .iter()
+                .map(|(_, v)| v.as_integer().unwrap_or(0))
+                .collect(),
+        })
     }
 }
\ No newline at end of file
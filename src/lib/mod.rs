@@ -1,8 +1,26 @@
+pub mod address_map;
+pub mod backup;
+pub mod clock;
 pub mod command_parser;
 pub mod config;
+pub mod handshake;
+pub mod identity;
 pub mod interpreter;
+pub mod manufacturers;
 pub mod midi_command;
 pub mod midi_interface;
 pub mod synth;
+pub mod syx;
 pub mod utils;
+pub mod verify;
+pub mod wav;
 pub mod midi_parser;
+pub mod monitor;
+pub mod morph;
+pub mod patch;
+pub mod player;
+pub mod randomizer;
+pub mod sds;
+pub mod smf;
+pub mod recorder;
+pub mod state;
@@ -0,0 +1,209 @@
+use super::config::Config;
+use super::manufacturers;
+use super::midi_command::MidiCommand;
+use super::midi_parser::MessageKind;
+use super::state::Direction;
+use super::synth::Synth;
+use super::syx::manufacturer_id;
+use super::utils::btos;
+use std::time::Instant;
+
+/// Messages shown by the monitor. Empty lists don't filter anything.
+#[derive(Debug, Clone, Default)]
+pub struct MonitorFilter {
+    pub kinds: Vec<MessageKind>,
+    pub channels: Vec<usize>,
+    /// Synths whose definitions decode the message, "midi" for the standard
+    pub synths: Vec<String>,
+}
+
+impl MonitorFilter {
+    pub fn kind_from_str(s: &str) -> Option<MessageKind> {
+        match s {
+            "channel" => Some(MessageKind::Channel),
+            "common" => Some(MessageKind::SystemCommon),
+            "realtime" => Some(MessageKind::Realtime),
+            "sysex" => Some(MessageKind::Sysex),
+            _ => None,
+        }
+    }
+
+    pub fn accepts(&self, kind: Option<MessageKind>, channel: Option<usize>, synth: Option<&str>) -> bool {
+        (self.kinds.is_empty() || kind.map(|k| self.kinds.contains(&k)).unwrap_or(false))
+            && (self.channels.is_empty() || channel.map(|c| self.channels.contains(&c)).unwrap_or(false))
+            && (self.synths.is_empty() || synth.map(|s| self.synths.iter().any(|f| f == s)).unwrap_or(false))
+    }
+}
+
+/// A message decoded with a synth's definitions
+#[derive(Debug, Clone, PartialEq)]
+pub struct Decoded {
+    pub synth: String,
+    /// Name of the command or dump
+    pub name: String,
+    pub channel: Option<usize>,
+    pub description: String,
+}
+
+/// Prints the messages going through the ports, decoded with the loaded
+/// definitions
+pub struct Monitor {
+    pub enabled: bool,
+    pub filter: MonitorFilter,
+    synths: Vec<Synth>,
+    started: Instant,
+    /// Input timestamp (µs) of a received message and when it was received,
+    /// to give sent messages timestamps on the same clock
+    origin: Option<(u64, Instant)>,
+}
+
+impl Default for Monitor {
+    fn default() -> Self {
+        Monitor::new()
+    }
+}
+
+impl Monitor {
+    pub fn new() -> Monitor {
+        Monitor {
+            enabled: false,
+            filter: MonitorFilter::default(),
+            synths: Vec::new(),
+            started: Instant::now(),
+            origin: None,
+        }
+    }
+
+    /// Takes the definitions of every synth loaded, the MIDI standard included
+    pub fn update_definitions(&mut self, config: &Config) {
+        self.synths = config.synths().into_iter().cloned().collect();
+    }
+
+    fn describe_command(command: &MidiCommand, message: &[u8]) -> String {
+        let values = command.decode(message).unwrap_or_default();
+        let values: Vec<String> = values
+            .iter()
+            .map(|(key, v)| match command.parameters.get(key) {
+                Some(p) => format!("{} {}", p.name, v),
+                None => format!("{} {}", key, v),
+            })
+            .collect();
+
+        if values.is_empty() {
+            command.name.clone()
+        } else {
+            format!("{} ({})", command.name, values.join(", "))
+        }
+    }
+
+    /// Finds the synth command or dump the message is an instance of
+    pub fn decode(&self, message: &[u8]) -> Option<Decoded> {
+        decode(&self.synths, message)
+    }
+
+    /// Line shown for a message, None when it is filtered out
+    pub fn format(&self, stamp: u64, direction: Direction, message: &[u8]) -> Option<String> {
+        let kind = MessageKind::of(message);
+        let decoded = self.decode(message);
+
+        let channel = match kind {
+            Some(MessageKind::Channel) => Some((message[0] & 0x0F) as usize),
+            _ => decoded.as_ref().and_then(|d| d.channel),
+        };
+
+        if !self.filter.accepts(kind, channel, decoded.as_ref().map(|d| d.synth.as_str())) {
+            return None;
+        }
+
+        let direction = match direction {
+            Direction::Sent => "OUT",
+            Direction::Received => "IN ",
+        };
+        let mut description = match decoded {
+            Some(d) => format!("{} {}", d.synth, d.description),
+            None => String::from("?"),
+        };
+        if let Some(id) = manufacturer_id(message) {
+            description = format!("{} {}", manufacturers::describe(id), description);
+        }
+
+        Some(format!(
+            "{:>10.3} {} {} : {}",
+            stamp as f64 / 1000.0,
+            direction,
+            btos(message),
+            description
+        ))
+    }
+
+    /// Prints a message when monitoring. Sent messages have no input
+    /// timestamp, theirs is estimated from the last message received.
+    pub fn log(&mut self, stamp: Option<u64>, direction: Direction, message: &[u8]) {
+        if !self.enabled {
+            return;
+        }
+
+        let now = Instant::now();
+        let stamp = match (stamp, self.origin) {
+            (Some(s), _) => {
+                self.origin = Some((s, now));
+                s
+            }
+            (None, Some((s, at))) => s + now.duration_since(at).as_micros() as u64,
+            (None, None) => now.duration_since(self.started).as_micros() as u64,
+        };
+
+        if let Some(line) = self.format(stamp, direction, message) {
+            println!("{}", line);
+        }
+    }
+}
+
+/// Finds the synth command, dump or DT1 area a message is an instance of
+pub fn decode<'a, I>(synths: I, message: &[u8]) -> Option<Decoded>
+where
+    I: IntoIterator<Item = &'a Synth>,
+{
+    for synth in synths {
+        if let Some(c) = synth.commands.iter().find(|c| c.matches(message)) {
+            let channel = c
+                .decode(message)
+                .and_then(|v| v.into_iter().find(|(k, _)| c.parameters.get(k).map(|p| p.name == "Channel").unwrap_or(false)))
+                .and_then(|(_, v)| v.as_integer());
+
+            return Some(Decoded {
+                synth: synth.id.clone(),
+                name: c.name.clone(),
+                channel,
+                description: Monitor::describe_command(c, message),
+            });
+        }
+
+        if let Some(d) = synth.dumps.iter().find(|d| d.matches(message)) {
+            return Some(Decoded {
+                synth: synth.id.clone(),
+                name: format!("{} dump", d.name),
+                channel: None,
+                description: format!("{} dump ({} bytes)", d.name, message.len()),
+            });
+        }
+
+        if let Some(map) = &synth.address_map {
+            if let Some(Ok((address, data))) = map.parse_dt1(message) {
+                let name = match map.area_at(address) {
+                    Some((area, 0)) => area.name.clone(),
+                    Some((area, offset)) => format!("{} + {}", area.name, offset),
+                    None => map.format_address(address),
+                };
+                return Some(Decoded {
+                    synth: synth.id.clone(),
+                    name: format!("DT1 {}", name),
+                    channel: None,
+                    description: format!("DT1 {} ({} bytes)", name, data.len()),
+                });
+            }
+        }
+    }
+
+    None
+}
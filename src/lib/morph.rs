@@ -0,0 +1,88 @@
+use super::midi_command::ParameterValue;
+use super::patch::Patch;
+use super::synth::{Synth, SynthParameter};
+
+/// Value of a parameter at a position (0 to 1) between two values.
+/// Continuous parameters are interpolated, enumerations and texts switch
+/// at the midpoint.
+pub fn interpolate(p: &SynthParameter, a: &ParameterValue, b: &ParameterValue, position: f32) -> ParameterValue {
+    let position = position.clamp(0.0, 1.0);
+
+    match (a, b) {
+        (ParameterValue::Integer(x), ParameterValue::Integer(y)) if !p.is_enumeration() => {
+            let v = *x as f32 + (*y as f32 - *x as f32) * position;
+            ParameterValue::Integer(v.round() as usize)
+        }
+        _ => {
+            if position < 0.5 {
+                a.clone()
+            } else {
+                b.clone()
+            }
+        }
+    }
+}
+
+/// Patch at a position (0 to 1) between two patches. Parameters missing
+/// from one of the patches keep the value of the other.
+pub fn morph(synth: &Synth, a: &Patch, b: &Patch, position: f32) -> Patch {
+    let mut patch = Patch::new(synth.id.as_str(), format!("{} > {}", a.name, b.name).as_str());
+
+    for p in &synth.parameters {
+        let value = match (a.get(&p.id), b.get(&p.id)) {
+            (Some(x), Some(y)) => interpolate(p, x, y, position),
+            (Some(x), None) => x.clone(),
+            (None, Some(y)) => y.clone(),
+            (None, None) => continue,
+        };
+        patch.set(&p.id, value);
+    }
+
+    patch
+}
+
+/// Morph between two patches, remembering the values already sent so that
+/// each step only gives back the parameters that changed
+#[derive(Debug, Clone)]
+pub struct Morpher {
+    pub a: Patch,
+    pub b: Patch,
+    pub position: f32,
+    sent: Option<Patch>,
+}
+
+impl Morpher {
+    pub fn new(a: Patch, b: Patch) -> Morpher {
+        Morpher {
+            a,
+            b,
+            position: 0.0,
+            sent: None,
+        }
+    }
+
+    /// Moves to a position (0 to 1), sending the values that changed and
+    /// returning their count. The values only count as sent, and the
+    /// position as reached, once `send` succeeds.
+    pub fn step<F, E>(&mut self, synth: &Synth, position: f32, send: F) -> Result<usize, E>
+    where
+        F: FnOnce(&[(String, ParameterValue)]) -> Result<(), E>,
+    {
+        let patch = morph(synth, &self.a, &self.b, position);
+
+        let changes: Vec<(String, ParameterValue)> = match &self.sent {
+            Some(sent) => patch
+                .values
+                .iter()
+                .filter(|(k, v)| sent.get(k) != Some(v))
+                .cloned()
+                .collect(),
+            None => patch.values.clone(),
+        };
+
+        send(&changes)?;
+        self.position = position.clamp(0.0, 1.0);
+        self.sent = Some(patch);
+        Ok(changes.len())
+    }
+}
@@ -0,0 +1,272 @@
+use super::midi_command::ParameterValue;
+use super::synth::Synth;
+use super::utils::*;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use derive_more::*;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, From)]
+pub enum PatchError {
+    IOError(std::io::Error),
+    JsonError(json::Error),
+    CommonError(String),
+}
+
+impl std::fmt::Display for PatchError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PatchError::IOError(e) => {
+                write!(f, "{}", e)
+            }
+
+            PatchError::JsonError(e) => {
+                write!(f, "{}", e)
+            }
+
+            PatchError::CommonError(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+/// A program of a synth, as raw dump data and/or decoded parameter values
+#[derive(Debug, Clone, Default)]
+pub struct Patch {
+    pub synth: String,
+    pub name: String,
+    /// Raw SysEx data, empty when the patch was not built from a dump
+    pub data: Vec<u8>,
+    /// Parameter values by synth parameter id
+    pub values: Vec<(String, ParameterValue)>,
+}
+
+impl Patch {
+    pub fn new(synth: &str, name: &str) -> Patch {
+        Patch {
+            synth: synth.to_owned(),
+            name: name.to_owned(),
+            data: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    pub fn from_dump(synth: &Synth, name: &str, data: &[u8]) -> Patch {
+        let mut p = Patch::new(synth.id.as_str(), name);
+        p.data = data.to_vec();
+        p.decode(synth);
+        p
+    }
+
+    /// Decodes the parameter values from the program dump found in the data,
+    /// if the synth defines its layout
+    pub fn decode(&mut self, synth: &Synth) {
+        let dump = match synth.program_dump() {
+            Some(d) => d,
+            None => return,
+        };
+
+        if let Some(message) = split_sysex(&self.data).into_iter().find(|m| dump.matches(m)) {
+            let payload = dump.payload(message);
+            self.values = synth
+                .parameters
+                .iter()
+                .filter_map(|p| p.read(payload).map(|v| (p.id.clone(), v)))
+                .collect();
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<&ParameterValue> {
+        self.values.iter().find(|(k, _)| k == id).map(|(_, v)| v)
+    }
+
+    pub fn set(&mut self, id: &str, value: ParameterValue) {
+        match self.values.iter_mut().find(|(k, _)| k == id) {
+            Some(v) => v.1 = value,
+            None => self.values.push((id.to_owned(), value)),
+        }
+    }
+
+    /// Loads a patch from a .syx dump, or from a JSON patch file :
+    /// { "synth" : "dw-8000", "name" : "Brass", "parameters" : { "cutoff" : 34 }, "data" : "F0 ... F7" }
+    pub fn load(path: &str, synth: Option<&Synth>) -> Result<Patch, PatchError> {
+        let mut file = File::open(path)?;
+        let mut contents: Vec<u8> = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        let name = Path::new(path)
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .unwrap_or("")
+            .to_owned();
+
+        if path.to_lowercase().ends_with(".syx") {
+            let mut p = Patch::new(synth.map(|s| s.id.as_str()).unwrap_or(""), name.as_str());
+            p.data = contents;
+            if let Some(s) = synth {
+                p.decode(s);
+            }
+            return Ok(p);
+        }
+
+        let val = json::parse(String::from_utf8_lossy(&contents).as_ref())?;
+        let mut p = Patch::new(
+            val["synth"].as_str().unwrap_or(""),
+            val["name"].as_str().unwrap_or(name.as_str()),
+        );
+
+        if let Some(data) = val["data"].as_str() {
+            p.data = stob(data);
+        }
+
+        for (k, v) in val["parameters"].entries() {
+            if let Some(i) = v.as_usize() {
+                p.values.push((k.to_owned(), ParameterValue::Integer(i)));
+            } else if let Some(t) = v.as_str() {
+                p.values.push((k.to_owned(), ParameterValue::Text(t.to_owned())));
+            }
+        }
+
+        if p.values.is_empty() {
+            if let Some(s) = synth {
+                p.decode(s);
+            }
+        }
+
+        Ok(p)
+    }
+
+    pub fn to_json(&self) -> json::JsonValue {
+        let mut parameters = json::JsonValue::new_object();
+        for (k, v) in &self.values {
+            parameters[k.as_str()] = match v {
+                ParameterValue::Integer(i) => (*i).into(),
+                ParameterValue::Text(t) => t.as_str().into(),
+            };
+        }
+
+        let mut val = json::JsonValue::new_object();
+        val["synth"] = self.synth.as_str().into();
+        val["name"] = self.name.as_str().into();
+        val["parameters"] = parameters;
+        if !self.data.is_empty() {
+            val["data"] = btos(&self.data).into();
+        }
+        val
+    }
+
+    /// Saves the patch as raw SysEx when the path ends in .syx, as JSON otherwise
+    pub fn save(&self, path: &str) -> Result<(), PatchError> {
+        let mut file = File::create(path)?;
+
+        if path.to_lowercase().ends_with(".syx") {
+            if self.data.is_empty() {
+                return Err(PatchError::CommonError(format!(
+                    "Patch {} has no dump data to save as SYSEX",
+                    self.name
+                )));
+            }
+            file.write_all(&self.data)?;
+        } else {
+            file.write_all(self.to_json().pretty(4).as_bytes())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParameterChange {
+    pub id: String,
+    pub name: String,
+    pub old: Option<ParameterValue>,
+    pub new: Option<ParameterValue>,
+    pub old_label: String,
+    pub new_label: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ByteChange {
+    pub offset: usize,
+    pub old: Option<u8>,
+    pub new: Option<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum PatchDiff {
+    Parameters(Vec<ParameterChange>),
+    Bytes(Vec<ByteChange>),
+}
+
+impl PatchDiff {
+    pub fn is_empty(&self) -> bool {
+        match self {
+            PatchDiff::Parameters(v) => v.is_empty(),
+            PatchDiff::Bytes(v) => v.is_empty(),
+        }
+    }
+}
+
+impl std::fmt::Display for PatchDiff {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let byte = |b: &Option<u8>| b.map(|x| format!("{:02X}", x)).unwrap_or(String::from("--"));
+
+        match self {
+            PatchDiff::Parameters(changes) => {
+                for c in changes {
+                    writeln!(f, "{} ({}): {} -> {}", c.name, c.id, c.old_label, c.new_label)?;
+                }
+            }
+
+            PatchDiff::Bytes(changes) => {
+                for c in changes {
+                    writeln!(f, "{:04X}: {} -> {}", c.offset, byte(&c.old), byte(&c.new))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Lists the differences between two patches, by parameter when both can be
+/// decoded with the synth's parameters, byte by byte otherwise
+pub fn diff(synth: Option<&Synth>, a: &Patch, b: &Patch) -> PatchDiff {
+    if let Some(s) = synth {
+        if !s.parameters.is_empty() && !a.values.is_empty() && !b.values.is_empty() {
+            let label = |p: &super::synth::SynthParameter, v: Option<&ParameterValue>| {
+                v.map(|x| p.label(x)).unwrap_or(String::from("-"))
+            };
+
+            let changes = s
+                .parameters
+                .iter()
+                .filter(|p| a.get(&p.id) != b.get(&p.id))
+                .map(|p| ParameterChange {
+                    id: p.id.clone(),
+                    name: p.name.clone(),
+                    old: a.get(&p.id).cloned(),
+                    new: b.get(&p.id).cloned(),
+                    old_label: label(p, a.get(&p.id)),
+                    new_label: label(p, b.get(&p.id)),
+                })
+                .collect();
+
+            return PatchDiff::Parameters(changes);
+        }
+    }
+
+    let size = a.data.len().max(b.data.len());
+    let changes = (0..size)
+        .filter(|&i| a.data.get(i) != b.data.get(i))
+        .map(|i| ByteChange {
+            offset: i,
+            old: a.data.get(i).cloned(),
+            new: b.data.get(i).cloned(),
+        })
+        .collect();
+
+    PatchDiff::Bytes(changes)
+}
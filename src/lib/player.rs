@@ -0,0 +1,100 @@
+use super::midi_interface::MidiTransport;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Longest sleep between two checks of the stop flag
+const STOP_CHECK: Duration = Duration::from_millis(5);
+
+/// Plays timed messages on a thread of its own
+pub struct Player {
+    stop: Arc<AtomicBool>,
+    playing: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Sends a channel message on another channel, other messages unchanged
+fn remap(message: &[u8], channel: Option<u8>) -> Vec<u8> {
+    let mut message = message.to_vec();
+    if let (Some(c), Some(status)) = (channel, message.first_mut()) {
+        if (0x80..0xF0).contains(status) {
+            *status = (*status & 0xF0) | (c & 0x0F);
+        }
+    }
+    message
+}
+
+/// Sleeps until a time, unless stopped. Returns whether it was stopped.
+fn wait_until(at: Instant, stop: &AtomicBool) -> bool {
+    loop {
+        if stop.load(Ordering::Relaxed) {
+            return true;
+        }
+
+        let now = Instant::now();
+        if now >= at {
+            return false;
+        }
+        thread::sleep((at - now).min(STOP_CHECK));
+    }
+}
+
+impl Player {
+    /// Starts playing messages at their time, remapped to a channel if any,
+    /// from the start again after `length` when looping. All notes are
+    /// turned off when playing ends.
+    pub fn start<T>(mut output: T, messages: Vec<(Duration, Vec<u8>)>, length: Duration, channel: Option<u8>, looping: bool) -> Player
+    where
+        T: MidiTransport + Send + 'static,
+    {
+        let stop = Arc::new(AtomicBool::new(false));
+        let playing = Arc::new(AtomicBool::new(true));
+        let (thread_stop, thread_playing) = (stop.clone(), playing.clone());
+
+        let thread = thread::spawn(move || {
+            'playing: loop {
+                let start = Instant::now();
+
+                for (at, message) in &messages {
+                    if wait_until(start + *at, &thread_stop) {
+                        break 'playing;
+                    }
+                    if let Err(e) = output.send(&remap(message, channel)) {
+                        println!("Playback stopped : {}", e);
+                        break 'playing;
+                    }
+                }
+
+                if !looping || wait_until(start + length, &thread_stop) {
+                    break;
+                }
+            }
+
+            for c in 0..16 {
+                let _ = output.send(&[0xB0 | c, 0x7B, 0x00]);
+            }
+            thread_playing.store(false, Ordering::Relaxed);
+        });
+
+        Player { stop, playing, thread: Some(thread) }
+    }
+
+    pub fn is_playing(&self) -> bool {
+        self.playing.load(Ordering::Relaxed)
+    }
+
+    /// Stops playing and waits for the notes to be turned off
+    pub fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(t) = self.thread.take() {
+            let _ = t.join();
+        }
+    }
+}
+
+impl Drop for Player {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
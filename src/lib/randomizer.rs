@@ -0,0 +1,138 @@
+use super::midi_command::ParameterValue;
+use super::patch::Patch;
+use super::synth::{Synth, SynthParameter};
+use std::collections::HashMap;
+
+/// Small seeded generator (SplitMix64), so that a seed always gives back the
+/// same patch
+#[derive(Debug, Clone)]
+pub struct Random {
+    state: u64,
+}
+
+impl Random {
+    pub fn new(seed: u64) -> Random {
+        Random { state: seed }
+    }
+
+    /// Seed taken from the clock, for when the user doesn't give one
+    pub fn clock_seed() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_nanos() as u64)
+            .unwrap_or(0)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform value in [0, 1)
+    pub fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+
+    /// Uniform value between min and max, both included
+    pub fn range(&mut self, min: usize, max: usize) -> usize {
+        if max <= min {
+            return min;
+        }
+        min + (self.next_u64() % (max - min + 1) as u64) as usize
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Constraint {
+    /// Keep the base patch's value
+    Lock,
+    /// Only use this fraction (0 to 1) of the parameter's range, around the
+    /// base patch's value or the middle of the range
+    Ratio(f32),
+}
+
+/// Generates patches from a synth's parameter table
+#[derive(Debug, Clone)]
+pub struct Randomizer {
+    pub seed: u64,
+    /// Fraction of the range each parameter may move by from the base patch
+    pub amount: f32,
+    pub constraints: HashMap<String, Constraint>,
+}
+
+impl Randomizer {
+    pub fn new(seed: u64) -> Randomizer {
+        Randomizer {
+            seed,
+            amount: 1.0,
+            constraints: HashMap::new(),
+        }
+    }
+
+    pub fn lock(&mut self, id: &str) {
+        self.constraints.insert(id.to_owned(), Constraint::Lock);
+    }
+
+    pub fn ratio(&mut self, id: &str, ratio: f32) {
+        self.constraints.insert(id.to_owned(), Constraint::Ratio(ratio.clamp(0.0, 1.0)));
+    }
+
+    /// Mutates the base patch by the given percentage of each parameter's range
+    pub fn mutate(&mut self, percent: f32) {
+        self.amount = (percent / 100.0).clamp(0.0, 1.0);
+    }
+
+    /// Generates a new patch, a mutation of the base patch when one is given.
+    /// Text parameters are left untouched.
+    pub fn generate(&self, synth: &Synth, base: Option<&Patch>) -> Patch {
+        let mut random = Random::new(self.seed);
+        let mut patch = match base {
+            Some(b) => b.clone(),
+            None => Patch::new(synth.id.as_str(), ""),
+        };
+        patch.name = format!("random-{}", self.seed);
+
+        for p in synth.parameters.iter().filter(|p| !p.is_text()) {
+            let current = base.and_then(|b| b.get(&p.id)).and_then(|v| v.as_integer());
+
+            let ratio = match (self.constraints.get(&p.id), current) {
+                (Some(Constraint::Lock), _) => continue,
+                (Some(Constraint::Ratio(r)), _) => *r,
+                (None, Some(_)) => self.amount,
+                (None, None) => 1.0,
+            };
+
+            let value = Randomizer::random_value(&mut random, p, current, ratio);
+            patch.set(&p.id, ParameterValue::Integer(value));
+        }
+
+        patch
+    }
+
+    fn random_value(random: &mut Random, p: &SynthParameter, current: Option<usize>, ratio: f32) -> usize {
+        if p.is_enumeration() {
+            // Enumerations are not ordered, they switch to a random value
+            // with a probability given by the ratio
+            return match current {
+                Some(c) if random.next_f32() >= ratio => c,
+                _ => random.range(p.min, p.max),
+            };
+        }
+
+        let center = current.unwrap_or((p.min + p.max) / 2).max(p.min).min(p.max);
+        let span = ((p.max - p.min) as f32 * ratio).round() as usize;
+
+        if current.is_some() {
+            let low = center.saturating_sub(span).max(p.min);
+            let high = (center + span).min(p.max);
+            random.range(low, high)
+        } else {
+            let low = center.saturating_sub(span / 2).max(p.min);
+            let high = (low + span).min(p.max);
+            random.range(low, high)
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use super::midi_parser::MessageKind;
+use super::smf::{self, Event, EventKind, Smf};
+use super::state::Direction;
+use std::fs::{self, File};
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+/// Where recorded messages go
+#[derive(Debug)]
+pub enum RecordTarget {
+    /// Every message appended to a single .syx file
+    File(File),
+    /// One .syx file per message in a directory
+    Split(PathBuf),
+}
+
+/// Saves the SysEx messages received to .syx files
+#[derive(Debug)]
+pub struct Recorder {
+    pub path: PathBuf,
+    target: RecordTarget,
+    pub messages: usize,
+    pub bytes: usize,
+}
+
+/// File name part for a command name, e.g. "Parameter Change" gives "parameter-change"
+fn file_name(name: &str) -> String {
+    name.to_lowercase()
+        .split(|c: char| !c.is_ascii_alphanumeric())
+        .filter(|s| !s.is_empty())
+        .collect::<Vec<&str>>()
+        .join("-")
+}
+
+impl Recorder {
+    /// Records to a file, or to a directory with one file per message
+    pub fn start(path: &Path, split: bool) -> std::io::Result<Recorder> {
+        let target = if split {
+            fs::create_dir_all(path)?;
+            RecordTarget::Split(path.to_path_buf())
+        } else {
+            RecordTarget::File(File::create(path)?)
+        };
+
+        Ok(Recorder {
+            path: path.to_path_buf(),
+            target,
+            messages: 0,
+            bytes: 0,
+        })
+    }
+
+    /// Saves a SysEx message, named after the command it matches in split
+    /// mode. Other messages are ignored.
+    pub fn record(&mut self, message: &[u8], name: Option<&str>) -> std::io::Result<()> {
+        if message.first() != Some(&0xF0) {
+            return Ok(());
+        }
+
+        match &mut self.target {
+            RecordTarget::File(f) => {
+                f.write_all(message)?;
+                f.flush()?;
+            }
+
+            RecordTarget::Split(dir) => {
+                let name = file_name(name.unwrap_or("unknown"));
+                fs::write(dir.join(format!("{:04}_{}.syx", self.messages + 1, name)), message)?;
+            }
+        }
+
+        self.messages += 1;
+        self.bytes += message.len();
+        Ok(())
+    }
+}
+
+/// Records the channel and SysEx messages received and sent to a Standard
+/// MIDI File, the performance and the patch changes made during it
+#[derive(Debug)]
+pub struct MidiFileRecorder {
+    pub path: PathBuf,
+    /// 0 for a single track, 1 for a track per direction
+    pub format: u16,
+    /// Ticks per quarter note
+    pub ppq: u16,
+    /// Microseconds per quarter note
+    pub tempo: u32,
+    started: Instant,
+    /// First input timestamp, and when it was received
+    origin: Option<(u64, Instant)>,
+    /// Microseconds from the start, direction, message
+    events: Vec<(u64, Direction, Vec<u8>)>,
+}
+
+impl MidiFileRecorder {
+    pub fn start(path: &Path, format: u16, ppq: u16, bpm: f64) -> Result<MidiFileRecorder, String> {
+        let tempo = smf::tempo(bpm).ok_or(format!("A MIDI file can't hold a tempo of {} BPM", bpm))?;
+
+        Ok(MidiFileRecorder {
+            path: path.to_path_buf(),
+            format,
+            ppq: ppq.max(1),
+            tempo,
+            started: Instant::now(),
+            origin: None,
+            events: Vec::new(),
+        })
+    }
+
+    /// Microseconds from the start for a message received at an input
+    /// timestamp, or for a message sent now
+    fn time(&mut self, stamp: Option<u64>) -> u64 {
+        let now = Instant::now();
+        match (stamp, self.origin) {
+            (Some(s), Some((first, at))) => (at - self.started).as_micros() as u64 + s.saturating_sub(first),
+            (Some(s), None) => {
+                self.origin = Some((s, now));
+                (now - self.started).as_micros() as u64
+            }
+            (None, _) => (now - self.started).as_micros() as u64,
+        }
+    }
+
+    /// Saves a channel or SysEx message, with the input timestamp in
+    /// microseconds for received messages. Other messages are ignored.
+    pub fn record(&mut self, stamp: Option<u64>, direction: Direction, message: &[u8]) {
+        match MessageKind::of(message) {
+            Some(MessageKind::Channel) | Some(MessageKind::Sysex) => {
+                let time = self.time(stamp);
+                self.events.push((time, direction, message.to_vec()));
+            }
+            _ => {}
+        }
+    }
+
+    pub fn messages(&self) -> usize {
+        self.events.len()
+    }
+
+    /// Tempo in the first track, then the messages received and sent in
+    /// tracks of their own for type 1
+    pub fn to_smf(&self) -> Smf {
+        let name = |n: &str| Event { tick: 0, kind: EventKind::Meta(0x03, n.as_bytes().to_vec()) };
+        let mut tracks = vec![vec![Event { tick: 0, kind: EventKind::Tempo(self.tempo) }]];
+        if self.format == 1 {
+            tracks.push(vec![name("Received")]);
+            tracks.push(vec![name("Sent")]);
+        }
+
+        let mut events = self.events.clone();
+        events.sort_by_key(|(time, _, _)| *time);
+
+        for (time, direction, message) in events {
+            let tick = time * self.ppq as u64 / self.tempo.max(1) as u64;
+            let track = match (self.format, direction) {
+                (1, Direction::Received) => 1,
+                (1, Direction::Sent) => 2,
+                _ => 0,
+            };
+            tracks[track].push(Event { tick, kind: EventKind::Midi(message) });
+        }
+
+        Smf { format: self.format, division: self.ppq, tracks }
+    }
+
+    pub fn finish(&self) -> std::io::Result<()> {
+        self.to_smf().write(&self.path)
+    }
+}
@@ -0,0 +1,375 @@
+use super::handshake::{Handshake, HandshakeError};
+use super::midi_interface::*;
+use std::time::{Duration, Instant};
+
+use derive_more::*;
+
+/// Bytes of sample data in a data packet
+pub const PACKET_DATA_SIZE: usize = 120;
+
+/// Time the receiver has to answer the dump header, after which the
+/// transfer goes on without handshake
+const HEADER_TIMEOUT: Duration = Duration::from_millis(2000);
+
+/// Time the receiver has to answer a data packet, after which the next
+/// packet is sent anyway
+const PACKET_TIMEOUT: Duration = Duration::from_millis(20);
+
+/// Time between packets when the receiver doesn't answer
+const OPEN_LOOP_PACE: Duration = Duration::from_millis(20);
+
+/// Bad packets in a row after which a transfer is given up
+const MAX_RETRIES: usize = 8;
+
+/// Packets between two progress lines
+const PROGRESS_STEP: usize = 100;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, From)]
+pub enum SdsError {
+    InterfaceError(MidiInterfaceError),
+    HandshakeError(HandshakeError),
+    CommonError(String),
+}
+
+impl std::fmt::Display for SdsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SdsError::InterfaceError(e) => {
+                write!(f, "{}", e)
+            }
+
+            SdsError::HandshakeError(e) => {
+                write!(f, "{}", e)
+            }
+
+            SdsError::CommonError(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoopType {
+    Forward,
+    Alternating,
+    Off,
+}
+
+impl LoopType {
+    fn to_byte(self) -> u8 {
+        match self {
+            LoopType::Forward => 0x00,
+            LoopType::Alternating => 0x01,
+            LoopType::Off => 0x7F,
+        }
+    }
+
+    fn from_byte(b: u8) -> LoopType {
+        match b {
+            0x00 => LoopType::Forward,
+            0x01 => LoopType::Alternating,
+            _ => LoopType::Off,
+        }
+    }
+}
+
+/// Handshake messages : F0 7E channel type packet F7
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Reply {
+    Ack(u8),
+    Nak(u8),
+    Wait(u8),
+    Cancel(u8),
+}
+
+impl Reply {
+    pub fn parse(message: &[u8]) -> Option<Reply> {
+        if message.len() != 6 || message[1] != 0x7E || message[5] != 0xF7 {
+            return None;
+        }
+
+        let packet = message[4];
+        match message[3] {
+            0x7F => Some(Reply::Ack(packet)),
+            0x7E => Some(Reply::Nak(packet)),
+            0x7C => Some(Reply::Wait(packet)),
+            0x7D => Some(Reply::Cancel(packet)),
+            _ => None,
+        }
+    }
+
+    pub fn to_bytes(self, channel: u8) -> Vec<u8> {
+        let (kind, packet) = match self {
+            Reply::Ack(p) => (0x7F, p),
+            Reply::Nak(p) => (0x7E, p),
+            Reply::Wait(p) => (0x7C, p),
+            Reply::Cancel(p) => (0x7D, p),
+        };
+        vec![0xF0, 0x7E, channel, kind, packet, 0xF7]
+    }
+}
+
+/// A sample as described by the dump header, with its words as signed
+/// values of `bits` bits
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub number: u16,
+    /// Bits per word, 8 to 28
+    pub bits: u8,
+    /// Sample period in nanoseconds
+    pub period: u32,
+    /// Sustain loop, as word numbers
+    pub loop_start: u32,
+    pub loop_end: u32,
+    pub loop_type: LoopType,
+    pub data: Vec<i32>,
+}
+
+fn encode_21(value: u32) -> [u8; 3] {
+    [(value & 0x7F) as u8, ((value >> 7) & 0x7F) as u8, ((value >> 14) & 0x7F) as u8]
+}
+
+fn decode_21(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 7 | (bytes[2] as u32) << 14
+}
+
+/// Checksum of a data packet : XOR of every byte between F0 and the checksum
+fn checksum(data: &[u8]) -> u8 {
+    data.iter().fold(0x00, |acc, b| acc ^ b) & 0x7F
+}
+
+/// Dump Request : F0 7E channel 03 number(2) F7
+pub fn dump_request(channel: u8, number: u16) -> Vec<u8> {
+    vec![0xF0, 0x7E, channel, 0x03, (number & 0x7F) as u8, ((number >> 7) & 0x7F) as u8, 0xF7]
+}
+
+/// Data Packet : F0 7E channel 02 packet data(120) checksum F7. Returns the
+/// packet number, its data and whether its checksum is right.
+pub fn parse_packet(message: &[u8]) -> Option<(u8, &[u8], bool)> {
+    if message.len() != PACKET_DATA_SIZE + 7 || message[1] != 0x7E || message[3] != 0x02 {
+        return None;
+    }
+
+    let sum = message[PACKET_DATA_SIZE + 5];
+    Some((message[4], &message[5..PACKET_DATA_SIZE + 5], checksum(&message[1..PACKET_DATA_SIZE + 5]) == sum))
+}
+
+impl Sample {
+    pub fn new(bits: u8, rate: u32, data: Vec<i32>) -> Sample {
+        Sample {
+            number: 0,
+            bits,
+            period: (1_000_000_000 / rate.max(1) as u64) as u32,
+            loop_start: 0,
+            loop_end: (data.len() as u32).saturating_sub(1),
+            loop_type: LoopType::Off,
+            data,
+        }
+    }
+
+    /// Sample rate in Hz
+    pub fn rate(&self) -> u32 {
+        (1_000_000_000 / self.period.max(1) as u64) as u32
+    }
+
+    /// Bytes a word takes in data packets, 7 bits each
+    pub fn word_size(&self) -> usize {
+        (self.bits as usize).div_ceil(7)
+    }
+
+    /// Dump Header : F0 7E channel 01 number(2) bits period(3) length(3)
+    /// loop start(3) loop end(3) loop type F7
+    pub fn header(&self, channel: u8) -> Vec<u8> {
+        let mut header = vec![0xF0, 0x7E, channel, 0x01, (self.number & 0x7F) as u8, ((self.number >> 7) & 0x7F) as u8, self.bits];
+        header.extend(&encode_21(self.period));
+        header.extend(&encode_21(self.data.len() as u32));
+        header.extend(&encode_21(self.loop_start));
+        header.extend(&encode_21(self.loop_end));
+        header.push(self.loop_type.to_byte());
+        header.push(0xF7);
+        header
+    }
+
+    /// Sample described by a dump header, without data, and its length in words
+    pub fn from_header(message: &[u8]) -> Option<(Sample, usize)> {
+        if message.len() != 21 || message[1] != 0x7E || message[3] != 0x01 || message[20] != 0xF7 {
+            return None;
+        }
+
+        let sample = Sample {
+            number: message[4] as u16 | (message[5] as u16) << 7,
+            bits: message[6],
+            period: decode_21(&message[7..10]),
+            loop_start: decode_21(&message[13..16]),
+            loop_end: decode_21(&message[16..19]),
+            loop_type: LoopType::from_byte(message[19]),
+            data: Vec::new(),
+        };
+        Some((sample, decode_21(&message[10..13]) as usize))
+    }
+
+    /// Words as sent in data packets : unsigned, left-justified in 7 bit bytes
+    fn encode_words(&self) -> Vec<u8> {
+        let size = self.word_size();
+        let offset = 1i64 << (self.bits - 1);
+        let shift = size * 7 - self.bits as usize;
+        let mut bytes = Vec::with_capacity(self.data.len() * size);
+
+        for &v in &self.data {
+            let word = ((v as i64 + offset) as u64) << shift;
+            for i in (0..size).rev() {
+                bytes.push(((word >> (i * 7)) & 0x7F) as u8);
+            }
+        }
+        bytes
+    }
+
+    fn decode_words(&mut self, bytes: &[u8], length: usize) {
+        let size = self.word_size();
+        let offset = 1i64 << (self.bits - 1);
+        let shift = size * 7 - self.bits as usize;
+
+        self.data = bytes
+            .chunks(size)
+            .take(length)
+            .map(|w| {
+                let word = w.iter().fold(0u64, |acc, &b| acc << 7 | b as u64);
+                ((word >> shift) as i64 - offset) as i32
+            })
+            .collect();
+    }
+
+    /// Data packets of the sample, the last one padded with zeros
+    pub fn packets(&self, channel: u8) -> Vec<Vec<u8>> {
+        let words_per_packet = PACKET_DATA_SIZE / self.word_size();
+        let bytes = self.encode_words();
+
+        bytes
+            .chunks(words_per_packet * self.word_size())
+            .enumerate()
+            .map(|(i, data)| {
+                let mut packet = vec![0xF0, 0x7E, channel, 0x02, (i % 128) as u8];
+                packet.extend(data);
+                packet.resize(PACKET_DATA_SIZE + 5, 0x00);
+                packet.push(checksum(&packet[1..]));
+                packet.push(0xF7);
+                packet
+            })
+            .collect()
+    }
+}
+
+/// ACK, NAK, WAIT and CANCEL replies, for any channel and packet
+pub fn handshake() -> Handshake {
+    Handshake {
+        ack: vec![Handshake::reply("ACK", "F0 7E cc 7F pp F7")],
+        error: vec![Handshake::reply("NAK", "F0 7E cc 7E pp F7")],
+        wait: vec![Handshake::reply("WAIT", "F0 7E cc 7C pp F7")],
+        cancel: vec![Handshake::reply("CANCEL", "F0 7E cc 7D pp F7")],
+        timeout: PACKET_TIMEOUT,
+        retries: MAX_RETRIES,
+        continue_on_timeout: true,
+        ..Handshake::default()
+    }
+}
+
+/// Sends a sample to a sampler, packet by packet, resending the packets it
+/// refuses. Samplers that don't answer the header get the packets at a
+/// fixed pace.
+pub fn send<T: MidiTransport>(transport: &mut T, channel: u8, sample: &Sample) -> Result<usize, SdsError> {
+    if !(8..=28).contains(&sample.bits) {
+        return Err(SdsError::CommonError(format!("Unsupported sample format : {} bits", sample.bits)));
+    }
+
+    let header = Handshake { timeout: HEADER_TIMEOUT, ..handshake() };
+    let packet = if header.send(transport, &sample.header(channel))? {
+        handshake()
+    } else {
+        Handshake { pace: OPEN_LOOP_PACE, ..Handshake::default() }
+    };
+
+    let packets = sample.packets(channel);
+    for (i, p) in packets.iter().enumerate() {
+        packet
+            .send(transport, p)
+            .map_err(|e| SdsError::CommonError(format!("Packet {} : {}", i, e)))?;
+
+        if (i + 1) % PROGRESS_STEP == 0 {
+            println!("{}/{} packets", i + 1, packets.len());
+        }
+    }
+
+    Ok(packets.len())
+}
+
+/// Receives a sample, requesting it first when a sample number is given.
+/// Packets are acknowledged one by one, bad ones being asked again.
+pub fn receive<T: MidiTransport>(transport: &mut T, channel: u8, number: Option<u16>) -> Result<Sample, SdsError> {
+    if let Some(n) = number {
+        transport.flush();
+        transport.send(&dump_request(channel, n))?;
+    }
+
+    let deadline = Instant::now() + HEADER_TIMEOUT;
+    let (mut sample, length, channel) = loop {
+        let message = transport
+            .receive(deadline.saturating_duration_since(Instant::now()))
+            .ok_or(MidiInterfaceError::Timeout(String::from("No dump header received")))?;
+        if let Some((s, l)) = Sample::from_header(&message) {
+            break (s, l, message[2]);
+        }
+    };
+
+    if !(8..=28).contains(&sample.bits) {
+        transport.send(&Reply::Cancel(0).to_bytes(channel))?;
+        return Err(SdsError::CommonError(format!("Unsupported sample format : {} bits", sample.bits)));
+    }
+    transport.send(&Reply::Ack(0).to_bytes(channel))?;
+
+    let size = length * sample.word_size();
+    let mut bytes: Vec<u8> = Vec::with_capacity(size + PACKET_DATA_SIZE);
+    let mut expected: u8 = 0;
+    let mut received = 0;
+    let mut retries = 0;
+
+    while bytes.len() < size {
+        let message = transport
+            .receive(HEADER_TIMEOUT)
+            .ok_or_else(|| SdsError::CommonError(format!("Dump interrupted after {} packets", received)))?;
+
+        if let Some(Reply::Cancel(_)) = Reply::parse(&message) {
+            return Err(SdsError::CommonError(format!("Dump cancelled by the sender after {} packets", received)));
+        }
+
+        match parse_packet(&message) {
+            Some((n, data, true)) if n == expected => {
+                bytes.extend(data);
+                transport.send(&Reply::Ack(n).to_bytes(channel))?;
+                expected = (expected + 1) % 128;
+                received += 1;
+                retries = 0;
+            }
+
+            // Resent after a late acknowledgement
+            Some((n, _, true)) if (n + 1) % 128 == expected => {
+                transport.send(&Reply::Ack(n).to_bytes(channel))?;
+            }
+
+            Some((n, _, _)) => {
+                retries += 1;
+                if retries > MAX_RETRIES {
+                    transport.send(&Reply::Cancel(n).to_bytes(channel))?;
+                    return Err(SdsError::CommonError(format!("Packet {} received wrong {} times", received, retries)));
+                }
+                transport.send(&Reply::Nak(n).to_bytes(channel))?;
+            }
+
+            None => {}
+        }
+    }
+
+    sample.decode_words(&bytes, length);
+    Ok(sample)
+}
@@ -0,0 +1,278 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::time::Duration;
+
+/// Tempo until the first tempo change, 120 BPM
+pub const DEFAULT_TEMPO: u32 = 500_000;
+/// Longest tempo a tempo event holds in its 3 bytes, about 3.58 BPM
+pub const MAX_TEMPO: u32 = 0xFF_FFFF;
+
+/// Microseconds per quarter note at a number of beats per minute, None when
+/// a tempo event can't hold it
+pub fn tempo(bpm: f64) -> Option<u32> {
+    let tempo = 60_000_000.0 / bpm;
+    if bpm > 0.0 && tempo <= MAX_TEMPO as f64 {
+        Some(tempo as u32)
+    } else {
+        None
+    }
+}
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum EventKind {
+    /// Channel, system common or SysEx message, SysEx starting with F0
+    Midi(Vec<u8>),
+    /// Microseconds per quarter note
+    Tempo(u32),
+    EndOfTrack,
+    /// Other meta events, by type
+    Meta(u8, Vec<u8>),
+}
+
+/// Event of a track, at a tick counted from the start of the file
+#[derive(Debug, Clone, PartialEq)]
+pub struct Event {
+    pub tick: u64,
+    pub kind: EventKind,
+}
+
+/// Standard MIDI File, type 0 or 1
+#[derive(Debug, Clone, PartialEq)]
+pub struct Smf {
+    pub format: u16,
+    /// Ticks per quarter note, or SMPTE frames per second and ticks per
+    /// frame when the top bit is set
+    pub division: u16,
+    pub tracks: Vec<Vec<Event>>,
+}
+
+/// Reads a variable length quantity, moving the position past it
+fn read_vlq(data: &[u8], position: &mut usize) -> Option<u32> {
+    let mut value: u32 = 0;
+    for _ in 0..4 {
+        let b = *data.get(*position)?;
+        *position += 1;
+        value = value << 7 | (b & 0x7F) as u32;
+        if b & 0x80 == 0 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+/// Data bytes following a channel message status byte
+fn data_length(status: u8) -> usize {
+    match status & 0xF0 {
+        0xC0 | 0xD0 => 1,
+        _ => 2,
+    }
+}
+
+fn parse_track(data: &[u8]) -> Option<Vec<Event>> {
+    let mut events = Vec::new();
+    let mut position = 0;
+    let mut tick: u64 = 0;
+    let mut running: Option<u8> = None;
+
+    while position < data.len() {
+        tick += read_vlq(data, &mut position)? as u64;
+        let first = *data.get(position)?;
+
+        let kind = match first {
+            0xFF => {
+                let kind = *data.get(position + 1)?;
+                position += 2;
+                let length = read_vlq(data, &mut position)? as usize;
+                let body = data.get(position..position + length)?;
+                position += length;
+                running = None;
+
+                match (kind, body.len()) {
+                    (0x51, 3) => EventKind::Tempo((body[0] as u32) << 16 | (body[1] as u32) << 8 | body[2] as u32),
+                    (0x2F, _) => EventKind::EndOfTrack,
+                    _ => EventKind::Meta(kind, body.to_vec()),
+                }
+            }
+
+            0xF0 | 0xF7 => {
+                position += 1;
+                let length = read_vlq(data, &mut position)? as usize;
+                let body = data.get(position..position + length)?;
+                position += length;
+                running = None;
+
+                // F7 escapes any bytes, F0 starts a SysEx message
+                let mut message = if first == 0xF0 { vec![0xF0] } else { Vec::new() };
+                message.extend(body);
+                EventKind::Midi(message)
+            }
+
+            _ => {
+                let status = if first & 0x80 != 0 {
+                    position += 1;
+                    running = Some(first);
+                    first
+                } else {
+                    running?
+                };
+
+                let length = data_length(status);
+                let mut message = vec![status];
+                message.extend(data.get(position..position + length)?);
+                position += length;
+                EventKind::Midi(message)
+            }
+        };
+
+        let end = kind == EventKind::EndOfTrack;
+        events.push(Event { tick, kind });
+        if end {
+            break;
+        }
+    }
+
+    Some(events)
+}
+
+fn write_vlq(value: u32, out: &mut Vec<u8>) {
+    let mut bytes = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        bytes.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.extend(bytes.iter().rev());
+}
+
+/// Track chunk body, ended by End of Track if it isn't already
+fn track_bytes(events: &[Event]) -> Vec<u8> {
+    let mut out = Vec::new();
+    let mut last_tick: u64 = 0;
+
+    for e in events {
+        write_vlq(e.tick.saturating_sub(last_tick) as u32, &mut out);
+        last_tick = last_tick.max(e.tick);
+
+        match &e.kind {
+            EventKind::Midi(m) if m.first() == Some(&0xF0) => {
+                out.push(0xF0);
+                write_vlq(m.len() as u32 - 1, &mut out);
+                out.extend(&m[1..]);
+            }
+            EventKind::Midi(m) => out.extend(m),
+            EventKind::Tempo(t) => out.extend(&[0xFF, 0x51, 0x03, (t >> 16) as u8, (t >> 8) as u8, *t as u8]),
+            EventKind::EndOfTrack => out.extend(&[0xFF, 0x2F, 0x00]),
+            EventKind::Meta(kind, data) => {
+                out.extend(&[0xFF, *kind]);
+                write_vlq(data.len() as u32, &mut out);
+                out.extend(data);
+            }
+        }
+    }
+
+    if events.last().map(|e| &e.kind) != Some(&EventKind::EndOfTrack) {
+        out.extend(&[0x00, 0xFF, 0x2F, 0x00]);
+    }
+    out
+}
+
+impl Smf {
+    pub fn parse(data: &[u8]) -> std::io::Result<Smf> {
+        if data.len() < 14 || &data[0..4] != b"MThd" {
+            return Err(invalid("Not a MIDI file"));
+        }
+
+        let format = u16::from_be_bytes([data[8], data[9]]);
+        let division = u16::from_be_bytes([data[12], data[13]]);
+        if format > 1 {
+            return Err(invalid("Only type 0 and type 1 MIDI files are supported"));
+        }
+
+        let mut tracks = Vec::new();
+        let mut position = 8 + u32::from_be_bytes([data[4], data[5], data[6], data[7]]) as usize;
+
+        while position + 8 <= data.len() {
+            let size = u32::from_be_bytes([data[position + 4], data[position + 5], data[position + 6], data[position + 7]]) as usize;
+            let body = data
+                .get(position + 8..position + 8 + size)
+                .ok_or_else(|| invalid("Truncated track"))?;
+
+            if &data[position..position + 4] == b"MTrk" {
+                let track = parse_track(body).ok_or_else(|| invalid(&format!("Bad data in track {}", tracks.len() + 1)))?;
+                tracks.push(track);
+            }
+            position += 8 + size;
+        }
+
+        Ok(Smf { format, division, tracks })
+    }
+
+    pub fn read(path: &Path) -> std::io::Result<Smf> {
+        Smf::parse(&fs::read(path)?)
+    }
+
+    /// Events of every track, in time order
+    pub fn merged(&self) -> Vec<Event> {
+        let mut events: Vec<Event> = self.tracks.iter().flatten().cloned().collect();
+        events.sort_by_key(|e| e.tick);
+        events
+    }
+
+    /// Length of a tick in microseconds, at a tempo for metrical divisions
+    fn tick_length(&self, tempo: u32) -> f64 {
+        if self.division & 0x8000 != 0 {
+            let fps = -((self.division >> 8) as u8 as i8) as f64;
+            let ticks_per_frame = (self.division & 0xFF) as f64;
+            1_000_000.0 / (fps * ticks_per_frame)
+        } else {
+            tempo as f64 / self.division.max(1) as f64
+        }
+    }
+
+    /// MIDI messages at their time from the start following the tempo
+    /// map, and the time the file ends at
+    pub fn timeline(&self) -> (Vec<(Duration, Vec<u8>)>, Duration) {
+        let mut messages = Vec::new();
+        let mut tempo = DEFAULT_TEMPO;
+        let mut last_tick: u64 = 0;
+        let mut time: f64 = 0.0;
+
+        for e in self.merged() {
+            time += (e.tick - last_tick) as f64 * self.tick_length(tempo);
+            last_tick = e.tick;
+
+            match e.kind {
+                EventKind::Midi(m) => messages.push((Duration::from_micros(time as u64), m)),
+                EventKind::Tempo(t) => tempo = t,
+                _ => {}
+            }
+        }
+
+        (messages, Duration::from_micros(time as u64))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = b"MThd".to_vec();
+        out.extend(&6u32.to_be_bytes());
+        out.extend(&self.format.to_be_bytes());
+        out.extend(&(self.tracks.len() as u16).to_be_bytes());
+        out.extend(&self.division.to_be_bytes());
+
+        for t in &self.tracks {
+            let body = track_bytes(t);
+            out.extend(b"MTrk");
+            out.extend(&(body.len() as u32).to_be_bytes());
+            out.extend(body);
+        }
+        out
+    }
+
+    pub fn write(&self, path: &Path) -> std::io::Result<()> {
+        fs::write(path, self.to_bytes())
+    }
+}
@@ -0,0 +1,327 @@
+use super::midi_command::{MidiCommand, ParameterValue};
+use super::midi_interface::*;
+use super::patch::Patch;
+use super::synth::{Synth, SynthParameter};
+use std::collections::{BTreeMap, HashMap};
+use std::time::Duration;
+
+use derive_more::*;
+
+#[derive(Debug, From)]
+pub enum StateError {
+    InterfaceError(MidiInterfaceError),
+    CommonError(String),
+}
+
+impl std::fmt::Display for StateError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StateError::InterfaceError(e) => {
+                write!(f, "{}", e)
+            }
+
+            StateError::CommonError(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Direction {
+    Sent,
+    Received,
+}
+
+/// What is known of a parameter's value
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ParameterState {
+    pub sent: Option<ParameterValue>,
+    pub received: Option<ParameterValue>,
+    /// Last value sent or received
+    pub value: Option<ParameterValue>,
+}
+
+impl ParameterState {
+    pub fn update(&mut self, value: ParameterValue, direction: Direction) {
+        match direction {
+            Direction::Sent => self.sent = Some(value.clone()),
+            Direction::Received => self.received = Some(value.clone()),
+        }
+        self.value = Some(value);
+    }
+}
+
+/// A parameter whose value changed while applying a message
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateChange {
+    pub channel: usize,
+    pub id: String,
+    pub old: Option<ParameterValue>,
+    pub new: ParameterValue,
+}
+
+impl StateChange {
+    /// Change as shown to the user, e.g. "cutoff: 34 -> 40", using the labels
+    /// of the synth's enumerations
+    pub fn describe(&self, synth: Option<&Synth>) -> String {
+        let p = synth.and_then(|s| s.get_parameter(&self.id));
+        let show = |v: &ParameterValue| match p {
+            Some(p) => p.label(v),
+            None => v.to_string(),
+        };
+
+        match &self.old {
+            Some(old) => format!("{}: {} -> {}", self.id, show(old), show(&self.new)),
+            None => format!("{}: {}", self.id, show(&self.new)),
+        }
+    }
+}
+
+/// Parameter values sent to and received from each synth, per channel
+#[derive(Debug, Clone, Default)]
+pub struct SessionState {
+    synths: BTreeMap<(String, usize), BTreeMap<String, ParameterState>>,
+}
+
+impl SessionState {
+    pub fn new() -> SessionState {
+        SessionState {
+            synths: BTreeMap::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.synths.clear();
+    }
+
+    /// Records a value, returning the previous one
+    pub fn update(
+        &mut self,
+        synth: &str,
+        channel: usize,
+        id: &str,
+        value: ParameterValue,
+        direction: Direction,
+    ) -> Option<ParameterValue> {
+        let state = self
+            .synths
+            .entry((synth.to_owned(), channel))
+            .or_default()
+            .entry(id.to_owned())
+            .or_default();
+
+        let old = state.value.clone();
+        state.update(value, direction);
+        old
+    }
+
+    pub fn get(&self, synth: &str, channel: usize, id: &str) -> Option<&ParameterState> {
+        self.synths.get(&(synth.to_owned(), channel))?.get(id)
+    }
+
+    pub fn value(&self, synth: &str, channel: usize, id: &str) -> Option<&ParameterValue> {
+        self.get(synth, channel, id)?.value.as_ref()
+    }
+
+    /// Synths and channels with known values
+    pub fn sessions(&self) -> Vec<(&str, usize)> {
+        self.synths.keys().map(|(s, c)| (s.as_str(), *c)).collect()
+    }
+
+    pub fn parameters(&self, synth: &str, channel: usize) -> Option<&BTreeMap<String, ParameterState>> {
+        self.synths.get(&(synth.to_owned(), channel))
+    }
+
+    /// Current values of a synth's parameters, as a patch
+    pub fn to_patch(&self, synth: &Synth, channel: usize, name: &str) -> Patch {
+        let mut patch = Patch::new(synth.id.as_str(), name);
+
+        for p in &synth.parameters {
+            if let Some(v) = self.value(synth.id.as_str(), channel, &p.id) {
+                patch.set(&p.id, v.clone());
+            }
+        }
+
+        patch
+    }
+
+    /// Records a message : SysEx with the synth, other messages with the
+    /// MIDI standard commands
+    pub fn apply(
+        &mut self,
+        synth: Option<&Synth>,
+        midi: &HashMap<String, MidiCommand>,
+        default_channel: usize,
+        message: &[u8],
+        direction: Direction,
+    ) -> Vec<StateChange> {
+        match (message.first(), synth) {
+            (Some(0xF0), Some(synth)) => self.apply_sysex(synth, default_channel, message, direction),
+            (Some(0xF0), None) => Vec::new(),
+            _ => self.apply_midi(midi, message, direction),
+        }
+    }
+
+    /// Decodes a parameter change or program dump of the synth and records
+    /// its values. Messages without a channel are recorded on the default one.
+    pub fn apply_sysex(
+        &mut self,
+        synth: &Synth,
+        default_channel: usize,
+        message: &[u8],
+        direction: Direction,
+    ) -> Vec<StateChange> {
+        let mut values: Vec<(String, ParameterValue)> = Vec::new();
+        let mut channel = default_channel;
+
+        let change = synth.parameter_change.as_ref().and_then(|a| synth.get_command(a));
+        if let Some(c) = change.filter(|c| c.matches(message)) {
+            let decoded: Vec<ParameterValue> = c.decode(message).unwrap_or_default().into_iter().map(|(_, v)| v).collect();
+
+            // Channel (if any), parameter number, value
+            let (number, value) = match decoded.len() {
+                3 => {
+                    channel = decoded[0].as_integer().unwrap_or(default_channel);
+                    (decoded[1].as_integer(), decoded[2].clone())
+                }
+                2 => (decoded[0].as_integer(), decoded[1].clone()),
+                _ => (None, ParameterValue::Integer(0)),
+            };
+
+            if let Some(p) = synth.parameters.iter().find(|p| number.is_some() && p.number == number) {
+                values.push((p.id.clone(), value));
+            }
+        } else if let Some(dump) = synth.program_dump().filter(|d| d.matches(message)) {
+            if let Some(header) = &dump.header {
+                let decoded = header.decode(message).unwrap_or_default();
+                if let Some((_, ParameterValue::Integer(n))) = decoded.first() {
+                    channel = *n;
+                }
+            }
+            values = Patch::from_dump(synth, "", message).values;
+        }
+
+        self.apply_values(synth.id.as_str(), channel, values, direction)
+    }
+
+    /// Records a standard MIDI message, under the "midi" synth. Messages with
+    /// two data bytes are recorded per first byte (e.g. "Control Change 74").
+    pub fn apply_midi(
+        &mut self,
+        commands: &HashMap<String, MidiCommand>,
+        message: &[u8],
+        direction: Direction,
+    ) -> Vec<StateChange> {
+        let command = match commands.values().find(|c| c.matches(message)) {
+            Some(c) => c,
+            None => return Vec::new(),
+        };
+
+        let decoded: Vec<ParameterValue> = command.decode(message).unwrap_or_default().into_iter().map(|(_, v)| v).collect();
+        let channel = decoded.first().and_then(|v| v.as_integer()).unwrap_or(0);
+
+        let value = match decoded.len() {
+            3 => (format!("{} {}", command.name, decoded[1]), decoded[2].clone()),
+            2 => (command.name.clone(), decoded[1].clone()),
+            _ => return Vec::new(),
+        };
+
+        self.apply_values("midi", channel, vec![value], direction)
+    }
+
+    /// Values of a synth that differ from an earlier state
+    pub fn changes_since(&self, before: &SessionState, synth: &str, channel: usize) -> Vec<StateChange> {
+        let parameters = match self.parameters(synth, channel) {
+            Some(p) => p,
+            None => return Vec::new(),
+        };
+
+        parameters
+            .iter()
+            .filter_map(|(id, state)| {
+                let old = before.value(synth, channel, id).cloned();
+                match &state.value {
+                    Some(new) if old.as_ref() != Some(new) => Some(StateChange { channel, id: id.clone(), old, new: new.clone() }),
+                    _ => None,
+                }
+            })
+            .collect()
+    }
+
+    fn apply_values(
+        &mut self,
+        synth: &str,
+        channel: usize,
+        values: Vec<(String, ParameterValue)>,
+        direction: Direction,
+    ) -> Vec<StateChange> {
+        let mut changes: Vec<StateChange> = Vec::new();
+
+        for (id, value) in values {
+            let old = self.update(synth, channel, &id, value.clone(), direction);
+            if old.as_ref() != Some(&value) {
+                changes.push(StateChange { channel, id, old, new: value });
+            }
+        }
+
+        changes
+    }
+}
+
+/// Requests the synth's program, or each of its parameters when it can't
+/// send its program, returning the replies
+pub fn request_all<T: MidiTransport>(
+    transport: &mut T,
+    synth: &Synth,
+    channel: Option<usize>,
+    timeout: Duration,
+) -> Result<Vec<Vec<u8>>, StateError> {
+    if let Some(dump) = synth.program_dump() {
+        let request = synth.dump_request_bytes(dump, channel)?;
+        return Ok(transport.request(&request, timeout)?);
+    }
+
+    if synth.parameter_request.is_none() {
+        return Err(StateError::CommonError(format!(
+            "Synth {} declares no program dump nor parameter request",
+            synth.id
+        )));
+    }
+
+    let mut replies: Vec<Vec<u8>> = Vec::new();
+    for p in synth.parameters.iter().filter(|p| p.number.is_some()) {
+        match request_parameter(transport, synth, channel, p, timeout) {
+            Ok(r) => replies.extend(r),
+            Err(e) => println!("{} : {}", p.id, e),
+        }
+    }
+    Ok(replies)
+}
+
+/// Requests a single parameter when the synth can send it, its whole
+/// program otherwise, returning the replies
+pub fn request<T: MidiTransport>(
+    transport: &mut T,
+    synth: &Synth,
+    channel: Option<usize>,
+    parameter: &SynthParameter,
+    timeout: Duration,
+) -> Result<Vec<Vec<u8>>, StateError> {
+    if synth.parameter_request.is_some() && parameter.number.is_some() {
+        request_parameter(transport, synth, channel, parameter, timeout)
+    } else {
+        request_all(transport, synth, channel, timeout)
+    }
+}
+
+fn request_parameter<T: MidiTransport>(
+    transport: &mut T,
+    synth: &Synth,
+    channel: Option<usize>,
+    parameter: &SynthParameter,
+    timeout: Duration,
+) -> Result<Vec<Vec<u8>>, StateError> {
+    let request = synth.parameter_request_bytes(channel, parameter)?;
+    Ok(transport.request(&request, timeout)?)
+}
@@ -1,10 +1,220 @@
-use super::midi_command::MidiCommand;
+use super::address_map::AddressMap;
+use super::handshake::Handshake;
+use super::identity::IdentitySignature;
+use super::midi_command::{Argument, MidiCommand, MidiParameter, ParameterValue};
+use super::patch::Patch;
+use super::utils::stob;
 
+/// Parameter of the synth's sound engine, as it appears in parameter change
+/// commands and in program dumps
+#[derive(Debug, Clone)]
+pub struct SynthParameter {
+    pub id: String,
+    pub name: String,
+    /// Number sent to the parameter change command
+    pub number: Option<usize>,
+    /// Position of the value in the program dump, after the dump header
+    pub offset: Option<usize>,
+    pub min: usize,
+    pub max: usize,
+    /// Labels of an enumerated parameter, starting at `min`
+    pub values: Vec<String>,
+    /// Layout of the value in the dump ("b0-2", "s10"), a full byte by default
+    pub field: MidiParameter,
+}
+
+impl SynthParameter {
+    pub fn new(id: &str, name: &str) -> SynthParameter {
+        SynthParameter {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            number: None,
+            offset: None,
+            min: 0,
+            max: 127,
+            values: Vec::new(),
+            field: MidiParameter::new_str(id, 2, name),
+        }
+    }
+
+    /// Parses a parameter definition :
+    /// { "id" : "cutoff", "name" : "Cutoff", "number" : 15, "offset" : 15, "max" : 63 }
+    pub fn from_json(val: &json::JsonValue) -> SynthParameter {
+        let id = val["id"].as_str().expect("Expected parameter id");
+        let mut p = SynthParameter::new(id, val["name"].as_str().unwrap_or(id));
+
+        p.number = val["number"].as_usize();
+        p.offset = val["offset"].as_usize();
+
+        for v in val["values"].members() {
+            p.values.push(v.as_str().unwrap_or("").to_owned());
+        }
+
+        if let Some(min) = val["min"].as_usize() {
+            p.min = min;
+        }
+
+        if let Some(max) = val["max"].as_usize() {
+            p.max = max;
+        } else if !p.values.is_empty() {
+            p.max = p.min + p.values.len() - 1;
+        }
+
+        if let Some(format) = val["format"].as_str() {
+            p.field = MidiParameter::new_parse(format!("{} : {} : {}", p.id, format, p.name).as_str());
+        }
+
+        p
+    }
+
+    pub fn is_enumeration(&self) -> bool {
+        !self.values.is_empty()
+    }
+
+    pub fn is_text(&self) -> bool {
+        self.field.text_format().is_some()
+    }
+
+    /// Human readable value, using the enumeration labels when there are some
+    pub fn label(&self, value: &ParameterValue) -> String {
+        if let ParameterValue::Integer(v) = value {
+            if let Some(l) = v.checked_sub(self.min).and_then(|i| self.values.get(i)) {
+                return l.clone();
+            }
+        }
+        value.to_string()
+    }
+
+    /// Value moved by a number of steps, clamped to the parameter's range.
+    /// Enumerations are walked in the order of their labels.
+    pub fn step(&self, value: &ParameterValue, delta: isize) -> Option<ParameterValue> {
+        let v = value.as_integer()? as isize + delta;
+        Some(ParameterValue::Integer(v.clamp(self.min as isize, self.max as isize) as usize))
+    }
+
+    /// Next value of a switch : the other end of the range for two-state
+    /// parameters, the next label for longer enumerations. Continuous
+    /// parameters can't be toggled.
+    pub fn toggle(&self, value: &ParameterValue) -> Option<ParameterValue> {
+        let v = value.as_integer()?;
+
+        if !self.is_enumeration() && self.max != self.min + 1 {
+            None
+        } else if self.values.len() > 2 {
+            let next = if v >= self.max { self.min } else { v + 1 };
+            Some(ParameterValue::Integer(next))
+        } else if v == self.min {
+            Some(ParameterValue::Integer(self.max))
+        } else {
+            Some(ParameterValue::Integer(self.min))
+        }
+    }
+
+    /// Reads the parameter from the data following the dump header
+    pub fn read(&self, data: &[u8]) -> Option<ParameterValue> {
+        let offset = self.offset?;
+
+        if let Some(format) = self.field.text_format() {
+            let text = data.get(offset..offset + format.length)?;
+            return Some(ParameterValue::Text(format.decode(text)));
+        }
+
+        let byte = *data.get(offset)?;
+        Some(ParameterValue::Integer(self.field.unpack_bits(byte)))
+    }
+
+    /// Writes the parameter in the data following the dump header
+    pub fn write(&self, data: &mut [u8], value: &ParameterValue) {
+        let offset = match self.offset {
+            Some(o) => o,
+            None => return,
+        };
+
+        if let Some(format) = self.field.text_format() {
+            let text = match value {
+                ParameterValue::Text(t) => t.clone(),
+                ParameterValue::Integer(v) => v.to_string(),
+            };
+            for (i, b) in format.encode(&text).into_iter().enumerate() {
+                if let Some(d) = data.get_mut(offset + i) {
+                    *d = b;
+                }
+            }
+        } else if let Some(d) = data.get_mut(offset) {
+            let v = value.as_integer().unwrap_or(self.min);
+            *d = (*d & !self.field.bit_mask()) | self.field.pack_bits(v);
+        }
+    }
+}
+
+/// Data dump the synth can be asked for
+#[derive(Debug, Clone)]
+pub struct Dump {
+    pub name: String,
+    /// Alias of the command requesting the dump
+    pub request: String,
+    /// Header of the dump message sent back by the synth (e.g. "F0 42 3n 03 40")
+    pub header: Option<MidiCommand>,
+}
+
+impl Dump {
+    /// Parses a dump definition :
+    /// { "name" : "program", "request" : "data-save-request", "header" : "F0 42 3n 03 40" }
+    pub fn from_json(val: &json::JsonValue) -> Dump {
+        let name = val["name"].as_str().unwrap_or("");
+        let header = val["header"].as_str().map(|h| {
+            let mut c = MidiCommand::new(format!("{} header", name));
+            c.midi = h.to_owned();
+            c.add_parameter(MidiParameter::new_str("n", 1, "Channel"));
+            c.update_mask();
+            c
+        });
+
+        Dump {
+            name: name.to_owned(),
+            request: val["request"].as_str().unwrap_or("").to_owned(),
+            header,
+        }
+    }
+
+    pub fn header_size(&self) -> usize {
+        self.header.as_ref().map(|h| h.mask.len()).unwrap_or(0)
+    }
+
+    /// Whether a message is a reply to this dump's request
+    pub fn matches(&self, data: &[u8]) -> bool {
+        match &self.header {
+            Some(h) => h.matches_prefix(data),
+            None => false,
+        }
+    }
+
+    /// Data of a dump message, without its header and final F7
+    pub fn payload<'a>(&self, data: &'a [u8]) -> &'a [u8] {
+        let start = self.header_size().min(data.len());
+        let end = if data.last() == Some(&0xF7) { data.len() - 1 } else { data.len() };
+        &data[start..end.max(start)]
+    }
+}
+
+#[derive(Clone)]
 pub struct Synth {
     pub name: String,
     pub id: String,
     pub manufacturer: String,
     pub commands: Vec<MidiCommand>,
+    pub parameters: Vec<SynthParameter>,
+    pub dumps: Vec<Dump>,
+    /// Alias of the command changing a parameter from its number and value
+    pub parameter_change: Option<String>,
+    /// Alias of the command requesting a parameter's value from its number
+    pub parameter_request: Option<String>,
+    /// What the synth replies to an Identity Request
+    pub identity: Option<IdentitySignature>,
+    /// How the synth acknowledges bulk data
+    pub handshake: Option<Handshake>,
+    /// Memory read with RQ1 and written with DT1, for Roland synths
+    pub address_map: Option<AddressMap>,
 }
 
 impl Synth {
@@ -14,6 +224,13 @@ impl Synth {
             id,
             manufacturer,
             commands: Vec::new(),
+            parameters: Vec::new(),
+            dumps: Vec::new(),
+            parameter_change: None,
+            parameter_request: None,
+            identity: None,
+            handshake: None,
+            address_map: None,
         }
     }
 
@@ -23,6 +240,13 @@ impl Synth {
             id,
             manufacturer: String::from(""),
             commands: Vec::new(),
+            parameters: Vec::new(),
+            dumps: Vec::new(),
+            parameter_change: None,
+            parameter_request: None,
+            identity: None,
+            handshake: None,
+            address_map: None,
         }
     }
 
@@ -45,4 +269,114 @@ impl Synth {
 
         return None;
     }
+
+    pub fn get_parameter(&self, id: &str) -> Option<&SynthParameter> {
+        self.parameters.iter().find(|p| p.id == id)
+    }
+
+    pub fn get_dump(&self, name: &str) -> Option<&Dump> {
+        self.dumps.iter().find(|d| d.name == name)
+    }
+
+    /// The dump whose layout is described by the parameters' offsets
+    pub fn program_dump(&self) -> Option<&Dump> {
+        self.get_dump("program")
+    }
+
+    /// Builds a command's message, the channel (if any) being given as its
+    /// first parameter, as in the interpreter
+    pub fn command_bytes(&self, alias: &str, channel: Option<usize>, values: &[ParameterValue]) -> Result<Vec<u8>, String> {
+        let command = self
+            .get_command(alias)
+            .ok_or(format!("Command {} not found in synth {}", alias, self.id))?;
+
+        let mut arguments: Vec<Argument> = values.iter().cloned().map(Argument::Positional).collect();
+        if let Some(c) = channel {
+            arguments.insert(0, Argument::Positional(ParameterValue::Integer(c)));
+        }
+
+        Ok(stob(command.generate_arguments(&arguments)?.as_str()))
+    }
+
+    /// Builds the message changing a parameter, the command being given the
+    /// channel (if any), the parameter number and the value
+    pub fn parameter_change_bytes(
+        &self,
+        channel: Option<usize>,
+        parameter: &SynthParameter,
+        value: &ParameterValue,
+    ) -> Result<Vec<u8>, String> {
+        let alias = self
+            .parameter_change
+            .as_ref()
+            .ok_or(format!("Synth {} has no parameter change command", self.id))?;
+        let number = parameter
+            .number
+            .ok_or(format!("Parameter {} has no number", parameter.id))?;
+
+        self.command_bytes(alias, channel, &[ParameterValue::Integer(number), value.clone()])
+    }
+
+    /// Builds the message requesting a parameter's value, the command being
+    /// given the channel (if any) and the parameter number
+    pub fn parameter_request_bytes(&self, channel: Option<usize>, parameter: &SynthParameter) -> Result<Vec<u8>, String> {
+        let alias = self
+            .parameter_request
+            .as_ref()
+            .ok_or(format!("Synth {} has no parameter request command", self.id))?;
+        let number = parameter
+            .number
+            .ok_or(format!("Parameter {} has no number", parameter.id))?;
+
+        self.command_bytes(alias, channel, &[ParameterValue::Integer(number)])
+    }
+
+    /// Builds the message requesting a dump
+    pub fn dump_request_bytes(&self, dump: &Dump, channel: Option<usize>) -> Result<Vec<u8>, String> {
+        self.command_bytes(dump.request.as_str(), channel, &[])
+    }
+
+    /// Builds a program dump from a patch, starting from the patch's own dump
+    /// data when it has some
+    pub fn program_dump_bytes(&self, channel: Option<usize>, patch: &Patch) -> Result<Vec<u8>, String> {
+        let dump = self
+            .program_dump()
+            .ok_or(format!("Synth {} has no program dump", self.id))?;
+        let header = dump
+            .header
+            .as_ref()
+            .ok_or(format!("Synth {} program dump has no header", self.id))?;
+
+        let mut payload: Vec<u8> = match super::utils::split_sysex(&patch.data)
+            .into_iter()
+            .find(|m| dump.matches(m))
+        {
+            Some(m) => dump.payload(m).to_vec(),
+            None => {
+                let size = self
+                    .parameters
+                    .iter()
+                    .filter_map(|p| p.offset.map(|o| o + p.field.width()))
+                    .max()
+                    .unwrap_or(0);
+                vec![0x00; size]
+            }
+        };
+
+        for p in &self.parameters {
+            if let Some(v) = patch.get(&p.id) {
+                p.write(&mut payload, v);
+            }
+        }
+
+        let mut data = header.generate_bytes(&[channel.unwrap_or(0)]);
+        data.extend_from_slice(&payload);
+        data.push(0xF7);
+        Ok(data)
+    }
+
+    /// Whether program dumps can be decoded into parameter values
+    pub fn has_layout(&self) -> bool {
+        self.program_dump().is_some() && self.parameters.iter().any(|p| p.offset.is_some())
+    }
 }
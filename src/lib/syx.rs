@@ -0,0 +1,255 @@
+use super::address_map::{DT1, ROLAND, RQ1};
+use super::handshake::{Handshake, HandshakeError};
+use super::manufacturers;
+use super::midi_interface::*;
+use super::monitor::{decode, Decoded};
+use super::synth::Synth;
+use super::utils::*;
+use std::fs;
+use std::path::Path;
+
+use derive_more::*;
+
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, From)]
+pub enum SyxError {
+    IOError(std::io::Error),
+    InterfaceError(MidiInterfaceError),
+    HandshakeError(HandshakeError),
+    CommonError(String),
+}
+
+impl std::fmt::Display for SyxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyxError::IOError(e) => {
+                write!(f, "{}", e)
+            }
+
+            SyxError::InterfaceError(e) => {
+                write!(f, "{}", e)
+            }
+
+            SyxError::HandshakeError(e) => {
+                write!(f, "{}", e)
+            }
+
+            SyxError::CommonError(e) => {
+                write!(f, "{}", e)
+            }
+        }
+    }
+}
+
+/// Reads a .syx file as a list of F0 ... F7 messages
+pub fn load(path: &Path) -> Result<Vec<Vec<u8>>, SyxError> {
+    let data = fs::read(path)?;
+    Ok(split_sysex(&data).into_iter().map(|m| m.to_vec()).collect())
+}
+
+/// Sends messages one by one, each once the previous one is accepted. When
+/// synths are given, every message must match one of their definitions,
+/// otherwise nothing is sent.
+pub fn send_messages<T: MidiTransport>(
+    transport: &mut T,
+    messages: &[Vec<u8>],
+    handshake: &Handshake,
+    identify_with: Option<&[&Synth]>,
+) -> Result<usize, SyxError> {
+    if let Some(synths) = identify_with {
+        let unknown: Vec<String> = messages
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| decode(synths.iter().copied(), m).is_none())
+            .map(|(i, _)| (i + 1).to_string())
+            .collect();
+
+        if !unknown.is_empty() {
+            return Err(SyxError::CommonError(format!(
+                "Refusing to send unknown messages {}",
+                unknown.join(", ")
+            )));
+        }
+    }
+
+    let total: usize = messages.iter().map(|m| m.len()).sum();
+    let mut sent = 0;
+
+    for (i, m) in messages.iter().enumerate() {
+        handshake.send(transport, m)?;
+        sent += m.len();
+
+        let name = identify_with
+            .and_then(|synths| decode(synths.iter().copied(), m))
+            .map(|d| format!(" {}", d.name))
+            .unwrap_or_default();
+        println!("[{}/{}]{} : {}/{} bytes", i + 1, messages.len(), name, sent, total);
+    }
+
+    Ok(messages.len())
+}
+
+/// Manufacturer ID of a SysEx message : one byte, or three starting with 00
+pub fn manufacturer_id(message: &[u8]) -> Option<&[u8]> {
+    if message.first() != Some(&0xF0) {
+        return None;
+    }
+
+    match message.get(1)? {
+        0x00 => message.get(1..4),
+        _ => message.get(1..2),
+    }
+}
+
+/// Checksum of a Roland RQ1 or DT1 message
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Checksum {
+    Valid,
+    Invalid,
+    /// No address map gives the length of the model ID, so the checksummed
+    /// bytes aren't known
+    Unknown,
+}
+
+/// Checks the checksum of a Roland RQ1 or DT1 message (F0 41 device model
+/// 11|12 address size|data sum F7) with the model ID of the synths' address
+/// maps. None for messages that aren't from Roland.
+pub fn check_roland_checksum(message: &[u8], synths: &[&Synth]) -> Option<Checksum> {
+    if message.len() < 7 || message[1] != ROLAND || message.last() != Some(&0xF7) {
+        return None;
+    }
+
+    let end = message.len() - 2;
+    let model = synths
+        .iter()
+        .filter_map(|s| s.address_map.as_ref())
+        .map(|m| &m.model)
+        .find(|model| {
+            let command = 3 + model.len();
+            command + 1 < end && message[3..command] == model[..] && (message[command] == RQ1 || message[command] == DT1)
+        });
+
+    match model {
+        Some(model) if roland_checksum(&message[4 + model.len()..end]) == message[end] => Some(Checksum::Valid),
+        Some(_) => Some(Checksum::Invalid),
+        None => Some(Checksum::Unknown),
+    }
+}
+
+/// A message read from a .syx file, or bytes found outside of any message
+#[derive(Debug, Clone)]
+pub struct MessageInfo {
+    pub offset: usize,
+    pub data: Vec<u8>,
+    pub identified: Option<Decoded>,
+    /// Checksum of Roland messages
+    pub checksum: Option<Checksum>,
+    pub problems: Vec<String>,
+}
+
+impl std::fmt::Display for MessageInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "offset {}, {} bytes", self.offset, self.data.len())?;
+
+        if self.data.first() == Some(&0xF0) {
+            match manufacturer_id(&self.data) {
+                Some(id) => write!(f, ", manufacturer {}", manufacturers::describe(id))?,
+                None => write!(f, ", no manufacturer")?,
+            }
+
+            match &self.identified {
+                Some(i) => write!(f, " : {} {}", i.synth, i.description)?,
+                None => write!(f, " : unknown")?,
+            }
+
+            if self.checksum == Some(Checksum::Unknown) {
+                write!(f, ", checksum unknown")?;
+            }
+        }
+
+        for p in &self.problems {
+            write!(f, "\n  ! {}", p)?;
+        }
+        Ok(())
+    }
+}
+
+impl MessageInfo {
+    fn new(offset: usize, data: &[u8]) -> MessageInfo {
+        MessageInfo {
+            offset,
+            data: data.to_vec(),
+            identified: None,
+            checksum: None,
+            problems: Vec::new(),
+        }
+    }
+
+    pub fn is_valid(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+/// Splits the content of a .syx file, matching each message against the
+/// synths' definitions and flagging framing errors and bad checksums
+pub fn info(data: &[u8], synths: &[&Synth]) -> Vec<MessageInfo> {
+    let mut infos: Vec<MessageInfo> = Vec::new();
+    let mut start: Option<usize> = None;
+    let mut stray: Option<usize> = None;
+
+    let stray_bytes = |infos: &mut Vec<MessageInfo>, from: &mut Option<usize>, to: usize| {
+        if let Some(s) = from.take() {
+            let mut i = MessageInfo::new(s, &data[s..to]);
+            i.problems.push(format!("{} bytes outside of any message", to - s));
+            infos.push(i);
+        }
+    };
+
+    for (i, &b) in data.iter().enumerate() {
+        match (b, start) {
+            (0xF0, Some(s)) => {
+                let mut m = MessageInfo::new(s, &data[s..i]);
+                m.problems.push(String::from("Message not ended by F7"));
+                infos.push(m);
+                start = Some(i);
+            }
+
+            (0xF0, None) => {
+                stray_bytes(&mut infos, &mut stray, i);
+                start = Some(i);
+            }
+
+            (0xF7, Some(s)) => {
+                let mut m = MessageInfo::new(s, &data[s..=i]);
+                for (j, &c) in data[s + 1..i].iter().enumerate() {
+                    if c >= 0x80 {
+                        m.problems.push(format!("Status byte {:02X} at offset {}", c, s + 1 + j));
+                    }
+                }
+                infos.push(m);
+                start = None;
+            }
+
+            (_, None) if stray.is_none() => stray = Some(i),
+
+            _ => {}
+        }
+    }
+
+    if let Some(s) = start {
+        let mut m = MessageInfo::new(s, &data[s..]);
+        m.problems.push(String::from("Message not ended by F7"));
+        infos.push(m);
+    }
+    stray_bytes(&mut infos, &mut stray, data.len());
+
+    for m in infos.iter_mut().filter(|m| m.data.first() == Some(&0xF0)) {
+        m.identified = decode(synths.iter().copied(), &m.data);
+        m.checksum = check_roland_checksum(&m.data, synths);
+        if m.checksum == Some(Checksum::Invalid) {
+            m.problems.push(String::from("Bad checksum"));
+        }
+    }
+
+    infos
+}
@@ -24,3 +24,74 @@ pub fn stob(s: &str) -> Vec<u8> {
     }
     return bytes;
 }
+
+/// Bytes of a hex string such as "F0 42 30", None if it isn't one
+pub fn parse_hex(s: &str) -> Option<Vec<u8>> {
+    let digits: String = s.split_whitespace().collect();
+    if digits.is_empty() || digits.len() % 2 == 1 || !digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    Some(stob(&digits))
+}
+
+/// Splits a byte stream into its F0 ... F7 SysEx messages, ignoring the
+/// bytes found outside of them
+pub fn split_sysex(data: &[u8]) -> Vec<&[u8]> {
+    let mut messages: Vec<&[u8]> = Vec::new();
+    let mut start: Option<usize> = None;
+
+    for (i, &b) in data.iter().enumerate() {
+        if b == 0xF0 {
+            start = Some(i);
+        } else if b == 0xF7 {
+            if let Some(s) = start.take() {
+                messages.push(&data[s..=i]);
+            }
+        }
+    }
+
+    messages
+}
+
+/// Formats bytes as "F0 42 30 F7"
+pub fn btos(data: &[u8]) -> String {
+    let v: Vec<String> = data.iter().map(|b| format!("{:02X}", b)).collect();
+    v.join(" ")
+}
+
+/// UTC date and time as "2020-06-21_18-30-05", usable in file names
+pub fn timestamp() -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+
+    // Civil date from the day count, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let time = secs % 86400;
+    format!(
+        "{:04}-{:02}-{:02}_{:02}-{:02}-{:02}",
+        year,
+        month,
+        day,
+        time / 3600,
+        (time % 3600) / 60,
+        time % 60
+    )
+}
+
+/// Roland checksum of the address and data bytes of a message : the value
+/// bringing their sum to a multiple of 128
+pub fn roland_checksum(data: &[u8]) -> u8 {
+    let sum: u32 = data.iter().map(|b| *b as u32).sum();
+    ((128 - sum % 128) % 128) as u8
+}
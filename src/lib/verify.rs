@@ -0,0 +1,124 @@
+use super::handshake::{Handshake, HandshakeError};
+use super::midi_interface::*;
+use super::patch::*;
+use super::synth::{Dump, Synth};
+use std::time::Duration;
+
+use derive_more::*;
+
+#[derive(Debug, From)]
+pub enum VerifyError {
+    InterfaceError(MidiInterfaceError),
+    HandshakeError(HandshakeError),
+    CommonError(String),
+    Mismatch(PatchDiff),
+}
+
+impl std::fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VerifyError::InterfaceError(e) => {
+                write!(f, "{}", e)
+            }
+
+            VerifyError::HandshakeError(e) => {
+                write!(f, "{}", e)
+            }
+
+            VerifyError::CommonError(e) => {
+                write!(f, "{}", e)
+            }
+
+            VerifyError::Mismatch(d) => {
+                write!(f, "Synth data differs from what was sent :\n{}", d)
+            }
+        }
+    }
+}
+
+/// Compares what the synth sent back with what was uploaded : the uploaded
+/// parameters when the synth's program dump can be decoded, the bytes otherwise
+pub fn check(synth: &Synth, expected: &Patch, received: &Patch) -> PatchDiff {
+    if !expected.values.is_empty() && synth.has_layout() {
+        match diff(Some(synth), expected, received) {
+            PatchDiff::Parameters(changes) => {
+                let changes = changes.into_iter().filter(|c| c.old.is_some()).collect();
+                return PatchDiff::Parameters(changes);
+            }
+            d => return d,
+        }
+    }
+
+    diff(None, expected, received)
+}
+
+/// Uploads data and reads it back from the synth until both match
+#[derive(Debug, Clone)]
+pub struct Verifier {
+    /// Number of times the upload is tried again after a mismatch
+    pub retries: usize,
+    pub timeout: Duration,
+    /// Pause after each uploaded message
+    pub pace: Duration,
+}
+
+impl Verifier {
+    pub fn new(retries: usize, timeout: Duration) -> Verifier {
+        Verifier {
+            retries,
+            timeout,
+            pace: Duration::from_millis(0),
+        }
+    }
+
+    /// Requests a dump and returns the synth's reply
+    pub fn read_back<T: MidiTransport>(
+        &self,
+        transport: &mut T,
+        synth: &Synth,
+        dump: &Dump,
+        channel: Option<usize>,
+    ) -> Result<Patch, VerifyError> {
+        let request = synth.dump_request_bytes(dump, channel)?;
+        let replies = transport.request(&request, self.timeout)?;
+        Ok(Patch::from_dump(synth, dump.name.as_str(), &replies.concat()))
+    }
+
+    /// Sends the messages with the synth's handshake if any, then requests
+    /// the dump holding the uploaded data and compares it to the expected
+    /// patch, retrying on mismatches
+    pub fn upload<T: MidiTransport>(
+        &self,
+        transport: &mut T,
+        synth: &Synth,
+        dump: &Dump,
+        channel: Option<usize>,
+        messages: &[Vec<u8>],
+        expected: &Patch,
+    ) -> Result<(), VerifyError> {
+        let mut last = VerifyError::Mismatch(PatchDiff::Bytes(Vec::new()));
+        let handshake = Handshake { pace: self.pace, ..synth.handshake.clone().unwrap_or_default() };
+
+        for attempt in 0..=self.retries {
+            handshake.send_all(transport, messages)?;
+
+            // A read back that fails counts as a failed attempt too
+            last = match self.read_back(transport, synth, dump, channel) {
+                Ok(received) => {
+                    let d = check(synth, expected, &received);
+                    if d.is_empty() {
+                        return Ok(());
+                    }
+                    println!("Verification failed (attempt {}/{})", attempt + 1, self.retries + 1);
+                    VerifyError::Mismatch(d)
+                }
+                Err(e) => {
+                    println!("Read back failed (attempt {}/{}) : {}", attempt + 1, self.retries + 1, e);
+                    e
+                }
+            };
+        }
+
+        Err(last)
+    }
+}
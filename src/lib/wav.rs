@@ -0,0 +1,128 @@
+use super::sds::{LoopType, Sample};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+
+fn invalid(message: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, message)
+}
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes([data[offset], data[offset + 1], data[offset + 2], data[offset + 3]])
+}
+
+fn chunk(id: &[u8], body: &[u8]) -> Vec<u8> {
+    let mut c = id.to_vec();
+    c.extend(&(body.len() as u32).to_le_bytes());
+    c.extend(body);
+    if body.len() % 2 == 1 {
+        c.push(0x00);
+    }
+    c
+}
+
+/// Reads a PCM .wav file (8, 16 or 24 bits) as a sample, mixing its
+/// channels down to mono. The first loop of a "smpl" chunk becomes the
+/// sustain loop.
+pub fn read(path: &Path) -> std::io::Result<Sample> {
+    let data = fs::read(path)?;
+    if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+        return Err(invalid("Not a WAV file"));
+    }
+
+    let mut format: Option<(u16, u16, u32, u16)> = None;
+    let mut pcm: Option<&[u8]> = None;
+    let mut sustain: Option<(u32, u32, u32)> = None;
+    let mut offset = 12;
+
+    while offset + 8 <= data.len() {
+        let size = u32_at(&data, offset + 4) as usize;
+        let body = &data[offset + 8..(offset + 8 + size).min(data.len())];
+
+        match &data[offset..offset + 4] {
+            b"fmt " if body.len() >= 16 => {
+                format = Some((u16_at(body, 0), u16_at(body, 2), u32_at(body, 4), u16_at(body, 14)));
+            }
+            b"data" => pcm = Some(body),
+            b"smpl" if body.len() >= 60 && u32_at(body, 28) > 0 => {
+                sustain = Some((u32_at(body, 40), u32_at(body, 44), u32_at(body, 48)));
+            }
+            _ => {}
+        }
+        offset += 8 + size + size % 2;
+    }
+
+    let (tag, channels, rate, bits) = format.ok_or_else(|| invalid("Missing fmt chunk"))?;
+    let pcm = pcm.ok_or_else(|| invalid("Missing data chunk"))?;
+    if tag != 1 || channels == 0 || ![8, 16, 24].contains(&bits) {
+        return Err(invalid("Only 8, 16 and 24 bit PCM files are supported"));
+    }
+
+    let width = bits as usize / 8;
+    let frame = width * channels as usize;
+    let samples = pcm
+        .chunks_exact(frame)
+        .map(|f| {
+            let sum: i64 = f
+                .chunks(width)
+                .map(|s| match width {
+                    1 => s[0] as i64 - 128,
+                    2 => i16::from_le_bytes([s[0], s[1]]) as i64,
+                    _ => (i32::from_le_bytes([0, s[0], s[1], s[2]]) >> 8) as i64,
+                })
+                .sum();
+            (sum / channels as i64) as i32
+        })
+        .collect();
+
+    let mut sample = Sample::new(bits as u8, rate, samples);
+    if let Some((kind, start, end)) = sustain {
+        sample.loop_type = if kind == 1 { LoopType::Alternating } else { LoopType::Forward };
+        sample.loop_start = start;
+        sample.loop_end = end;
+    }
+    Ok(sample)
+}
+
+/// Writes a sample as a mono PCM .wav file, its words widened to a whole
+/// number of bytes. The sustain loop is saved in a "smpl" chunk.
+pub fn write(path: &Path, sample: &Sample) -> std::io::Result<()> {
+    let width = (sample.bits as usize).div_ceil(8);
+    let shift = width * 8 - sample.bits as usize;
+
+    let mut pcm: Vec<u8> = Vec::with_capacity(sample.data.len() * width);
+    for &v in &sample.data {
+        let v = v << shift;
+        match width {
+            1 => pcm.push((v + 128) as u8),
+            _ => pcm.extend(&v.to_le_bytes()[..width]),
+        }
+    }
+
+    let mut fmt: Vec<u8> = Vec::new();
+    fmt.extend(&1u16.to_le_bytes());
+    fmt.extend(&1u16.to_le_bytes());
+    fmt.extend(&sample.rate().to_le_bytes());
+    fmt.extend(&(sample.rate() * width as u32).to_le_bytes());
+    fmt.extend(&(width as u16).to_le_bytes());
+    fmt.extend(&(width as u16 * 8).to_le_bytes());
+
+    let mut body = b"WAVE".to_vec();
+    body.extend(chunk(b"fmt ", &fmt));
+    body.extend(chunk(b"data", &pcm));
+
+    if sample.loop_type != LoopType::Off {
+        let kind: u32 = if sample.loop_type == LoopType::Alternating { 1 } else { 0 };
+        let header = [0, 0, sample.period, 60, 0, 0, 0, 1, 0];
+        let sustain = [0, kind, sample.loop_start, sample.loop_end, 0, 0];
+
+        let smpl: Vec<u8> = header.iter().chain(sustain.iter()).flat_map(|v| v.to_le_bytes().to_vec()).collect();
+        body.extend(chunk(b"smpl", &smpl));
+    }
+
+    fs::write(path, chunk(b"RIFF", &body))
+}